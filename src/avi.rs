@@ -0,0 +1,413 @@
+use std::{fs::{File, OpenOptions}, io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write}, path::Path, time::Duration};
+
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIIF_KEYFRAME: u32 = 0x10;
+//Header sizes without the chunk header itself
+const AVIH_SIZE: u32 = 56;
+const STRH_SIZE: u32 = 56;
+const STRF_SIZE: u32 = 40;
+const STRL_SIZE: u32 = 4 + (8 + STRH_SIZE) + (8 + STRF_SIZE);
+const HDRL_SIZE: u32 = 4 + (8 + AVIH_SIZE) + (8 + STRL_SIZE);
+const RIFF_SIZE_OFFSET: u64 = 4;
+//Frame rate assumed when the file doesn't contain one
+const DEFAULT_MICROS_PER_FRAME: u32 = 40_000;
+
+/// Writes JPEG frames into an MJPEG AVI file, which every common video player can open.
+///
+/// The frame rate is not known in advance, so it is calculated from the number of frames
+/// and the recording duration passed to `finish` and patched into the headers afterwards.
+pub struct AviWriter{
+    file: BufWriter<File>,
+    width: u32,
+    height: u32,
+    index: Vec<(u32, u32)>,
+    movi_length: u32,
+    largest_frame: u32
+}
+
+impl AviWriter{
+    pub fn create(path: &Path, width: u32, height: u32) -> io::Result<AviWriter>{
+        AviWriter::from_file(File::create(path)?, width, height)
+    }
+
+    /// Like `create`, but fails with `AlreadyExists` instead of overwriting an existing file
+    pub fn create_new(path: &Path, width: u32, height: u32) -> io::Result<AviWriter>{
+        AviWriter::from_file(OpenOptions::new().write(true).create_new(true).open(path)?, width, height)
+    }
+
+    fn from_file(file: File, width: u32, height: u32) -> io::Result<AviWriter>{
+        let mut writer = AviWriter{
+            file: BufWriter::new(file),
+            width,
+            height,
+            index: Vec::new(),
+            movi_length: 0,
+            largest_frame: 0
+        };
+        writer.write_headers(Duration::from_millis(0))?;
+        Ok(writer)
+    }
+
+    pub fn frame_count(&self) -> usize{
+        self.index.len()
+    }
+
+    /// Size of the file once `finish` has added the index
+    pub fn file_length(&self) -> u64{
+        12 + 8 + HDRL_SIZE as u64 + 12 + self.movi_length as u64 + 8 + self.index.len() as u64 * 16
+    }
+
+    /// Size of the finished file if a frame of `length` bytes was appended
+    pub fn file_length_with_frame(&self, length: usize) -> u64{
+        self.file_length() + 8 + length as u64 + length as u64 % 2 + 16
+    }
+
+    /// Appends one compressed JPEG frame, fails if the file would grow past the 4 GiB the 32 bit sizes allow
+    pub fn write_frame(&mut self, jpeg: &[u8]) -> io::Result<()>{
+        let too_long = || io::Error::new(io::ErrorKind::InvalidInput, "AVI file would exceed 4 GiB");
+        if self.file_length_with_frame(jpeg.len()) > u32::MAX as u64{
+            return Err(too_long());
+        }
+        let length = jpeg.len() as u32;
+        let padding = length % 2;
+        let movi_length = self.movi_length.checked_add(8 + length + padding).ok_or_else(too_long)?;
+        //Index offsets are relative to the 'movi' fourcc
+        self.index.push((4 + self.movi_length, length));
+        self.file.write_all(b"00dc")?;
+        write_u32(&mut self.file, length)?;
+        self.file.write_all(jpeg)?;
+        if padding == 1{
+            self.file.write_all(&[0])?;
+        }
+        self.movi_length = movi_length;
+        self.largest_frame = self.largest_frame.max(length);
+        Ok(())
+    }
+
+    /// Writes the index and patches the headers with the final frame count and rate
+    pub fn finish(mut self, duration: Duration) -> io::Result<()>{
+        self.file.write_all(b"idx1")?;
+        write_u32(&mut self.file, self.index.len() as u32 * 16)?;
+        for (offset, length) in self.index.iter(){
+            self.file.write_all(b"00dc")?;
+            write_u32(&mut self.file, AVIIF_KEYFRAME)?;
+            write_u32(&mut self.file, *offset)?;
+            write_u32(&mut self.file, *length)?;
+        }
+        let file_length = self.file.stream_position()?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_headers(duration)?;
+        self.file.seek(SeekFrom::Start(RIFF_SIZE_OFFSET))?;
+        write_u32(&mut self.file, file_length as u32 - 8)?;
+        self.file.flush()
+    }
+
+    fn write_headers(&mut self, duration: Duration) -> io::Result<()>{
+        let frames = self.index.len() as u32;
+        //A single frame or a recording without duration has no frame rate of its own
+        let micros_per_frame = match duration.as_micros() / frames.max(1) as u128{
+            0 => DEFAULT_MICROS_PER_FRAME,
+            micros => micros as u32
+        };
+        let movi_length = 4 + self.movi_length;
+        let f = &mut self.file;
+        //The RIFF size is patched separately in `finish` once the index is written
+        f.write_all(b"RIFF")?;
+        write_u32(f, 4 + 8 + HDRL_SIZE + 8 + movi_length)?;
+        f.write_all(b"AVI ")?;
+        f.write_all(b"LIST")?;
+        write_u32(f, HDRL_SIZE)?;
+        f.write_all(b"hdrl")?;
+        f.write_all(b"avih")?;
+        write_u32(f, AVIH_SIZE)?;
+        write_u32(f, micros_per_frame)?;
+        write_u32(f, (self.largest_frame as u64 * 1_000_000 / micros_per_frame as u64) as u32)?; //Max bytes per second
+        write_u32(f, 0)?; //Padding granularity
+        write_u32(f, AVIF_HASINDEX)?;
+        write_u32(f, frames)?;
+        write_u32(f, 0)?; //Initial frames
+        write_u32(f, 1)?; //Streams
+        write_u32(f, self.largest_frame)?; //Suggested buffer size
+        write_u32(f, self.width)?;
+        write_u32(f, self.height)?;
+        f.write_all(&[0; 16])?; //Reserved
+        f.write_all(b"LIST")?;
+        write_u32(f, STRL_SIZE)?;
+        f.write_all(b"strl")?;
+        f.write_all(b"strh")?;
+        write_u32(f, STRH_SIZE)?;
+        f.write_all(b"vids")?;
+        f.write_all(b"MJPG")?;
+        write_u32(f, 0)?; //Flags
+        write_u32(f, 0)?; //Priority and language
+        write_u32(f, 0)?; //Initial frames
+        write_u32(f, micros_per_frame)?; //Scale
+        write_u32(f, 1_000_000)?; //Rate, so rate / scale is the frame rate
+        write_u32(f, 0)?; //Start
+        write_u32(f, frames)?; //Length
+        write_u32(f, self.largest_frame)?; //Suggested buffer size
+        write_u32(f, u32::MAX)?; //Quality (default)
+        write_u32(f, 0)?; //Sample size (varies)
+        f.write_all(&0u16.to_le_bytes())?; //Frame rectangle
+        f.write_all(&0u16.to_le_bytes())?;
+        f.write_all(&(self.width as u16).to_le_bytes())?;
+        f.write_all(&(self.height as u16).to_le_bytes())?;
+        f.write_all(b"strf")?;
+        write_u32(f, STRF_SIZE)?;
+        write_u32(f, STRF_SIZE)?;
+        write_u32(f, self.width)?;
+        write_u32(f, self.height)?;
+        f.write_all(&1u16.to_le_bytes())?; //Planes
+        f.write_all(&24u16.to_le_bytes())?; //Bit count
+        f.write_all(b"MJPG")?;
+        write_u32(f, self.width * self.height * 3)?;
+        f.write_all(&[0; 16])?; //Pixels per meter and palette
+        f.write_all(b"LIST")?;
+        write_u32(f, movi_length)?;
+        f.write_all(b"movi")?;
+        //Continue writing frames after the existing ones
+        f.seek(SeekFrom::Current(self.movi_length as i64))?;
+        Ok(())
+    }
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()>{
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32>{
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Reads the JPEG frames of an MJPEG AVI file.
+///
+/// The index is rebuilt by walking the frames in the `movi` list instead of reading `idx1`,
+/// so recordings that were interrupted before `AviWriter::finish` can still be played back.
+pub struct AviReader{
+    file: BufReader<File>,
+    /// Offset and length of every frame's data
+    frames: Vec<(u64, u32)>,
+    frame_duration: Duration,
+    width: u32,
+    height: u32
+}
+
+impl AviReader{
+    pub fn open(path: &Path) -> io::Result<AviReader>{
+        let mut file = BufReader::new(File::open(path)?);
+        let file_length = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut fourcc = [0; 4];
+        file.read_exact(&mut fourcc)?;
+        read_u32(&mut file)?;
+        let mut form = [0; 4];
+        file.read_exact(&mut form)?;
+        if &fourcc != b"RIFF" || &form != b"AVI "{
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not an AVI file"));
+        }
+        let mut reader = AviReader{
+            file,
+            frames: Vec::new(),
+            frame_duration: Duration::from_micros(DEFAULT_MICROS_PER_FRAME as u64),
+            width: 0,
+            height: 0
+        };
+        //Walk the top level chunks, the RIFF size can't be trusted in interrupted recordings
+        let mut position = 12;
+        while position + 8 <= file_length{
+            reader.file.seek(SeekFrom::Start(position))?;
+            reader.file.read_exact(&mut fourcc)?;
+            let size = read_u32(&mut reader.file)? as u64;
+            if &fourcc == b"LIST"{
+                let mut list_type = [0; 4];
+                reader.file.read_exact(&mut list_type)?;
+                match &list_type{
+                    b"hdrl" => reader.read_main_header()?,
+                    b"movi" => {
+                        //An unfinished recording still has the size of the empty list
+                        let end = if size <= 4 {file_length} else {(position + 8 + size).min(file_length)};
+                        reader.index_frames(position + 12, end)?;
+                        if size <= 4{
+                            break;
+                        }
+                    },
+                    _ => ()
+                }
+            }
+            position += 8 + size + size % 2;
+        }
+        Ok(reader)
+    }
+
+    /// Reads frame rate and size from the `avih` chunk, which starts the header list
+    fn read_main_header(&mut self) -> io::Result<()>{
+        let mut fourcc = [0; 4];
+        self.file.read_exact(&mut fourcc)?;
+        if &fourcc != b"avih"{
+            return Ok(());
+        }
+        read_u32(&mut self.file)?;
+        let micros_per_frame = read_u32(&mut self.file)?;
+        if micros_per_frame > 0{
+            self.frame_duration = Duration::from_micros(micros_per_frame as u64);
+        }
+        //Skip max bytes per second, padding, flags, frame count, initial frames, streams and buffer size
+        self.file.seek(SeekFrom::Current(7 * 4))?;
+        self.width = read_u32(&mut self.file)?;
+        self.height = read_u32(&mut self.file)?;
+        Ok(())
+    }
+
+    /// Collects the compressed video chunks (`##dc` or `##db`) between `start` and `end`
+    fn index_frames(&mut self, start: u64, end: u64) -> io::Result<()>{
+        let mut position = start;
+        let mut fourcc = [0; 4];
+        while position + 8 <= end{
+            self.file.seek(SeekFrom::Start(position))?;
+            self.file.read_exact(&mut fourcc)?;
+            let size = read_u32(&mut self.file)?;
+            //The last frame of an interrupted recording may be cut off
+            if position + 8 + size as u64 > end{
+                break;
+            }
+            if &fourcc[2..4] == b"dc" || &fourcc[2..4] == b"db"{
+                self.frames.push((position + 8, size));
+            }
+            position += 8 + size as u64 + size as u64 % 2;
+        }
+        Ok(())
+    }
+
+    pub fn frame_count(&self) -> usize{
+        self.frames.len()
+    }
+
+    /// Time each frame is shown at normal speed
+    pub fn frame_duration(&self) -> Duration{
+        self.frame_duration
+    }
+
+    pub fn width(&self) -> u32{
+        self.width
+    }
+
+    pub fn height(&self) -> u32{
+        self.height
+    }
+
+    /// Reads the JPEG data of a frame
+    pub fn read_frame(&mut self, index: usize) -> io::Result<Vec<u8>>{
+        let (offset, length) = *self.frames.get(index).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Frame index out of range"))?;
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0; length as usize];
+        self.file.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::{env, fs, path::PathBuf};
+
+    fn temp_path(name: &str) -> PathBuf{
+        env::temp_dir().join(format!("dashcam-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn single_frame_falls_back_to_default_frame_rate(){
+        let path = temp_path("single-frame.avi");
+        let mut writer = AviWriter::create(&path, 64, 48).unwrap();
+        writer.write_frame(b"jpeg").unwrap();
+        writer.finish(Duration::from_millis(0)).unwrap();
+        let reader = AviReader::open(&path).unwrap();
+        assert_eq!(reader.frame_duration(), Duration::from_micros(DEFAULT_MICROS_PER_FRAME as u64));
+        assert_eq!(reader.frame_count(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn write_recording(path: &Path, frames: &[&[u8]], duration: Duration, finish: bool){
+        let mut writer = AviWriter::create(path, 640, 360).unwrap();
+        for frame in frames{
+            writer.write_frame(frame).unwrap();
+        }
+        if finish{
+            writer.finish(duration).unwrap();
+        }
+    }
+
+    #[test]
+    fn reads_back_written_frames(){
+        let path = temp_path("round-trip.avi");
+        let frames: [&[u8]; 3] = [b"first", b"second", b"odd"];
+        write_recording(&path, &frames, Duration::from_millis(120), true);
+        let mut reader = AviReader::open(&path).unwrap();
+        assert_eq!((reader.width(), reader.height()), (640, 360));
+        assert_eq!(reader.frame_duration(), Duration::from_millis(40));
+        assert_eq!(reader.frame_count(), 3);
+        for (index, frame) in frames.iter().enumerate(){
+            assert_eq!(reader.read_frame(index).unwrap(), *frame);
+        }
+        assert!(reader.read_frame(3).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_interrupted_recording(){
+        let path = temp_path("interrupted.avi");
+        //Dropping the writer without finishing leaves the headers of the empty file, like a power loss would
+        write_recording(&path, &[b"first", b"second", b"third"], Duration::from_millis(0), false);
+        //Cut the last frame in half
+        let length = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(length - 3).unwrap();
+        let mut reader = AviReader::open(&path).unwrap();
+        assert_eq!(reader.frame_count(), 2);
+        assert_eq!(reader.read_frame(0).unwrap(), b"first");
+        assert_eq!(reader.read_frame(1).unwrap(), b"second");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_length_matches_finished_file(){
+        let path = temp_path("file-length.avi");
+        let mut writer = AviWriter::create(&path, 64, 48).unwrap();
+        writer.write_frame(b"odd").unwrap();
+        writer.write_frame(b"even").unwrap();
+        let expected = writer.file_length();
+        writer.finish(Duration::from_millis(80)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), expected);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn predicts_length_with_next_frame(){
+        let path = temp_path("next-frame-length.avi");
+        let mut writer = AviWriter::create(&path, 64, 48).unwrap();
+        let expected = writer.file_length_with_frame(3);
+        writer.write_frame(b"odd").unwrap();
+        assert_eq!(writer.file_length(), expected);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refuses_frames_past_32_bit_sizes(){
+        let path = temp_path("too-long.avi");
+        let mut writer = AviWriter::create(&path, 64, 48).unwrap();
+        //Pretend almost 4 GiB were written already
+        writer.movi_length = u32::MAX - 1000;
+        assert_eq!(writer.write_frame(&[0; 2000]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(writer.frame_count(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn create_new_keeps_existing_file(){
+        let path = temp_path("existing.avi");
+        fs::write(&path, b"recording").unwrap();
+        assert_eq!(AviWriter::create_new(&path, 64, 48).err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&path).unwrap(), b"recording");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,36 @@
+use dashcam::{calibrate, config::Config, gui::Gui, recorder, server::StreamServer, source};
+
+fn main() {
+    let config = Config::from_args();
+    if let Some(directory) = config.calibrate_lens.clone(){
+        if let Err(e) = calibrate::calibrate_lens(&directory, &config.lens_calibration_path()){
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(path) = config.play.clone(){
+        if let Err(e) = Gui::play(path, config){
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let mut source = match source::open(&config.source, &config.camera){
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let event_trigger = recorder::start_recorders(source.as_mut(), &config.recorder);
+    if let Some(address) = config.http_address(){
+        if let Err(e) = StreamServer::start(address, source.subscribe_frames(4), source.monitor()){
+            eprintln!("Error starting HTTP server on {}: {}", address, e);
+        }
+    }
+    if let Err(e) = Gui::start(source, config, event_trigger){
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,21 @@
+use dashcam::{config::Config, recorder, server::StreamServer, source};
+
+fn main() {
+    let mut config = Config::from_args();
+    //Without a window the stream is only visible over HTTP
+    config.headless = true;
+    let mut source = match source::open(&config.source, &config.camera){
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    recorder::start_recorders(source.as_mut(), &config.recorder);
+    if let Some(address) = config.http_address(){
+        if let Err(e) = StreamServer::start(address, source.subscribe_frames(4), source.monitor()){
+            eprintln!("Error starting HTTP server on {}: {}", address, e);
+        }
+    }
+    source.run();
+}
@@ -0,0 +1,358 @@
+//! Simulates the Wi-Fi camera, so the dashcam can be tested without sitting in the car.
+//!
+//! Listens for the TCP control connection like the camera does, answers the init sequence and keepalives
+//! and streams JPEG frames over UDP to the address the control connection came from.
+
+use std::{env, fs, io::{self, prelude::*}, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket}, path::{Path, PathBuf}, process, sync::{Arc, atomic::{AtomicBool, Ordering}}, thread, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use dashcam::{jpeg, source::test_pattern};
+
+//Control messages of the MRT_Camera protocol, see wificam.rs
+const INIT_SEQUENCE: [u8; 20] = [
+    0x01, 0x01, 0x02, 0x10,
+    0x02, 0x01, 0x03, 0x20, 0x02, 0x01, 0x03, 0x20,
+    0x0e, 0x01, 0xaf, 0xe0, 0x24, 0x01, 0xc0, 0x42
+];
+const KEEPALIVE_SEQUENCE: [u8; 4] = [0x0e, 0x01, 0xaf, 0xe0];
+const INITIALIZATION: [u8; 8] = [0x0D, 0x02, 0xD0, 0xD0, 0x0E, 0x01, 0xAF, 0xE0];
+const KEEPALIVE_ACKNOWLEDGEMENT: [u8; 4] = [0x0e, 0x01, 0xaf, 0xe0];
+const YELLOW_WIRE_HIGH: [u8; 4] = [0x0B, 0x01, 0xC0, 0xB0];
+const YELLOW_WIRE_LOW: [u8; 4] = [0x0B, 0x00, 0xC0, 0xB0];
+
+//Length of the header in front of every UDP packet
+const HEADER_LENGTH: usize = 9;
+//The dashcam receives packets into a 32 KiB buffer
+const MAX_PACKET_LENGTH: usize = 32 * 1024;
+const TEST_PATTERN_QUALITY: u8 = 80;
+
+const USAGE: &str = "Usage: fake-camera [OPTIONS]
+
+Simulates the Wi-Fi camera: answers the control connection and streams JPEG frames
+over UDP to the host that connected.
+
+Options:
+    --control-address <ip:port>  Address to accept the control connection on (default: 0.0.0.0:5252)
+    --video-port <port>          UDP port of the client the video is sent to (default: 5555)
+    --frames <directory>         Send the JPEG files in this directory in a loop instead of a test pattern
+    --width <pixels>             Width of the test pattern (default: 640)
+    --height <pixels>            Height of the test pattern (default: 360)
+    --fps <n>                    Frames sent per second (default: 25)
+    --packet-size <bytes>        Maximum JPEG bytes per UDP packet (default: 1400)
+    --loss <percent>             Share of UDP packets that are silently dropped (default: 0)
+    --reorder <percent>          Share of UDP packets swapped with the following packet (default: 0)
+    --yellow-wire <s>            Toggle the yellow wire every this many seconds, 0 never toggles it (default: 0)
+    --help                       Print this message
+";
+
+#[derive(Debug, Clone)]
+struct Options{
+    control_address: SocketAddr,
+    video_port: u16,
+    frames: Option<PathBuf>,
+    width: usize,
+    height: usize,
+    fps: f64,
+    packet_size: usize,
+    loss: f64,
+    reorder: f64,
+    yellow_wire: Option<Duration>
+}
+
+impl Default for Options{
+    fn default() -> Self {
+        Options{
+            control_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 5252),
+            video_port: 5555,
+            frames: None,
+            width: 640,
+            height: 360,
+            fps: 25.0,
+            packet_size: 1400,
+            loss: 0.0,
+            reorder: 0.0,
+            yellow_wire: None
+        }
+    }
+}
+
+impl Options{
+    /// Parses `--some-key value` and `--some-key=value` arguments
+    fn parse(args: Vec<String>) -> Result<Options, String>{
+        let mut options = Options::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next(){
+            if arg == "--help" || arg == "-h"{
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            let flag = match arg.strip_prefix("--"){
+                Some(flag) => flag,
+                None => return Err(format!("Unexpected argument {}", arg))
+            };
+            let (key, value) = match flag.find('='){
+                Some(index) => (&flag[..index], String::from(&flag[index + 1..])),
+                None => (flag, args.next().ok_or(format!("Missing value for --{}", flag))?)
+            };
+            options.set(key, &value)?;
+        }
+        if options.width == 0 || options.height == 0 || options.width > 0xFFFF || options.height > 0xFFFF{
+            return Err(String::from("Test pattern size must be between 1 and 65535 pixels"));
+        }
+        if options.fps <= 0.0{
+            return Err(String::from("--fps must be greater than 0"));
+        }
+        if options.packet_size == 0 || options.packet_size + HEADER_LENGTH > MAX_PACKET_LENGTH{
+            return Err(format!("--packet-size must be between 1 and {}", MAX_PACKET_LENGTH - HEADER_LENGTH));
+        }
+        Ok(options)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String>{
+        match key{
+            "control-address" => self.control_address = parse_value(key, value)?,
+            "video-port" => self.video_port = parse_value(key, value)?,
+            "frames" => self.frames = Some(PathBuf::from(value)),
+            "width" => self.width = parse_value(key, value)?,
+            "height" => self.height = parse_value(key, value)?,
+            "fps" => self.fps = parse_value(key, value)?,
+            "packet-size" => self.packet_size = parse_value(key, value)?,
+            "loss" => self.loss = parse_value(key, value)?,
+            "reorder" => self.reorder = parse_value(key, value)?,
+            "yellow-wire" => {
+                let seconds: f64 = parse_value(key, value)?;
+                self.yellow_wire = if seconds > 0.0 {Some(Duration::from_secs_f64(seconds))} else {None};
+            },
+            _ => return Err(format!("Unknown option --{}", key))
+        }
+        Ok(())
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String>{
+    value.parse().map_err(|_| format!("Invalid value for --{}: {}", key, value))
+}
+
+/// The images that are streamed
+enum Footage{
+    /// JPEG files sent as they are
+    Files(Vec<Vec<u8>>),
+    /// Generated colour bars with a moving square
+    TestPattern{ width: usize, height: usize }
+}
+
+impl Footage{
+    /// Loads all `.jpg` and `.jpeg` files of a directory, sorted by name
+    fn load(directory: &Path) -> io::Result<Footage>{
+        let mut paths: Vec<PathBuf> = fs::read_dir(directory)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| match path.extension().and_then(|extension| extension.to_str()){
+                Some(extension) => extension.eq_ignore_ascii_case("jpg") || extension.eq_ignore_ascii_case("jpeg"),
+                None => false
+            })
+            .collect();
+        paths.sort();
+        let mut files = Vec::new();
+        for path in paths{
+            let data = fs::read(&path)?;
+            if !data.is_empty(){
+                files.push(data);
+            }
+        }
+        if files.is_empty(){
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("No JPEG files in {}", directory.display())));
+        }
+        Ok(Footage::Files(files))
+    }
+
+    /// JPEG data of the `index`th frame sent
+    fn frame(&self, index: usize) -> Vec<u8>{
+        match self{
+            Footage::Files(files) => files[index % files.len()].clone(),
+            Footage::TestPattern{ width, height } => jpeg::encode_rgb(*width, *height, &test_pattern(*width, *height, index), TEST_PATTERN_QUALITY)
+        }
+    }
+}
+
+/// Xorshift generator, good enough to decide which packets to mess with
+struct Random(u64);
+
+impl Random{
+    fn new() -> Random{
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_nanos() as u64).unwrap_or(0);
+        Random(seed | 1)
+    }
+
+    fn next(&mut self) -> u64{
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// True with a probability of `percent` percent
+    fn chance(&mut self, percent: f64) -> bool{
+        percent > 0.0 && (self.next() % 1_000_000) as f64 / 10_000.0 < percent
+    }
+}
+
+/// Splits a frame into UDP packets, each with the 9 byte header the camera uses:
+/// packet number (u16), frame number, sub-packet number, total sub-packets and packet length including the header (u32), all big endian
+fn packetize(jpeg: &[u8], frame_number: u8, packet_number: &mut u16, packet_size: usize) -> Option<Vec<Vec<u8>>>{
+    //There can't be more than 255 packets per frame, so big frames need bigger packets
+    let payload_length = packet_size.max(jpeg.len().div_ceil(255));
+    if payload_length + HEADER_LENGTH > MAX_PACKET_LENGTH{
+        return None;
+    }
+    let chunks: Vec<&[u8]> = jpeg.chunks(payload_length).collect();
+    let total_sub_packets = chunks.len() as u8;
+    Some(chunks.into_iter().enumerate().map(|(sub_packet_number, chunk)| {
+        let mut packet = Vec::with_capacity(HEADER_LENGTH + chunk.len());
+        packet.extend_from_slice(&packet_number.to_be_bytes());
+        packet.extend_from_slice(&[frame_number, sub_packet_number as u8, total_sub_packets]);
+        packet.extend_from_slice(&((HEADER_LENGTH + chunk.len()) as u32).to_be_bytes());
+        packet.extend_from_slice(chunk);
+        *packet_number = packet_number.wrapping_add(1);
+        packet
+    }).collect())
+}
+
+/// Sends frames to `target` until `running` is cleared
+fn stream_video(target: SocketAddr, options: &Options, footage: &Footage, running: &AtomicBool) -> io::Result<()>{
+    let local_address = match target{
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local_address, 0))?;
+    let interval = Duration::from_secs_f64(1.0 / options.fps);
+    let mut random = Random::new();
+    let mut packet_number = 0u16;
+    let mut next_frame = Instant::now();
+    let mut index = 0;
+    while running.load(Ordering::Relaxed){
+        let jpeg = footage.frame(index);
+        match packetize(&jpeg, index as u8, &mut packet_number, options.packet_size){
+            Some(mut packets) => {
+                for i in 1..packets.len(){
+                    if random.chance(options.reorder){
+                        packets.swap(i - 1, i);
+                    }
+                }
+                for packet in packets{
+                    if !random.chance(options.loss){
+                        socket.send_to(&packet, target)?;
+                    }
+                }
+            },
+            None => eprintln!("Frame {} is too big to be sent ({} bytes)", index, jpeg.len())
+        }
+        index += 1;
+        //Keep the frame rate steady, but don't try to catch up after falling behind
+        next_frame += interval;
+        let now = Instant::now();
+        if next_frame > now{
+            thread::sleep(next_frame - now);
+        }else{
+            next_frame = now;
+        }
+    }
+    Ok(())
+}
+
+/// Answers the control connection of one client, streaming video to it once it sent the init sequence
+fn serve_client(mut stream: TcpStream, options: Arc<Options>, footage: Arc<Footage>) -> io::Result<()>{
+    let peer = stream.peer_addr()?;
+    eprintln!("Control connection from {}", peer);
+    let streaming = Arc::new(AtomicBool::new(false));
+    let result = run_control_session(&mut stream, peer, &options, &footage, &streaming);
+    //Stops the video thread, if it was started
+    streaming.store(false, Ordering::Relaxed);
+    eprintln!("Control connection from {} closed", peer);
+    result
+}
+
+fn run_control_session(stream: &mut TcpStream, peer: SocketAddr, options: &Arc<Options>, footage: &Arc<Footage>, streaming: &Arc<AtomicBool>) -> io::Result<()>{
+    //Every message is sent in its own segment, the dashcam expects one message per read
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+    let mut yellow_wire = false;
+    let mut last_toggle = Instant::now();
+    let mut buf = [0; 256];
+    loop{
+        if let Some(interval) = options.yellow_wire{
+            if streaming.load(Ordering::Relaxed) && last_toggle.elapsed() >= interval{
+                yellow_wire = !yellow_wire;
+                eprintln!("Yellow wire {}", if yellow_wire {"high"} else {"low"});
+                stream.write_all(if yellow_wire {&YELLOW_WIRE_HIGH} else {&YELLOW_WIRE_LOW})?;
+                last_toggle = Instant::now();
+            }
+        }
+        let length = match stream.read(&mut buf){
+            Ok(0) => return Ok(()),
+            Ok(length) => length,
+            Err(e) => match e.kind(){
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted => continue,
+                _ => return Err(e)
+            }
+        };
+        let mut message = &buf[..length];
+        if message.starts_with(&INIT_SEQUENCE){
+            stream.write_all(&INITIALIZATION)?;
+            if !streaming.swap(true, Ordering::Relaxed){
+                let target = SocketAddr::new(peer.ip(), options.video_port);
+                eprintln!("Streaming video to {}", target);
+                let (options, footage, streaming) = (options.clone(), footage.clone(), streaming.clone());
+                thread::spawn(move || {
+                    if let Err(e) = stream_video(target, &options, &footage, &streaming){
+                        eprintln!("Error sending video: {}", e);
+                    }
+                });
+                last_toggle = Instant::now();
+            }
+            message = &message[INIT_SEQUENCE.len()..];
+        }
+        //Several keepalives may arrive in one read
+        for chunk in message.chunks(KEEPALIVE_SEQUENCE.len()){
+            if chunk == KEEPALIVE_SEQUENCE{
+                stream.write_all(&KEEPALIVE_ACKNOWLEDGEMENT)?;
+            }else{
+                eprintln!("Unknown control message {:02x?}", chunk);
+            }
+        }
+    }
+}
+
+fn main(){
+    let options = match Options::parse(env::args().skip(1).collect()){
+        Ok(options) => Arc::new(options),
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(1);
+        }
+    };
+    let footage = match &options.frames{
+        Some(directory) => Footage::load(directory).unwrap_or_else(|e| {
+            eprintln!("Error loading frames from {}: {}", directory.display(), e);
+            process::exit(1);
+        }),
+        None => Footage::TestPattern{ width: options.width, height: options.height }
+    };
+    let footage = Arc::new(footage);
+    let listener = TcpListener::bind(options.control_address).unwrap_or_else(|e| {
+        eprintln!("Error listening on {}: {}", options.control_address, e);
+        process::exit(1);
+    });
+    eprintln!("Fake camera listening on {}", options.control_address);
+    for stream in listener.incoming(){
+        match stream{
+            Ok(stream) => {
+                let (options, footage) = (options.clone(), footage.clone());
+                thread::spawn(move || {
+                    if let Err(e) = serve_client(stream, options, footage){
+                        eprintln!("Control connection error: {}", e);
+                    }
+                });
+            },
+            Err(e) => eprintln!("Error accepting control connection: {}", e)
+        }
+    }
+}
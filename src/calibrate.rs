@@ -0,0 +1,776 @@
+use std::{collections::{HashMap, VecDeque}, f64::consts::PI, fs, path::{Path, PathBuf}, time::Instant};
+use crate::{decode::decode_jpeg_frame, frame::{EncodedFrame, Frame, PixelFormat}, lens::LensCalibration};
+
+//Smoothing before looking for corners, in pixels
+const BLUR_SIGMA: f32 = 1.2;
+//Corners need a saddle response of at least this fraction of the strongest one
+const RESPONSE_THRESHOLD: f32 = 0.01;
+//Only the strongest corner within this distance is kept
+const SUPPRESSION_RADIUS: i32 = 3;
+//Radius of the circle that has to cross two dark and two bright squares around a corner
+const CHECK_RADIUS: f32 = 4.0;
+const CHECK_SAMPLES: usize = 32;
+//Minimum brightness difference between the squares, 1 is black to white
+const MIN_CONTRAST: f32 = 0.15;
+//Half size of the window used to locate a corner to a fraction of a pixel
+const REFINE_RADIUS: i32 = 4;
+//Neighbouring corners have to be found within this fraction of the expected step
+const GRID_TOLERANCE: f64 = 0.3;
+//Maximum angle between the edges of the squares and the direction to the first neighbours
+const EDGE_TOLERANCE: f64 = 15.0 * PI / 180.0;
+//Corners of a single square grid found in one snapshot, smaller ones are ignored
+const MIN_CORNERS: usize = 12;
+const MIN_SNAPSHOTS: usize = 3;
+//Field of view assumed before calibrating, the cameras have lenses of about 170°
+const INITIAL_FIELD_OF_VIEW: f64 = 170.0 * PI / 180.0;
+//Rays further from the axis are not used to estimate the initial board positions
+const MAX_INITIAL_ANGLE: f64 = 80.0 * PI / 180.0;
+const MAX_ITERATIONS: usize = 200;
+//Parameters of the lens, followed by rotation and translation of the board in every snapshot
+const LENS_PARAMETERS: usize = 8;
+const POSE_PARAMETERS: usize = 6;
+
+/// Estimates the fisheye parameters of the camera from snapshots of a checkerboard and saves them to `output`.
+///
+/// All JPEG files in `directory` are searched for a checkerboard, which can be of any size and doesn't have to
+/// be fully visible. The board should be held at different angles and positions, especially near the edges of the image.
+pub fn calibrate_lens(directory: &Path, output: &Path) -> Result<(), String>{
+    let start = Instant::now();
+    let mut paths: Vec<PathBuf> = fs::read_dir(directory).map_err(|e| format!("Error reading {}: {}", directory.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().map(|extension| extension.eq_ignore_ascii_case("jpg") || extension.eq_ignore_ascii_case("jpeg")).unwrap_or(false))
+        .collect();
+    paths.sort();
+    let mut size = None;
+    let mut views = Vec::new();
+    for path in paths.iter(){
+        let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        let image = match load_image(path){
+            Ok(image) => image,
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                continue;
+            }
+        };
+        if *size.get_or_insert((image.width, image.height)) != (image.width, image.height){
+            eprintln!("{}: Skipped, the snapshots have to be of the same size", name);
+            continue;
+        }
+        let corners = find_corners(&image);
+        let grid = build_grid(&corners);
+        if grid.len() < MIN_CORNERS{
+            eprintln!("{}: No checkerboard found ({} corners)", name, corners.len());
+            continue;
+        }
+        eprintln!("{}: {} corners", name, grid.len());
+        views.push(View::new(grid));
+    }
+    let (width, height) = size.ok_or_else(|| format!("No JPEG snapshots in {}", directory.display()))?;
+    if views.len() < MIN_SNAPSHOTS{
+        return Err(format!("A checkerboard was found in {} snapshots, at least {} are needed", views.len(), MIN_SNAPSHOTS));
+    }
+    let (calibration, error) = Calibration::new(width, height, views)?.optimize();
+    eprintln!("Calibrated in {:.1} s, reprojection error {:.3} px", start.elapsed().as_secs_f64(), error);
+    calibration.save(output)?;
+    eprintln!("Saved lens calibration to {}", output.display());
+    Ok(())
+}
+
+/// Brightness of every pixel between 0 and 1
+struct GrayImage{
+    width: usize,
+    height: usize,
+    pixels: Vec<f32>
+}
+
+impl GrayImage{
+    fn from_frame(frame: &Frame) -> GrayImage{
+        let pixels = match frame.format{
+            PixelFormat::Luma8 => frame.pixels.iter().map(|value| *value as f32 / 255.0).collect(),
+            PixelFormat::Rgb24 => frame.pixels.chunks_exact(3)
+                .map(|rgb| (0.299 * rgb[0] as f32 + 0.587 * rgb[1] as f32 + 0.114 * rgb[2] as f32) / 255.0).collect()
+        };
+        GrayImage{
+            width: frame.width,
+            height: frame.height,
+            pixels
+        }
+    }
+
+    /// Pixel value, coordinates outside of the image are clamped to the edge
+    fn get(&self, x: i32, y: i32) -> f32{
+        let x = x.clamp(0, self.width as i32 - 1) as usize;
+        let y = y.clamp(0, self.height as i32 - 1) as usize;
+        self.pixels[y * self.width + x]
+    }
+
+    /// Bilinear interpolation between the pixels
+    fn sample(&self, x: f32, y: f32) -> f32{
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i32, y0 as i32);
+        let top = self.get(x0, y0) * (1.0 - fx) + self.get(x0 + 1, y0) * fx;
+        let bottom = self.get(x0, y0 + 1) * (1.0 - fx) + self.get(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+
+    /// Gaussian blur, applied horizontally and vertically
+    fn blur(&self, sigma: f32) -> GrayImage{
+        let radius = (sigma * 3.0).ceil() as i32;
+        let kernel: Vec<f32> = (-radius..=radius).map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp()).collect();
+        let sum: f32 = kernel.iter().sum();
+        let kernel: Vec<f32> = kernel.iter().map(|weight| weight / sum).collect();
+        let pass = |image: &GrayImage, horizontal: bool| {
+            let mut pixels = vec![0.0; image.pixels.len()];
+            for y in 0..image.height as i32{
+                for x in 0..image.width as i32{
+                    pixels[y as usize * image.width + x as usize] = kernel.iter().enumerate().map(|(i, weight)| {
+                        let offset = i as i32 - radius;
+                        weight * if horizontal {image.get(x + offset, y)} else {image.get(x, y + offset)}
+                    }).sum();
+                }
+            }
+            GrayImage{ width: image.width, height: image.height, pixels }
+        };
+        pass(&pass(self, true), false)
+    }
+}
+
+fn load_image(path: &Path) -> Result<GrayImage, String>{
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+    let frame = decode_jpeg_frame(&EncodedFrame{
+        data,
+        received: Instant::now(),
+        frame_number: 0,
+        sub_packets: 0
+    }).map_err(|e| e.to_string())?;
+    Ok(GrayImage::from_frame(&frame))
+}
+
+/// Inner corner of a checkerboard, where two dark and two bright squares meet
+struct Corner{
+    /// Position in pixels
+    position: [f64; 2],
+    /// Angles of the two edges between the squares
+    edges: [f64; 2]
+}
+
+fn find_corners(image: &GrayImage) -> Vec<Corner>{
+    let blurred = image.blur(BLUR_SIGMA);
+    let (width, height) = (image.width as i32, image.height as i32);
+    //Saddle points have a Hessian with a negative determinant
+    let mut response = vec![0.0f32; image.pixels.len()];
+    for y in 1..height - 1{
+        for x in 1..width - 1{
+            let p = |dx: i32, dy: i32| blurred.get(x + dx, y + dy);
+            let ixx = p(1, 0) - 2.0 * p(0, 0) + p(-1, 0);
+            let iyy = p(0, 1) - 2.0 * p(0, 0) + p(0, -1);
+            let ixy = (p(1, 1) - p(1, -1) - p(-1, 1) + p(-1, -1)) / 4.0;
+            response[(y * width + x) as usize] = (ixy * ixy - ixx * iyy).max(0.0);
+        }
+    }
+    let threshold = response.iter().cloned().fold(0.0, f32::max) * RESPONSE_THRESHOLD;
+    let mut corners: Vec<Corner> = Vec::new();
+    for y in SUPPRESSION_RADIUS..height - SUPPRESSION_RADIUS{
+        for x in SUPPRESSION_RADIUS..width - SUPPRESSION_RADIUS{
+            let value = response[(y * width + x) as usize];
+            if value <= threshold{
+                continue;
+            }
+            //Local maximum, ties go to the first pixel
+            let is_maximum = (-SUPPRESSION_RADIUS..=SUPPRESSION_RADIUS).all(|dy| (-SUPPRESSION_RADIUS..=SUPPRESSION_RADIUS).all(|dx| {
+                let other = response[((y + dy) * width + x + dx) as usize];
+                other < value || (other == value && (dy, dx) >= (0, 0))
+            }));
+            if !is_maximum{
+                continue;
+            }
+            let edges = match checker_edges(&blurred, x as f32, y as f32){
+                Some(edges) => edges,
+                None => continue
+            };
+            if let Some(position) = refine_corner(&blurred, x as f64, y as f64){
+                //Neighbouring maxima can converge to the same corner
+                if corners.iter().all(|other| distance(other.position, position) > 1.5){
+                    corners.push(Corner{ position, edges });
+                }
+            }
+        }
+    }
+    corners
+}
+
+/// Checks that a circle around the point crosses exactly two dark and two bright areas,
+/// returns the angles of the edges between them
+fn checker_edges(image: &GrayImage, x: f32, y: f32) -> Option<[f64; 2]>{
+    let samples: Vec<f32> = (0..CHECK_SAMPLES).map(|i| {
+        let angle = i as f32 * 2.0 * std::f32::consts::PI / CHECK_SAMPLES as f32;
+        image.sample(x + CHECK_RADIUS * angle.cos(), y + CHECK_RADIUS * angle.sin())
+    }).collect();
+    let min = samples.iter().cloned().fold(f32::MAX, f32::min);
+    let max = samples.iter().cloned().fold(f32::MIN, f32::max);
+    if max - min < MIN_CONTRAST{
+        return None;
+    }
+    let middle = (min + max) / 2.0;
+    //Angles where the circle crosses an edge, interpolated between the samples
+    let crossings: Vec<f64> = (0..CHECK_SAMPLES).filter_map(|i| {
+        let (a, b) = (samples[i], samples[(i + 1) % CHECK_SAMPLES]);
+        if (a > middle) == (b > middle){
+            return None;
+        }
+        Some((i as f64 + ((middle - a) / (b - a)) as f64) * 2.0 * PI / CHECK_SAMPLES as f64)
+    }).collect();
+    if crossings.len() != 4{
+        return None;
+    }
+    //Each edge crosses the circle twice on opposite sides
+    let edge = |first: f64, second: f64| (first.sin() - second.sin()).atan2(first.cos() - second.cos());
+    Some([edge(crossings[0], crossings[2]), edge(crossings[1], crossings[3])])
+}
+
+/// Moves a corner to the point all image gradients around it are pointing away from,
+/// returns None if it doesn't settle near the starting point
+fn refine_corner(image: &GrayImage, x: f64, y: f64) -> Option<[f64; 2]>{
+    let mut corner = [x, y];
+    for _ in 0..10{
+        let (cx, cy) = (corner[0].round() as i32, corner[1].round() as i32);
+        let (mut a11, mut a12, mut a22, mut b1, mut b2) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for dy in -REFINE_RADIUS..=REFINE_RADIUS{
+            for dx in -REFINE_RADIUS..=REFINE_RADIUS{
+                let (qx, qy) = (cx + dx, cy + dy);
+                let gx = ((image.get(qx + 1, qy) - image.get(qx - 1, qy)) / 2.0) as f64;
+                let gy = ((image.get(qx, qy + 1) - image.get(qx, qy - 1)) / 2.0) as f64;
+                let weight = (-((dx * dx + dy * dy) as f64) / (REFINE_RADIUS * REFINE_RADIUS) as f64).exp();
+                a11 += weight * gx * gx;
+                a12 += weight * gx * gy;
+                a22 += weight * gy * gy;
+                b1 += weight * (gx * gx * qx as f64 + gx * gy * qy as f64);
+                b2 += weight * (gx * gy * qx as f64 + gy * gy * qy as f64);
+            }
+        }
+        let determinant = a11 * a22 - a12 * a12;
+        if determinant.abs() < 1e-12{
+            return None;
+        }
+        let refined = [(a22 * b1 - a12 * b2) / determinant, (a11 * b2 - a12 * b1) / determinant];
+        if distance(refined, [x, y]) > REFINE_RADIUS as f64{
+            return None;
+        }
+        let moved = distance(refined, corner);
+        corner = refined;
+        if moved < 0.01{
+            break;
+        }
+    }
+    Some(corner)
+}
+
+fn distance(a: [f64; 2], b: [f64; 2]) -> f64{
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)).sqrt()
+}
+
+/// Arranges corners into the largest square grid, returns the position of each corner on the board
+/// (in squares, centred on the board) and in the image
+fn build_grid(corners: &[Corner]) -> Vec<([f64; 2], [f64; 2])>{
+    if corners.is_empty(){
+        return Vec::new();
+    }
+    let positions: Vec<[f64; 2]> = corners.iter().map(|corner| corner.position).collect();
+    //Start next to the middle of all corners, where the board most likely is
+    let count = positions.len() as f64;
+    let middle = [positions.iter().map(|p| p[0]).sum::<f64>() / count, positions.iter().map(|p| p[1]).sum::<f64>() / count];
+    let mut seeds: Vec<usize> = (0..positions.len()).collect();
+    seeds.sort_by(|a, b| distance(positions[*a], middle).total_cmp(&distance(positions[*b], middle)));
+    let mut best: HashMap<(i32, i32), usize> = HashMap::new();
+    for seed in seeds.into_iter().take(20){
+        let grid = grow_grid(&positions, corners[seed].edges, seed);
+        if grid.len() > best.len(){
+            best = grid;
+        }
+    }
+    let count = best.len() as f64;
+    let center = [best.keys().map(|(i, _)| *i as f64).sum::<f64>() / count, best.keys().map(|(_, j)| *j as f64).sum::<f64>() / count];
+    best.iter().map(|((i, j), index)| ([*i as f64 - center[0], *j as f64 - center[1]], positions[*index])).collect()
+}
+
+/// Follows the grid from `seed` to neighbouring corners as long as they are where the grid predicts them
+fn grow_grid(corners: &[[f64; 2]], edges: [f64; 2], seed: usize) -> HashMap<(i32, i32), usize>{
+    let origin = corners[seed];
+    //The first neighbours are the nearest corners along the edges of the squares
+    let neighbour = |angle: f64| {
+        let direction = [angle.cos(), angle.sin()];
+        (0..corners.len()).filter(|index| *index != seed).filter_map(|index| {
+            let offset = [corners[index][0] - origin[0], corners[index][1] - origin[1]];
+            let length = distance(corners[index], origin);
+            let along = offset[0] * direction[0] + offset[1] * direction[1];
+            //Either way along the edge, pointing in its direction
+            if along.abs() >= length * EDGE_TOLERANCE.cos(){
+                Some((length, [offset[0] * along.signum(), offset[1] * along.signum()]))
+            }else{
+                None
+            }
+        }).min_by(|a, b| a.0.total_cmp(&b.0)).map(|(_, axis)| axis)
+    };
+    let (first, second) = match (neighbour(edges[0]), neighbour(edges[1])){
+        (Some(first), Some(second)) => (first, second),
+        _ => return HashMap::new()
+    };
+
+    //Step to the next corner along each axis, updated as the grid grows since the distortion changes it
+    let mut grid = HashMap::new();
+    let mut axes: HashMap<(i32, i32), [[f64; 2]; 2]> = HashMap::new();
+    let mut used = vec![false; corners.len()];
+    grid.insert((0, 0), seed);
+    axes.insert((0, 0), [first, second]);
+    used[seed] = true;
+    let mut queue = VecDeque::new();
+    queue.push_back((0, 0));
+    while let Some((i, j)) = queue.pop_front(){
+        let point = corners[grid[&(i, j)]];
+        for (di, dj) in [(1, 0), (-1, 0), (0, 1), (0, -1)].iter(){
+            let key = (i + di, j + dj);
+            if grid.contains_key(&key){
+                continue;
+            }
+            //Extrapolate from the corners behind this one, which follows the bending of the lines
+            let behind = |steps: i32| grid.get(&(i - di * steps, j - dj * steps)).map(|index| corners[*index]);
+            let axis = axes[&(i, j)][if *di != 0 {0} else {1}];
+            let sign = (di + dj) as f64;
+            let predicted = match (behind(1), behind(2)){
+                (Some(b1), Some(b2)) => [3.0 * point[0] - 3.0 * b1[0] + b2[0], 3.0 * point[1] - 3.0 * b1[1] + b2[1]],
+                (Some(b1), None) => [2.0 * point[0] - b1[0], 2.0 * point[1] - b1[1]],
+                _ => [point[0] + sign * axis[0], point[1] + sign * axis[1]]
+            };
+            let step = distance(predicted, point);
+            let found = (0..corners.len()).filter(|index| !used[*index])
+                .min_by(|a, b| distance(corners[*a], predicted).total_cmp(&distance(corners[*b], predicted)));
+            if let Some(index) = found.filter(|index| distance(corners[*index], predicted) < GRID_TOLERANCE * step){
+                let mut new_axes = axes[&(i, j)];
+                new_axes[if *di != 0 {0} else {1}] = [(corners[index][0] - point[0]) * sign, (corners[index][1] - point[1]) * sign];
+                grid.insert(key, index);
+                axes.insert(key, new_axes);
+                used[index] = true;
+                queue.push_back(key);
+            }
+        }
+    }
+    grid
+}
+
+type Matrix3 = [[f64; 3]; 3];
+
+fn multiply(a: &Matrix3, b: &Matrix3) -> Matrix3{
+    let mut result = [[0.0; 3]; 3];
+    for (i, row) in result.iter_mut().enumerate(){
+        for (j, value) in row.iter_mut().enumerate(){
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    result
+}
+
+/// Rotation matrix of a rotation vector (axis times angle)
+fn rotation(vector: [f64; 3]) -> Matrix3{
+    let angle = (vector[0] * vector[0] + vector[1] * vector[1] + vector[2] * vector[2]).sqrt();
+    if angle < 1e-12{
+        return [[1.0, -vector[2], vector[1]], [vector[2], 1.0, -vector[0]], [-vector[1], vector[0], 1.0]];
+    }
+    let [x, y, z] = [vector[0] / angle, vector[1] / angle, vector[2] / angle];
+    let (sin, cos) = angle.sin_cos();
+    let c = 1.0 - cos;
+    [
+        [cos + x * x * c, x * y * c - z * sin, x * z * c + y * sin],
+        [y * x * c + z * sin, cos + y * y * c, y * z * c - x * sin],
+        [z * x * c - y * sin, z * y * c + x * sin, cos + z * z * c]
+    ]
+}
+
+/// The corners found in one snapshot and the position of the board in it
+struct View{
+    /// Position on the board and in the image of every corner
+    corners: Vec<([f64; 2], [f64; 2])>,
+    /// Initial rotation of the board, refined by a rotation vector during the optimization
+    base_rotation: Matrix3
+}
+
+impl View{
+    fn new(corners: Vec<([f64; 2], [f64; 2])>) -> View{
+        View{
+            corners,
+            base_rotation: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+        }
+    }
+
+    /// Differences between the projected and the detected corners in pixels, for lens parameters in pixels
+    fn residuals(&self, lens: &LensCalibration, pose: &[f64]) -> Vec<f64>{
+        let rotation = multiply(&rotation([pose[0], pose[1], pose[2]]), &self.base_rotation);
+        let mut residuals = Vec::with_capacity(self.corners.len() * 2);
+        for (board, image) in self.corners.iter(){
+            let point = [
+                rotation[0][0] * board[0] + rotation[0][1] * board[1] + pose[3],
+                rotation[1][0] * board[0] + rotation[1][1] * board[1] + pose[4],
+                rotation[2][0] * board[0] + rotation[2][1] * board[1] + pose[5]
+            ];
+            let projected = lens.project(point);
+            residuals.push(projected[0] - image[0]);
+            residuals.push(projected[1] - image[1]);
+        }
+        residuals
+    }
+}
+
+/// Lens parameters and board positions being fitted to the detected corners
+struct Calibration{
+    width: usize,
+    height: usize,
+    views: Vec<View>,
+    /// Lens parameters in pixels, then the pose of each view
+    parameters: Vec<f64>
+}
+
+impl Calibration{
+    /// Estimates the board positions with a lens of the typical field of view
+    fn new(width: usize, height: usize, mut views: Vec<View>) -> Result<Calibration, String>{
+        let focal_length = width as f64 / INITIAL_FIELD_OF_VIEW;
+        let (cx, cy) = ((width as f64 - 1.0) / 2.0, (height as f64 - 1.0) / 2.0);
+        let mut parameters = vec![focal_length, focal_length, cx, cy, 0.0, 0.0, 0.0, 0.0];
+        for view in views.iter_mut(){
+            //Turn the corners into points on an image plane at distance 1, like a pinhole camera would see them
+            let points: Vec<([f64; 2], [f64; 2])> = view.corners.iter().filter_map(|(board, image)| {
+                let (a, b) = ((image[0] - cx) / focal_length, (image[1] - cy) / focal_length);
+                let angle = (a * a + b * b).sqrt();
+                if angle > MAX_INITIAL_ANGLE{
+                    None
+                }else if angle < 1e-12{
+                    Some((*board, [a, b]))
+                }else{
+                    Some((*board, [a * angle.tan() / angle, b * angle.tan() / angle]))
+                }
+            }).collect();
+            let homography = homography(&points).ok_or("Could not estimate the position of a checkerboard")?;
+            let (rotation, translation) = pose_from_homography(&homography).ok_or("Could not estimate the position of a checkerboard")?;
+            view.base_rotation = rotation;
+            parameters.extend_from_slice(&[0.0, 0.0, 0.0, translation[0], translation[1], translation[2]]);
+        }
+        Ok(Calibration{
+            width,
+            height,
+            views,
+            parameters
+        })
+    }
+
+    fn lens(parameters: &[f64]) -> LensCalibration{
+        let mut lens = [0.0; LENS_PARAMETERS];
+        lens.copy_from_slice(&parameters[0..LENS_PARAMETERS]);
+        LensCalibration::from_parameters(&lens)
+    }
+
+    fn pose(parameters: &[f64], view: usize) -> &[f64]{
+        &parameters[LENS_PARAMETERS + view * POSE_PARAMETERS..LENS_PARAMETERS + (view + 1) * POSE_PARAMETERS]
+    }
+
+    fn residuals(&self, parameters: &[f64]) -> Vec<Vec<f64>>{
+        let lens = Calibration::lens(parameters);
+        self.views.iter().enumerate().map(|(i, view)| view.residuals(&lens, Calibration::pose(parameters, i))).collect()
+    }
+
+    fn cost(residuals: &[Vec<f64>]) -> f64{
+        residuals.iter().flatten().map(|residual| residual * residual).sum()
+    }
+
+    /// Levenberg-Marquardt optimization of the reprojection error,
+    /// returns the calibration relative to the image size and the remaining error in pixels
+    fn optimize(mut self) -> (LensCalibration, f64){
+        let mut residuals = self.residuals(&self.parameters);
+        let mut cost = Calibration::cost(&residuals);
+        let mut damping = 1e-3;
+        for _ in 0..MAX_ITERATIONS{
+            let (normal, gradient) = self.normal_equations(&residuals);
+            let mut improved = false;
+            while damping < 1e10{
+                let mut system = normal.clone();
+                for (i, row) in system.iter_mut().enumerate(){
+                    row[i] += damping * normal[i][i].max(1e-9);
+                }
+                let step = match solve(system, gradient.iter().map(|value| -value).collect()){
+                    Some(step) => step,
+                    None => {
+                        damping *= 10.0;
+                        continue;
+                    }
+                };
+                let candidate: Vec<f64> = self.parameters.iter().zip(step.iter()).map(|(parameter, step)| parameter + step).collect();
+                let candidate_residuals = self.residuals(&candidate);
+                let candidate_cost = Calibration::cost(&candidate_residuals);
+                if candidate_cost.is_finite() && candidate_cost < cost{
+                    improved = cost - candidate_cost > cost * 1e-10;
+                    self.parameters = candidate;
+                    residuals = candidate_residuals;
+                    cost = candidate_cost;
+                    damping = (damping / 10.0).max(1e-12);
+                    break;
+                }
+                damping *= 10.0;
+            }
+            if !improved{
+                break;
+            }
+        }
+        let corners: usize = self.views.iter().map(|view| view.corners.len()).sum();
+        let lens = Calibration::lens(&self.parameters);
+        let (width, height) = (self.width as f64, self.height as f64);
+        //Pixel centres are half a pixel into the image
+        let calibration = LensCalibration{
+            fx: lens.fx / width,
+            fy: lens.fy / height,
+            cx: (lens.cx + 0.5) / width,
+            cy: (lens.cy + 0.5) / height,
+            k: lens.k
+        };
+        (calibration, (cost / corners as f64).sqrt())
+    }
+
+    /// JᵀJ and Jᵀr with the Jacobian J of the residuals r, from finite differences
+    fn normal_equations(&self, residuals: &[Vec<f64>]) -> (Vec<Vec<f64>>, Vec<f64>){
+        let count = self.parameters.len();
+        //Columns of the Jacobian, each view only depends on the lens and its own pose
+        let mut columns: Vec<Vec<Option<Vec<f64>>>> = vec![vec![None; count]; self.views.len()];
+        let lens = Calibration::lens(&self.parameters);
+        for parameter in 0..count{
+            let step = 1e-6 * self.parameters[parameter].abs().max(1e-2);
+            let mut parameters = self.parameters.clone();
+            parameters[parameter] += step;
+            let changed_lens = Calibration::lens(&parameters);
+            for (i, view) in self.views.iter().enumerate(){
+                let pose_range = LENS_PARAMETERS + i * POSE_PARAMETERS..LENS_PARAMETERS + (i + 1) * POSE_PARAMETERS;
+                if parameter >= LENS_PARAMETERS && !pose_range.contains(&parameter){
+                    continue;
+                }
+                let view_lens = if parameter < LENS_PARAMETERS {&changed_lens} else {&lens};
+                let changed = view.residuals(view_lens, Calibration::pose(&parameters, i));
+                columns[i][parameter] = Some(changed.iter().zip(residuals[i].iter()).map(|(changed, residual)| (changed - residual) / step).collect());
+            }
+        }
+        let mut normal = vec![vec![0.0; count]; count];
+        let mut gradient = vec![0.0; count];
+        for (i, view_columns) in columns.iter().enumerate(){
+            let nonzero: Vec<(usize, &Vec<f64>)> = view_columns.iter().enumerate().filter_map(|(index, column)| column.as_ref().map(|column| (index, column))).collect();
+            for (a, column_a) in nonzero.iter(){
+                gradient[*a] += column_a.iter().zip(residuals[i].iter()).map(|(j, r)| j * r).sum::<f64>();
+                for (b, column_b) in nonzero.iter(){
+                    normal[*a][*b] += column_a.iter().zip(column_b.iter()).map(|(x, y)| x * y).sum::<f64>();
+                }
+            }
+        }
+        (normal, gradient)
+    }
+}
+
+/// Solves a linear system by Gaussian elimination, None if it is singular
+fn solve(mut matrix: Vec<Vec<f64>>, mut vector: Vec<f64>) -> Option<Vec<f64>>{
+    let n = vector.len();
+    for column in 0..n{
+        let pivot = (column..n).max_by(|a, b| matrix[*a][column].abs().total_cmp(&matrix[*b][column].abs()))?;
+        if matrix[pivot][column].is_nan() || matrix[pivot][column].abs() < 1e-300{
+            return None;
+        }
+        matrix.swap(column, pivot);
+        vector.swap(column, pivot);
+        let (pivot_rows, rows) = matrix.split_at_mut(column + 1);
+        let pivot_row = &pivot_rows[column];
+        for (offset, row) in rows.iter_mut().enumerate(){
+            let factor = row[column] / pivot_row[column];
+            for (value, pivot_value) in row[column..].iter_mut().zip(pivot_row[column..].iter()){
+                *value -= factor * pivot_value;
+            }
+            vector[column + 1 + offset] -= factor * vector[column];
+        }
+    }
+    let mut result = vec![0.0; n];
+    for row in (0..n).rev(){
+        let sum: f64 = (row + 1..n).map(|k| matrix[row][k] * result[k]).sum();
+        result[row] = (vector[row] - sum) / matrix[row][row];
+    }
+    Some(result)
+}
+
+/// Homography from board to image plane through the direct linear transform
+fn homography(points: &[([f64; 2], [f64; 2])]) -> Option<Matrix3>{
+    if points.len() < 4{
+        return None;
+    }
+    //Eigenvector of AᵀA with the smallest eigenvalue
+    let mut normal = [[0.0; 9]; 9];
+    for (board, image) in points.iter(){
+        let (x, y, u, v) = (board[0], board[1], image[0], image[1]);
+        let rows = [[x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y, -u], [0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y, -v]];
+        for row in rows.iter(){
+            for i in 0..9{
+                for j in 0..9{
+                    normal[i][j] += row[i] * row[j];
+                }
+            }
+        }
+    }
+    let h = smallest_eigenvector(normal);
+    Some([[h[0], h[1], h[2]], [h[3], h[4], h[5]], [h[6], h[7], h[8]]])
+}
+
+/// Eigenvector of a symmetric matrix with the smallest eigenvalue, found with Jacobi rotations
+fn smallest_eigenvector(mut matrix: [[f64; 9]; 9]) -> [f64; 9]{
+    let mut vectors = [[0.0; 9]; 9];
+    for (i, row) in vectors.iter_mut().enumerate(){
+        row[i] = 1.0;
+    }
+    for _ in 0..100{
+        let off_diagonal: f64 = (0..9).map(|i| (0..9).filter(|j| *j != i).map(|j| matrix[i][j] * matrix[i][j]).sum::<f64>()).sum();
+        if off_diagonal < 1e-22{
+            break;
+        }
+        for p in 0..9{
+            for q in p + 1..9{
+                if matrix[p][q].abs() < 1e-300{
+                    continue;
+                }
+                let theta = (matrix[q][q] - matrix[p][p]) / (2.0 * matrix[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                //Rotate columns p and q, then rows p and q
+                for row in matrix.iter_mut(){
+                    let (kp, kq) = (row[p], row[q]);
+                    row[p] = c * kp - s * kq;
+                    row[q] = s * kp + c * kq;
+                }
+                let (row_p, row_q) = (matrix[p], matrix[q]);
+                for k in 0..9{
+                    matrix[p][k] = c * row_p[k] - s * row_q[k];
+                    matrix[q][k] = s * row_p[k] + c * row_q[k];
+                }
+                for row in vectors.iter_mut(){
+                    let (kp, kq) = (row[p], row[q]);
+                    row[p] = c * kp - s * kq;
+                    row[q] = s * kp + c * kq;
+                }
+            }
+        }
+    }
+    let smallest = (0..9).min_by(|a, b| matrix[*a][*a].total_cmp(&matrix[*b][*b])).unwrap();
+    let mut vector = [0.0; 9];
+    for (i, value) in vector.iter_mut().enumerate(){
+        *value = vectors[i][smallest];
+    }
+    vector
+}
+
+/// Rotation and translation of the board from a homography onto the image plane at distance 1,
+/// None if the homography is degenerate
+fn pose_from_homography(h: &Matrix3) -> Option<(Matrix3, [f64; 3])>{
+    let column = |i: usize| [h[0][i], h[1][i], h[2][i]];
+    let norm = |v: [f64; 3]| (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    let (h1, h2, h3) = (column(0), column(1), column(2));
+    let mut scale = 2.0 / (norm(h1) + norm(h2));
+    //The board is in front of the camera
+    if h3[2] * scale < 0.0{
+        scale = -scale;
+    }
+    let r1 = [h1[0] * scale, h1[1] * scale, h1[2] * scale];
+    let translation = [h3[0] * scale, h3[1] * scale, h3[2] * scale];
+    //Make the axes orthonormal, the homography is only approximate
+    let r1_norm = norm(r1);
+    if !scale.is_finite() || !r1_norm.is_finite() || r1_norm < 1e-12{
+        return None;
+    }
+    let r1 = [r1[0] / r1_norm, r1[1] / r1_norm, r1[2] / r1_norm];
+    let r2 = [h2[0] * scale, h2[1] * scale, h2[2] * scale];
+    let dot = r1[0] * r2[0] + r1[1] * r2[1] + r1[2] * r2[2];
+    let r2 = [r2[0] - dot * r1[0], r2[1] - dot * r1[1], r2[2] - dot * r1[2]];
+    let r2_norm = norm(r2);
+    if !r2_norm.is_finite() || r2_norm < 1e-12{
+        return None;
+    }
+    let r2 = [r2[0] / r2_norm, r2[1] / r2_norm, r2[2] / r2_norm];
+    let r3 = [r1[1] * r2[2] - r1[2] * r2[1], r1[2] * r2[0] - r1[0] * r2[2], r1[0] * r2[1] - r1[1] * r2[0]];
+    Some(([[r1[0], r2[0], r3[0]], [r1[1], r2[1], r3[1]], [r1[2], r2[2], r3[2]]], translation))
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn solves_linear_system(){
+        let matrix = vec![vec![2.0, 1.0, -1.0], vec![-3.0, -1.0, 2.0], vec![-2.0, 1.0, 2.0]];
+        let result = solve(matrix, vec![8.0, -11.0, -3.0]).unwrap();
+        for (value, expected) in result.iter().zip([2.0, 3.0, -1.0].iter()){
+            assert!((value - expected).abs() < 1e-9, "{:?}", result);
+        }
+    }
+
+    #[test]
+    fn singular_system_has_no_solution(){
+        assert_eq!(solve(vec![vec![1.0, 2.0], vec![2.0, 4.0]], vec![1.0, 2.0]), None);
+        assert_eq!(solve(vec![vec![f64::NAN, 0.0], vec![0.0, 1.0]], vec![1.0, 2.0]), None);
+    }
+
+    #[test]
+    fn finds_smallest_eigenvector(){
+        //10 I - 9.5 u uᵀ has the eigenvalue 0.5 for u and 10 for everything orthogonal to it
+        let u = [1.0, -2.0, 0.5, 0.0, 3.0, 1.0, -1.0, 0.0, 2.0];
+        let length = u.iter().map(|value: &f64| value * value).sum::<f64>().sqrt();
+        let u: Vec<f64> = u.iter().map(|value| value / length).collect();
+        let mut matrix = [[0.0; 9]; 9];
+        for i in 0..9{
+            for j in 0..9{
+                matrix[i][j] = if i == j {10.0} else {0.0} - 9.5 * u[i] * u[j];
+            }
+        }
+        let vector = smallest_eigenvector(matrix);
+        let dot: f64 = vector.iter().zip(u.iter()).map(|(a, b)| a * b).sum();
+        assert!((dot.abs() - 1.0).abs() < 1e-9, "{:?}", vector);
+    }
+
+    #[test]
+    fn degenerate_homography_has_no_pose(){
+        assert!(pose_from_homography(&[[0.0; 3]; 3]).is_none());
+        assert!(pose_from_homography(&[[f64::NAN; 3]; 3]).is_none());
+    }
+
+    #[test]
+    fn recovers_synthetic_lens(){
+        let (width, height) = (640, 480);
+        //In pixels, as the optimization works with them
+        let lens = LensCalibration{ fx: 230.0, fy: 225.0, cx: 325.0, cy: 236.0, k: [-0.02, 0.003, 0.0, 0.0] };
+        let poses = [
+            ([0.0, 0.0, 0.0], [-3.5, -2.5, 6.0]),
+            ([0.5, 0.1, 0.0], [-3.0, -2.0, 5.0]),
+            ([-0.4, 0.5, 0.2], [-2.0, -3.0, 6.0]),
+            ([0.2, -0.6, -0.1], [-5.0, -1.0, 7.0]),
+            ([-0.3, -0.3, 0.3], [0.0, -2.0, 5.0])
+        ];
+        let views = poses.iter().map(|(rotation_vector, translation)| {
+            let rotation = rotation(*rotation_vector);
+            let corners = (0..8).flat_map(|x| (0..6).map(move |y| [x as f64, y as f64])).filter_map(|board| {
+                let point = [
+                    rotation[0][0] * board[0] + rotation[0][1] * board[1] + translation[0],
+                    rotation[1][0] * board[0] + rotation[1][1] * board[1] + translation[1],
+                    rotation[2][0] * board[0] + rotation[2][1] * board[1] + translation[2]
+                ];
+                let image = lens.project(point);
+                let inside = image[0] >= 0.0 && image[0] < width as f64 && image[1] >= 0.0 && image[1] < height as f64;
+                if inside {Some((board, image))} else {None}
+            }).collect();
+            View::new(corners)
+        }).collect();
+        let (calibration, error) = Calibration::new(width, height, views).unwrap().optimize();
+        assert!(error < 1e-3, "reprojection error {}", error);
+        let expected = [lens.fx / width as f64, lens.fy / height as f64, (lens.cx + 0.5) / width as f64, (lens.cy + 0.5) / height as f64];
+        let found = [calibration.fx, calibration.fy, calibration.cx, calibration.cy];
+        for (found, expected) in found.iter().zip(expected.iter()){
+            assert!((found - expected).abs() < 1e-4, "found {:?}, expected {:?}", calibration, expected);
+        }
+        assert!((calibration.k[0] - lens.k[0]).abs() < 1e-3, "{:?}", calibration);
+    }
+}
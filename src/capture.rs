@@ -0,0 +1,242 @@
+use std::{fs::File, io::{self, prelude::*, BufReader, BufWriter}, path::Path, sync::Mutex, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+/// First bytes of every capture file, the last one is the format version
+const MAGIC: [u8; 8] = *b"DCAMCAP1";
+//Buffered records are written out at least this often, so little is lost when the process is killed
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a captured packet is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind{
+    /// UDP datagram of the video stream, including its header
+    Video,
+    /// Message the camera sent over the TCP control connection
+    ControlReceived,
+    /// Message sent to the camera over the TCP control connection
+    ControlSent
+}
+
+impl RecordKind{
+    fn to_byte(self) -> u8{
+        match self{
+            RecordKind::Video => 0,
+            RecordKind::ControlReceived => 1,
+            RecordKind::ControlSent => 2
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<RecordKind>{
+        match byte{
+            0 => Some(RecordKind::Video),
+            1 => Some(RecordKind::ControlReceived),
+            2 => Some(RecordKind::ControlSent),
+            _ => None
+        }
+    }
+}
+
+/// A single packet read from a capture file
+#[derive(Debug, Clone)]
+pub struct CaptureRecord{
+    pub kind: RecordKind,
+    /// Time since the capture was started
+    pub timestamp: Duration,
+    pub data: Vec<u8>
+}
+
+/// Records the raw packets of a camera session.
+///
+/// The file starts with the magic bytes `DCAMCAP1` and the wall clock time the capture was started
+/// (u64, microseconds since the unix epoch). Every packet follows as a record of
+///
+/// * kind (u8): 0 = UDP video datagram, 1 = TCP message from the camera, 2 = TCP message to the camera
+/// * timestamp (u64): microseconds since the capture was started
+/// * length (u32): number of bytes of data
+/// * data
+///
+/// with all numbers big endian.
+pub struct CaptureWriter{
+    start: Instant,
+    state: Mutex<WriterState>
+}
+
+struct WriterState{
+    //None after a write error
+    file: Option<BufWriter<File>>,
+    last_flush: Instant
+}
+
+impl CaptureWriter{
+    pub fn create(path: &Path) -> io::Result<CaptureWriter>{
+        let mut file = BufWriter::new(File::create(path)?);
+        let start_time = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_micros() as u64).unwrap_or(0);
+        file.write_all(&MAGIC)?;
+        file.write_all(&start_time.to_be_bytes())?;
+        file.flush()?;
+        Ok(CaptureWriter{
+            start: Instant::now(),
+            state: Mutex::new(WriterState{
+                file: Some(file),
+                last_flush: Instant::now()
+            })
+        })
+    }
+
+    /// Appends a packet, capturing stops after the first write error
+    pub fn record(&self, kind: RecordKind, data: &[u8]){
+        let timestamp = self.start.elapsed();
+        let mut state = self.state.lock().unwrap();
+        let flush = state.last_flush.elapsed() >= FLUSH_INTERVAL;
+        let result = match &mut state.file{
+            Some(file) => CaptureWriter::write_record(file, kind, timestamp, data, flush),
+            None => return
+        };
+        match result{
+            Ok(()) if flush => state.last_flush = Instant::now(),
+            Ok(()) => (),
+            Err(e) => {
+                eprintln!("Error writing capture file, capturing stopped: {}", e);
+                state.file = None;
+            }
+        }
+    }
+
+    /// Writes out all buffered records, which also happens when the writer is dropped
+    pub fn flush(&self){
+        let mut state = self.state.lock().unwrap();
+        if let Some(file) = &mut state.file{
+            if let Err(e) = file.flush(){
+                eprintln!("Error writing capture file, capturing stopped: {}", e);
+                state.file = None;
+            }
+        }
+    }
+
+    fn write_record(file: &mut BufWriter<File>, kind: RecordKind, timestamp: Duration, data: &[u8], flush: bool) -> io::Result<()>{
+        file.write_all(&[kind.to_byte()])?;
+        file.write_all(&(timestamp.as_micros() as u64).to_be_bytes())?;
+        file.write_all(&(data.len() as u32).to_be_bytes())?;
+        file.write_all(data)?;
+        if flush{
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CaptureWriter{
+    fn drop(&mut self){
+        self.flush();
+    }
+}
+
+/// Reads the records of a file written by `CaptureWriter`
+pub struct CaptureReader{
+    reader: BufReader<File>,
+    /// When the capture was started
+    pub start_time: SystemTime
+}
+
+impl CaptureReader{
+    pub fn open(path: &Path) -> io::Result<CaptureReader>{
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0; 16];
+        reader.read_exact(&mut header)?;
+        if header[0..8] != MAGIC{
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a capture file"));
+        }
+        let mut start_time = [0; 8];
+        start_time.copy_from_slice(&header[8..16]);
+        Ok(CaptureReader{
+            reader,
+            start_time: UNIX_EPOCH + Duration::from_micros(u64::from_be_bytes(start_time))
+        })
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<CaptureRecord>>{
+        let mut kind = [0; 1];
+        if self.reader.read(&mut kind)? == 0{
+            return Ok(None);
+        }
+        let kind = RecordKind::from_byte(kind[0]).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Unknown record kind {}", kind[0])))?;
+        let mut header = [0; 12];
+        self.reader.read_exact(&mut header)?;
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&header[0..8]);
+        let length = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+        let mut data = vec![0; length as usize];
+        self.reader.read_exact(&mut data)?;
+        Ok(Some(CaptureRecord{
+            kind,
+            timestamp: Duration::from_micros(u64::from_be_bytes(timestamp)),
+            data
+        }))
+    }
+}
+
+impl Iterator for CaptureReader{
+    type Item = io::Result<CaptureRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_record(){
+            //Happens when the capturing process was killed while writing
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Capture file ends with an incomplete record"))),
+            result => result.transpose()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::{env, fs, path::PathBuf};
+
+    fn temp_path(name: &str) -> PathBuf{
+        env::temp_dir().join(format!("dashcam-test-{}-{}", std::process::id(), name))
+    }
+
+    fn write_capture(path: &Path){
+        let writer = CaptureWriter::create(path).unwrap();
+        writer.record(RecordKind::ControlSent, b"init");
+        writer.record(RecordKind::ControlReceived, b"ack");
+        writer.record(RecordKind::Video, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn reads_back_written_records(){
+        let path = temp_path("round-trip.cap");
+        write_capture(&path);
+        let reader = CaptureReader::open(&path).unwrap();
+        assert!(SystemTime::now().duration_since(reader.start_time).unwrap() < Duration::from_secs(60));
+        let records: Vec<CaptureRecord> = reader.collect::<io::Result<_>>().unwrap();
+        let contents: Vec<(RecordKind, &[u8])> = records.iter().map(|record| (record.kind, record.data.as_slice())).collect();
+        assert_eq!(contents, vec![
+            (RecordKind::ControlSent, &b"init"[..]),
+            (RecordKind::ControlReceived, &b"ack"[..]),
+            (RecordKind::Video, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9][..])
+        ]);
+        assert!(records.windows(2).all(|pair| pair[0].timestamp <= pair[1].timestamp));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reports_truncated_last_record(){
+        let path = temp_path("truncated.cap");
+        write_capture(&path);
+        let length = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(length - 3).unwrap();
+        let mut reader = CaptureReader::open(&path).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().data, b"init");
+        assert_eq!(reader.next().unwrap().unwrap().data, b"ack");
+        assert_eq!(reader.next().unwrap().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_files(){
+        let path = temp_path("not-a-capture.cap");
+        fs::write(&path, b"RIFF\0\0\0\0AVI LIST").unwrap();
+        assert_eq!(CaptureReader::open(&path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
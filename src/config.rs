@@ -0,0 +1,386 @@
+use std::{env, fs, net::SocketAddr, path::PathBuf, time::Duration};
+use crate::{guidelines::GuidelineConfig, recorder::RecorderConfig, reverse::ReverseConfig, source::SourceConfig, steering::SteeringConfig, transform::{ImageTransform, ScaleMode}, wificam::WifiCamConfig};
+
+const DEFAULT_CONFIG_PATH: &str = "dashcam.conf";
+const DEFAULT_HTTP_ADDRESS: &str = "0.0.0.0:8080";
+const DEFAULT_LENS_CALIBRATION_PATH: &str = "lens.conf";
+
+const USAGE: &str = "Usage: dashcam-gui [OPTIONS]
+       dashcam-headless [OPTIONS]
+
+Options:
+    --config <path>             Config file to load (default: ./dashcam.conf if present)
+    --camera-ip <ip>            IP address of the camera (default: 192.168.1.1)
+    --control-port <port>       TCP control port of the camera (default: 5252)
+    --bind-address <ip>         Local address to receive video on (default: 0.0.0.0)
+    --bind-port <port>          Local UDP port to receive video on (default: 5555)
+    --keepalive-interval <ms>   Interval between keepalive messages (default: 1000)
+    --connection-timeout <ms>   Time without answer until the camera is considered lost (default: 5000)
+    --max-reconnect-delay <ms>  Maximum delay between reconnection attempts (default: 10000)
+    --reorder-window <n>        Frames reassembled in parallel from interleaved packets (default: 4)
+    --frame-timeout <ms>        Time until a frame with missing packets is dropped (default: 500)
+    --decode-queue-length <n>   Frames waiting for decoding before the oldest is dropped (default: 2)
+    --decode-workers <n>        Number of threads decoding frames (default: 1)
+    --capture <path>            Record every packet of the camera session into this file
+    --replay <path>             Play back a capture file instead of connecting to the camera
+    --replay-speed <factor>     Playback speed of the capture, 0 plays it as fast as possible (default: 1)
+    --source <source>           Take the frames from camera, file:<path> (recorded AVI), directory:<path> (JPEG files),
+                                http://<host>[:port]/<path> (MJPEG stream) or test-pattern (default: camera)
+    --source-fps <n>            Frame rate of the directory and test pattern sources (default: 25)
+    --record-directory <path>   Enables loop recording into this directory
+    --segment-duration <s>      Length of a single recorded segment (default: 60)
+    --record-quota <MiB>        Disk space all loop segments may use together (default: 4096)
+    --pre-event <s>             Time before an event included in the event clip (default: 10)
+    --post-event <s>            Time after an event included in the event clip (default: 10)
+    --idle-mode <mode>          Window state while not reversing: normal, minimized, thumbnail or blank (default: normal)
+    --reverse-holdoff <ms>      Time to stay in reverse mode after the yellow wire went low (default: 2000)
+    --guideline-0-5m <y>        Screen height of the 0.5 m guideline, -1 is the bottom and 1 the top edge (default: -0.6)
+    --guideline-1m <y>          Screen height of the 1 m guideline (default: -0.2)
+    --guideline-2m <y>          Screen height of the 2 m guideline (default: 0.2)
+    --guideline-near-width <w>  Half the distance between the guidelines at the bottom edge, 1 is the full width (default: 0.6)
+    --guideline-far-width <w>   Half the distance between the guidelines at the 2 m mark (default: 0.3)
+    --guideline-center <x>      Horizontal offset of the guidelines (default: 0)
+    --mirror <bool>             Swap left and right of the image, like a rear-view mirror (default: false)
+    --flip <bool>               Swap top and bottom of the image (default: false)
+    --rotation <degrees>        Rotate the image clockwise by 0, 90, 180 or 270 degrees (default: 0)
+    --scale-mode <mode>         Fitting of the image into the window: fit (letterboxed), fill (cropped) or stretch (default: fit)
+    --lens-calibration <path>   Correct the fisheye distortion with this calibration file
+    --undistort-scale <f>       Zoom of the corrected image, smaller values show more of the edges (default: 1)
+    --calibrate-lens <path>     Calibrate the lens from checkerboard snapshots in this directory,
+                                the result is written to the lens calibration file (default: lens.conf)
+    --steering-source <source>  Draw trajectory lines following the steering angle read from udp:<ip:port>,
+                                tcp:<ip:port>, file:<path> or candump:<path> (`-` reads candump output from stdin)
+    --steering-can-id <hex>     CAN frame carrying the steering angle in candump input (default: 25)
+    --steering-can-offset <n>   Byte of the frame data the signed 16 bit big endian angle starts at (default: 0)
+    --steering-can-scale <f>    Degrees per unit of the CAN value (default: 1)
+    --steering-ratio <f>        Steering wheel angle per angle of the front wheels (default: 16)
+    --steering-invert <bool>    Bend the trajectory lines the other way (default: false)
+    --wheelbase <m>             Distance between the front and rear axle (default: 2.7)
+    --vehicle-width <m>         Width of the car the guidelines are calibrated to (default: 1.8)
+    --osd <bool>                Show the on-screen display with stream statistics at startup, O toggles it (default: false)
+    --http-address <ip:port>    Serve the stream over HTTP on this address (default for dashcam-headless: 0.0.0.0:8080)
+    --play <path>               Open a recorded AVI file in the player instead of showing the camera (dashcam-gui)
+    --help                      Print this message
+
+Every option can also be given in the config file as `key = value`,
+with dashes replaced by underscores (e.g. `camera_ip = 192.168.1.1`).
+dashcam-headless ignores the options of the window, the player and the lens calibration.
+";
+
+/// All settings of the application, merged from the config file and the command line.
+///
+/// The config file is a list of `key = value` lines, `#` starts a comment.
+/// Command line flags use the same keys and take precedence over the file.
+#[derive(Clone)]
+pub struct Config{
+    pub path: PathBuf,
+    pub camera: WifiCamConfig,
+    pub source: SourceConfig,
+    pub recorder: RecorderConfig,
+    pub reverse: ReverseConfig,
+    pub guidelines: GuidelineConfig,
+    pub steering: SteeringConfig,
+    pub transform: ImageTransform,
+    pub scale_mode: ScaleMode,
+    /// Calibration file of the fisheye lens
+    pub lens_calibration: Option<PathBuf>,
+    pub undistort_scale: f32,
+    /// Directory of checkerboard snapshots to calibrate the lens from, instead of showing the camera
+    pub calibrate_lens: Option<PathBuf>,
+    /// Show the stream statistics on top of the image
+    pub osd: bool,
+    /// Run without a window, set by `dashcam-headless` instead of the config
+    pub headless: bool,
+    /// Address of the MJPEG HTTP server, if it should be started
+    pub http_address: Option<SocketAddr>,
+    /// Recording to open in the player instead of connecting to the camera
+    pub play: Option<PathBuf>
+}
+
+impl Default for Config{
+    fn default() -> Self {
+        Config{
+            path: PathBuf::from(DEFAULT_CONFIG_PATH),
+            camera: WifiCamConfig::default(),
+            source: SourceConfig::default(),
+            recorder: RecorderConfig::default(),
+            reverse: ReverseConfig::default(),
+            guidelines: GuidelineConfig::default(),
+            steering: SteeringConfig::default(),
+            transform: ImageTransform::default(),
+            scale_mode: ScaleMode::Fit,
+            lens_calibration: None,
+            undistort_scale: 1.0,
+            calibrate_lens: None,
+            osd: false,
+            headless: false,
+            http_address: None,
+            play: None
+        }
+    }
+}
+
+impl Config{
+    /// Loads the config from the process arguments, printing the usage and exiting on errors
+    pub fn from_args() -> Config{
+        match Config::parse(env::args().skip(1).collect()){
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}\n\n{}", e, USAGE);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /// Address of the HTTP server, headless mode always serves the stream
+    pub fn http_address(&self) -> Option<SocketAddr>{
+        match self.http_address{
+            Some(address) => Some(address),
+            None if self.headless => DEFAULT_HTTP_ADDRESS.parse().ok(),
+            None => None
+        }
+    }
+
+    /// Where the lens calibration is written to
+    pub fn lens_calibration_path(&self) -> PathBuf{
+        self.lens_calibration.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_LENS_CALIBRATION_PATH))
+    }
+
+    fn parse(args: Vec<String>) -> Result<Config, String>{
+        let mut config = Config::default();
+        let options = Config::split_args(args)?;
+        //Load config file first, so command line flags can override it
+        let explicit_path = options.iter().find(|(key, _)| key == "config").map(|(_, value)| PathBuf::from(value));
+        if let Some(path) = explicit_path{
+            config.path = path;
+            config.load_file()?;
+        }else if config.path.exists(){
+            config.load_file()?;
+        }
+        for (key, value) in options.iter().filter(|(key, _)| key != "config"){
+            config.set(key, value)?;
+        }
+        Ok(config)
+    }
+
+    /// Turns `--some-key value` and `--some-key=value` into `("some_key", "value")` pairs
+    fn split_args(args: Vec<String>) -> Result<Vec<(String, String)>, String>{
+        let mut options = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next(){
+            if arg == "--help" || arg == "-h"{
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            let flag = match arg.strip_prefix("--"){
+                Some(flag) => flag,
+                None => return Err(format!("Unexpected argument {}", arg))
+            };
+            let (key, value) = match flag.find('='){
+                Some(index) => (&flag[..index], String::from(&flag[index + 1..])),
+                None => (flag, args.next().ok_or(format!("Missing value for --{}", flag))?)
+            };
+            options.push((key.replace('-', "_"), value));
+        }
+        Ok(options)
+    }
+
+    /// Writes `settings` into the config file, replacing the values of keys that are already in it
+    /// and keeping all other lines and comments
+    pub fn save_settings(&self, settings: &[(&str, String)]) -> Result<(), String>{
+        let contents = if self.path.exists(){
+            fs::read_to_string(&self.path).map_err(|e| format!("Error reading config file {}: {}", self.path.display(), e))?
+        }else{
+            String::new()
+        };
+        let mut saved = vec![false; settings.len()];
+        let mut lines: Vec<String> = contents.lines().map(|line| {
+            let mut parts = line.splitn(2, '#');
+            let (assignment, comment) = (parts.next().unwrap_or(""), parts.next());
+            let key = assignment.split('=').next().unwrap_or("").trim();
+            match settings.iter().position(|(setting, _)| *setting == key){
+                Some(index) => {
+                    saved[index] = true;
+                    let line = format!("{} = {}", key, settings[index].1);
+                    match comment{
+                        Some(comment) => format!("{} #{}", line, comment),
+                        None => line
+                    }
+                },
+                None => String::from(line)
+            }
+        }).collect();
+        for ((key, value), saved) in settings.iter().zip(saved.iter()){
+            if !saved{
+                lines.push(format!("{} = {}", key, value));
+            }
+        }
+        let mut contents = lines.join("\n");
+        contents.push('\n');
+        fs::write(&self.path, contents).map_err(|e| format!("Error writing config file {}: {}", self.path.display(), e))
+    }
+
+    fn load_file(&mut self) -> Result<(), String>{
+        let contents = fs::read_to_string(&self.path).map_err(|e| format!("Error reading config file {}: {}", self.path.display(), e))?;
+        for (line_number, line) in contents.lines().enumerate(){
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty(){
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = parts.next().ok_or(format!("{}:{}: Expected `key = value`", self.path.display(), line_number + 1))?.trim();
+            self.set(key, value).map_err(|e| format!("{}:{}: {}", self.path.display(), line_number + 1, e))?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String>{
+        match key{
+            "camera_ip" => self.camera.camera_ip = parse_value(key, value)?,
+            "control_port" => self.camera.control_port = parse_value(key, value)?,
+            "bind_address" => self.camera.bind_address = parse_value(key, value)?,
+            "bind_port" => self.camera.bind_port = parse_value(key, value)?,
+            "keepalive_interval" => self.camera.keepalive_interval = Duration::from_millis(parse_value(key, value)?),
+            "connection_timeout" => self.camera.connection_timeout = Duration::from_millis(parse_value(key, value)?),
+            "max_reconnect_delay" => self.camera.max_reconnect_delay = Duration::from_millis(parse_value(key, value)?),
+            "reorder_window" => self.camera.reorder_window = parse_value(key, value)?,
+            "frame_timeout" => self.camera.frame_timeout = Duration::from_millis(parse_value(key, value)?),
+            "decode_queue_length" => self.camera.decode_queue_length = parse_value(key, value)?,
+            "decode_workers" => self.camera.decode_workers = parse_in_range(key, value, 1, 64)?,
+            "capture" => self.camera.capture_file = Some(PathBuf::from(value)),
+            "replay" => self.camera.replay_file = Some(PathBuf::from(value)),
+            "replay_speed" => self.camera.replay_speed = parse_in_range(key, value, 0.0, 1000.0)?,
+            "source" => self.source.kind = parse_value(key, value)?,
+            "source_fps" => self.source.frame_rate = parse_in_range(key, value, 0.01, 1000.0)?,
+            "record_directory" => self.recorder.directory = Some(PathBuf::from(value)),
+            "segment_duration" => self.recorder.segment_duration = Duration::from_secs(parse_in_range(key, value, 1, 24 * 60 * 60)?),
+            "record_quota" => self.recorder.quota = parse_value::<u64>(key, value)?.checked_mul(1024 * 1024).ok_or(format!("Invalid value for {}: {}", key, value))?,
+            "pre_event" => self.recorder.pre_event = Duration::from_secs(parse_value(key, value)?),
+            "post_event" => self.recorder.post_event = Duration::from_secs(parse_value(key, value)?),
+            "idle_mode" => self.reverse.idle_mode = parse_value(key, value)?,
+            "reverse_holdoff" => self.reverse.holdoff = Duration::from_millis(parse_value(key, value)?),
+            "guideline_0_5m" => self.guidelines.marks[0] = parse_value(key, value)?,
+            "guideline_1m" => self.guidelines.marks[1] = parse_value(key, value)?,
+            "guideline_2m" => self.guidelines.marks[2] = parse_value(key, value)?,
+            "guideline_near_width" => self.guidelines.near_width = parse_value(key, value)?,
+            "guideline_far_width" => self.guidelines.far_width = parse_value(key, value)?,
+            "guideline_center" => self.guidelines.center = parse_value(key, value)?,
+            "mirror" => self.transform.mirror = parse_value(key, value)?,
+            "flip" => self.transform.flip = parse_value(key, value)?,
+            "rotation" => self.transform.rotation = parse_value(key, value)?,
+            "scale_mode" => self.scale_mode = parse_value(key, value)?,
+            "lens_calibration" => self.lens_calibration = Some(PathBuf::from(value)),
+            "undistort_scale" => self.undistort_scale = parse_in_range(key, value, 0.1, 10.0)?,
+            "calibrate_lens" => self.calibrate_lens = Some(PathBuf::from(value)),
+            "steering_source" => self.steering.source = Some(parse_value(key, value)?),
+            "steering_can_id" => self.steering.can_id = u32::from_str_radix(value.trim_start_matches("0x"), 16).map_err(|_| format!("Invalid value for {}: {}", key, value))?,
+            //CAN FD frames carry up to 64 bytes
+            "steering_can_offset" => self.steering.can_offset = parse_in_range(key, value, 0, 62)?,
+            "steering_can_scale" => self.steering.can_scale = parse_value(key, value)?,
+            "steering_ratio" => self.steering.steering_ratio = parse_in_range(key, value, 1.0, 100.0)?,
+            "steering_invert" => self.steering.invert = parse_value(key, value)?,
+            "wheelbase" => self.steering.wheelbase = parse_in_range(key, value, 0.5, 20.0)?,
+            "vehicle_width" => self.steering.vehicle_width = parse_in_range(key, value, 0.5, 5.0)?,
+            "osd" => self.osd = parse_value(key, value)?,
+            "http_address" => self.http_address = Some(parse_value(key, value)?),
+            "play" => self.play = Some(PathBuf::from(value)),
+            _ => return Err(format!("Unknown option {}", key))
+        }
+        Ok(())
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String>{
+    value.parse().map_err(|_| format!("Invalid value for {}: {}", key, value))
+}
+
+/// Parses a number that has to be between `min` and `max`, both included
+fn parse_in_range<T: std::str::FromStr + PartialOrd + std::fmt::Display>(key: &str, value: &str, min: T, max: T) -> Result<T, String>{
+    let parsed: T = parse_value(key, value)?;
+    //NaN fails both comparisons
+    if parsed >= min && parsed <= max{
+        Ok(parsed)
+    }else{
+        Err(format!("Invalid value for {}: {}, expected {} to {}", key, value, min, max))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::path::Path;
+    use crate::transform::Rotation;
+
+    fn temp_path(name: &str) -> PathBuf{
+        env::temp_dir().join(format!("dashcam-test-{}-{}", std::process::id(), name))
+    }
+
+    fn args(path: &Path, args: &[&str]) -> Vec<String>{
+        let mut all = vec![String::from("--config"), path.display().to_string()];
+        all.extend(args.iter().map(|arg| String::from(*arg)));
+        all
+    }
+
+    #[test]
+    fn command_line_overrides_file(){
+        let path = temp_path("override.conf");
+        fs::write(&path, "# Camera in the trunk\ncamera_ip = 10.0.0.2 # comment\n\ncontrol_port=6000\nmirror = true\n").unwrap();
+        let config = Config::parse(args(&path, &["--control-port", "7000", "--scale-mode=fill"])).unwrap();
+        assert_eq!(config.camera.camera_ip.to_string(), "10.0.0.2");
+        assert_eq!(config.camera.control_port, 7000);
+        assert_eq!(config.scale_mode, ScaleMode::Fill);
+        assert!(config.transform.mirror);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reports_line_of_invalid_file_entries(){
+        let path = temp_path("invalid.conf");
+        fs::write(&path, "mirror = true\nbind_port = many\n").unwrap();
+        let error = Config::parse(args(&path, &[])).err().unwrap();
+        assert!(error.ends_with(":2: Invalid value for bind_port: many"), "{}", error);
+        fs::write(&path, "mirror\n").unwrap();
+        assert!(Config::parse(args(&path, &[])).err().unwrap().ends_with(":1: Expected `key = value`"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_arguments(){
+        let path = temp_path("empty.conf");
+        fs::write(&path, "").unwrap();
+        assert!(Config::parse(args(&path, &["camera_ip"])).err().unwrap().starts_with("Unexpected argument"));
+        assert_eq!(Config::parse(args(&path, &["--camera-ip"])).err().unwrap(), "Missing value for --camera-ip");
+        assert_eq!(Config::parse(args(&path, &["--colour", "red"])).err().unwrap(), "Unknown option colour");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_values_out_of_range(){
+        let mut config = Config::default();
+        for (key, value) in [("undistort_scale", "0"), ("source_fps", "0"), ("segment_duration", "0"), ("steering_ratio", "NaN"),
+            ("steering_can_offset", "100"), ("decode_workers", "0"), ("record_quota", "18446744073709551615")].iter(){
+            assert!(config.set(key, value).is_err(), "{} = {}", key, value);
+        }
+        config.set("undistort_scale", "0.8").unwrap();
+        config.set("steering_can_offset", "6").unwrap();
+        assert_eq!((config.undistort_scale, config.steering.can_offset), (0.8, 6));
+        assert_eq!(config.set("wheelbase", "-1").err().unwrap(), "Invalid value for wheelbase: -1, expected 0.5 to 20");
+    }
+
+    #[test]
+    fn saved_settings_are_loaded_again(){
+        let path = temp_path("saved.conf");
+        fs::write(&path, "# Settings\nrotation = 90 # mounted sideways\ncamera_ip = 10.0.0.2\n").unwrap();
+        let mut config = Config::parse(args(&path, &[])).unwrap();
+        config.transform.rotation = Rotation::Half;
+        config.transform.flip = true;
+        config.save_settings(&config.transform.settings()).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, "# Settings\nrotation = 180 # mounted sideways\ncamera_ip = 10.0.0.2\nmirror = false\nflip = true\n");
+        let loaded = Config::parse(args(&path, &[])).unwrap();
+        assert_eq!(loaded.transform, config.transform);
+        assert_eq!(loaded.camera.camera_ip.to_string(), "10.0.0.2");
+        fs::remove_file(&path).unwrap();
+    }
+}
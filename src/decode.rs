@@ -0,0 +1,145 @@
+use std::{collections::VecDeque, fmt, sync::{Arc, Condvar, Mutex, atomic::{AtomicBool, Ordering}}, thread::{self, JoinHandle}, time::Instant};
+use arc_swap::ArcSwap;
+use jpeg_decoder::Decoder;
+use crate::{error::{DashcamError, ErrorEvents}, frame::{EncodedFrame, Frame, PixelFormat}, stats::WifiCamStats};
+
+/// Why a frame couldn't be decoded
+#[derive(Debug)]
+pub enum DecodeError{
+    /// Not a valid JPEG image
+    Jpeg(jpeg_decoder::Error),
+    /// The decoder finished without reading the image size
+    MissingMetadata,
+    UnsupportedFormat(jpeg_decoder::PixelFormat),
+    /// The decoded pixels don't match the size in the JPEG header
+    WrongSize{ expected: usize, actual: usize }
+}
+
+impl fmt::Display for DecodeError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            DecodeError::Jpeg(e) => write!(f, "Decoding error: {}", e),
+            DecodeError::MissingMetadata => write!(f, "Decoding error: no image size"),
+            DecodeError::UnsupportedFormat(format) => write!(f, "Unsupported pixel format {:?}", format),
+            DecodeError::WrongSize{ expected, actual } => write!(f, "Decoded {} bytes of pixels instead of {}", actual, expected)
+        }
+    }
+}
+
+/// Bounded queue between frame reassembly and decoding.
+///
+/// When the decoder can't keep up the oldest frame is dropped, so the picture is
+/// delayed by at most `capacity` frames and the UDP receiver never has to wait.
+pub struct FrameQueue{
+    frames: Mutex<VecDeque<Arc<EncodedFrame>>>,
+    available: Condvar,
+    capacity: usize,
+    closed: AtomicBool
+}
+
+impl FrameQueue{
+    pub fn new(capacity: usize) -> FrameQueue{
+        FrameQueue{
+            frames: Mutex::new(VecDeque::with_capacity(capacity)),
+            available: Condvar::new(),
+            capacity: capacity.max(1),
+            closed: AtomicBool::new(false)
+        }
+    }
+
+    /// Adds a frame, returns false if an older frame had to be dropped for it
+    pub fn push(&self, frame: Arc<EncodedFrame>) -> bool{
+        let mut frames = self.frames.lock().unwrap();
+        let mut dropped = false;
+        while frames.len() >= self.capacity{
+            frames.pop_front();
+            dropped = true;
+        }
+        frames.push_back(frame);
+        self.available.notify_one();
+        !dropped
+    }
+
+    /// Waits for the next frame, returns None once the queue is closed
+    pub fn pop(&self) -> Option<Arc<EncodedFrame>>{
+        let mut frames = self.frames.lock().unwrap();
+        loop{
+            if self.closed.load(Ordering::SeqCst){
+                return None;
+            }
+            if let Some(frame) = frames.pop_front(){
+                return Some(frame);
+            }
+            frames = self.available.wait(frames).unwrap();
+        }
+    }
+
+    /// Wakes up all waiting workers and lets them finish, frames still waiting are dropped
+    pub fn close(&self){
+        //Closing under the lock, so no worker can miss the notification between checking and waiting
+        let _frames = self.frames.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+        self.available.notify_all();
+    }
+}
+
+/// Spawns `workers` threads decoding frames from the queue into `last_frame` until it is closed
+pub fn start_decode_workers(queue: Arc<FrameQueue>, workers: usize, last_frame: Arc<ArcSwap<Frame>>, stats: Arc<WifiCamStats>, errors: ErrorEvents) -> Vec<JoinHandle<()>>{
+    (0..workers.max(1)).map(|_| {
+        let queue = queue.clone();
+        let last_frame = last_frame.clone();
+        let stats = stats.clone();
+        let errors = errors.clone();
+        thread::spawn(move || {
+            while let Some(encoded) = queue.pop(){
+                match decode_jpeg_frame(&encoded){
+                    Ok(frame) => {
+                        WifiCamStats::increment(&stats.frames_decoded);
+                        let frame = Arc::new(frame);
+                        //With several workers frames can finish out of order, never go back to an older one
+                        last_frame.rcu(|current| if frame.received > current.received {frame.clone()} else {current.clone()});
+                    },
+                    Err(e) => {
+                        match e{
+                            DecodeError::WrongSize{ .. } => WifiCamStats::increment(&stats.wrong_size_frames),
+                            _ => WifiCamStats::increment(&stats.decode_errors)
+                        }
+                        errors.report(DashcamError::from(e));
+                    }
+                }
+            }
+        })
+    }).collect()
+}
+
+pub fn decode_jpeg_frame(encoded: &EncodedFrame) -> Result<Frame, DecodeError>{
+    let decode_start = Instant::now();
+    let mut decoder = Decoder::new(&encoded.data[..]);
+    match decoder.decode(){
+        Ok(pixels) => {
+            let metadata = decoder.info().ok_or(DecodeError::MissingMetadata)?;
+            let format = match metadata.pixel_format{
+                jpeg_decoder::PixelFormat::RGB24 => PixelFormat::Rgb24,
+                jpeg_decoder::PixelFormat::L8 => PixelFormat::Luma8,
+                jpeg_decoder::PixelFormat::CMYK32 => return Err(DecodeError::UnsupportedFormat(metadata.pixel_format))
+            };
+            let frame = Frame{
+                width: metadata.width as usize,
+                height: metadata.height as usize,
+                format,
+                pixels,
+                received: encoded.received,
+                frame_number: encoded.frame_number,
+                sub_packets: encoded.sub_packets,
+                compressed_size: encoded.data.len(),
+                decode_duration: decode_start.elapsed()
+            };
+            let expected = frame.width * frame.height * frame.format.bytes_per_pixel();
+            if frame.pixels.len() != expected{
+                return Err(DecodeError::WrongSize{ expected, actual: frame.pixels.len() });
+            }
+            Ok(frame)
+        },
+        Err(e) => Err(DecodeError::Jpeg(e))
+    }
+}
@@ -0,0 +1,81 @@
+use std::{error::Error, fmt, io, sync::{Arc, Mutex, mpsc::{channel, Receiver, Sender}}, time::Duration};
+use crate::decode::DecodeError;
+
+/// Everything that can go wrong talking to the camera and showing its images
+#[derive(Debug)]
+pub enum DashcamError{
+    /// A socket couldn't be opened or broke down, `context` says which one
+    Network{ context: String, source: io::Error },
+    /// A file couldn't be opened or read, `context` says which one
+    Io{ context: String, source: io::Error },
+    /// The camera didn't behave as expected
+    Protocol(String),
+    Decode(DecodeError),
+    /// Window or context creation, shader compilation or linking failed
+    Gl(String),
+    /// A connection was lost, `cause` says why if it broke down rather than ended
+    Reconnecting{ cause: Option<Box<DashcamError>>, delay: Duration }
+}
+
+impl DashcamError{
+    pub fn network(context: impl Into<String>, source: io::Error) -> DashcamError{
+        DashcamError::Network{ context: context.into(), source }
+    }
+
+    pub fn io(context: impl Into<String>, source: io::Error) -> DashcamError{
+        DashcamError::Io{ context: context.into(), source }
+    }
+
+    pub fn reconnecting(cause: Option<DashcamError>, delay: Duration) -> DashcamError{
+        DashcamError::Reconnecting{ cause: cause.map(Box::new), delay }
+    }
+}
+
+impl fmt::Display for DashcamError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            DashcamError::Network{ context, source } | DashcamError::Io{ context, source } => write!(f, "{}: {}", context, source),
+            DashcamError::Protocol(message) => write!(f, "{}", message),
+            DashcamError::Decode(e) => write!(f, "{}", e),
+            DashcamError::Gl(message) => write!(f, "{}", message),
+            DashcamError::Reconnecting{ cause: Some(cause), delay } => write!(f, "{}, reconnecting in {} ms", cause, delay.as_millis()),
+            DashcamError::Reconnecting{ cause: None, delay } => write!(f, "Connection ended, reconnecting in {} ms", delay.as_millis())
+        }
+    }
+}
+
+impl Error for DashcamError{
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self{
+            DashcamError::Network{ source, .. } | DashcamError::Io{ source, .. } => Some(source),
+            DashcamError::Reconnecting{ cause: Some(cause), .. } => Some(cause.as_ref()),
+            _ => None
+        }
+    }
+}
+
+impl From<DecodeError> for DashcamError{
+    fn from(e: DecodeError) -> Self {
+        DashcamError::Decode(e)
+    }
+}
+
+/// Passes errors of background threads on to everyone who subscribed to them, and prints them
+#[derive(Clone, Default)]
+pub struct ErrorEvents{
+    subscribers: Arc<Mutex<Vec<Sender<Arc<DashcamError>>>>>
+}
+
+impl ErrorEvents{
+    pub fn subscribe(&self) -> Receiver<Arc<DashcamError>>{
+        let (sender, receiver) = channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn report(&self, error: DashcamError){
+        eprintln!("{}", error);
+        let error = Arc::new(error);
+        self.subscribers.lock().unwrap().retain(|subscriber| subscriber.send(error.clone()).is_ok());
+    }
+}
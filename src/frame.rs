@@ -0,0 +1,72 @@
+use std::time::{Duration, Instant};
+
+/// Layout of the pixels in a decoded frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat{
+    /// Three bytes per pixel, red, green and blue
+    Rgb24,
+    /// One byte per pixel, greyscale
+    Luma8
+}
+
+impl PixelFormat{
+    pub fn bytes_per_pixel(&self) -> usize{
+        match self{
+            PixelFormat::Rgb24 => 3,
+            PixelFormat::Luma8 => 1
+        }
+    }
+}
+
+/// A reassembled but still compressed camera image
+#[derive(Debug, Clone)]
+pub struct EncodedFrame{
+    /// JPEG bytes as sent by the camera
+    pub data: Vec<u8>,
+    /// When the last packet of the frame arrived
+    pub received: Instant,
+    /// Frame counter of the camera, wraps around
+    pub frame_number: u8,
+    /// Number of UDP packets the frame was split into
+    pub sub_packets: u8
+}
+
+/// A decoded camera image
+#[derive(Debug, Clone)]
+pub struct Frame{
+    pub width: usize,
+    pub height: usize,
+    pub format: PixelFormat,
+    pub pixels: Vec<u8>,
+    /// When the last packet of the frame arrived
+    pub received: Instant,
+    /// Frame counter of the camera, wraps around
+    pub frame_number: u8,
+    /// Number of UDP packets the frame was split into
+    pub sub_packets: u8,
+    /// Size of the JPEG data
+    pub compressed_size: usize,
+    /// Time it took to decode the JPEG data
+    pub decode_duration: Duration
+}
+
+impl Frame{
+    /// Placeholder until the first image arrives
+    pub fn empty() -> Frame{
+        Frame{
+            width: 0,
+            height: 0,
+            format: PixelFormat::Rgb24,
+            pixels: Vec::new(),
+            received: Instant::now(),
+            frame_number: 0,
+            sub_packets: 0,
+            compressed_size: 0,
+            decode_duration: Duration::from_secs(0)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.pixels.is_empty()
+    }
+}
@@ -1,67 +1,101 @@
-use arc_swap::ArcSwap;
 use gl::types::*;
-use glutin::{Api, GlRequest, dpi};
-use std::{ffi::CString, sync::Arc};
+use glutin::{Api, GlRequest, PossiblyCurrent, WindowedContext, dpi, event::MouseScrollDelta, event_loop::EventLoop, window::{Fullscreen, Window}};
+use std::{ffi::CString, path::{Path, PathBuf}, sync::mpsc::Sender, time::{Duration, Instant}};
 use std::mem;
 use std::ptr;
 use std::str;
-use crate::{Program, Shader, texture::Texture};
-
-const IMAGE_WIDTH: usize = 1280;
-const IMAGE_HEIGHT: usize = 720;
-const IMAGE_BYTES_PER_PIXEL: usize = 3;
-
+use crate::{config::Config, error::DashcamError, frame::{Frame, PixelFormat}, guidelines::{GuidelineConfig, GuidelineParameter}, lens::LensCalibration, osd::{FrameRate, TextOverlay}, overlay::{GuidelineOverlay, ImagePlacement, TimelineBar, TrajectoryOverlay}, player::Player, program::{Program, Shader}, reverse::{DisplayState, IdleMode, ReverseMode}, source::{FrameSource, SourceMetadata}, steering::SteeringInput, texture::Texture, transform::{ImageTransform, View}, wificam::{ConnectionState, TcpMessage}};
 
 
 const VERTEX_DATA: [GLfloat; 8] = [-1.0, -1.0, 1.0, -1.0, -1.0, 1.0, 1.0, 1.0];
+//Zoom factor per step of the mouse wheel
+const ZOOM_STEP: f32 = 1.25;
+//Scroll distance of touchpads counted as one step of the mouse wheel
+const PIXELS_PER_SCROLL_STEP: f64 = 50.0;
+//How long an error of the camera connection is shown in the title
+const ERROR_DISPLAY_TIME: Duration = Duration::from_secs(10);
 
-const VERTEX_SHADER_SOURCE: &'static str = "
+const VERTEX_SHADER_SOURCE: &str = "
 attribute vec2 position;
 attribute vec2 vertexUV;
-//uniform vec2 ul_corner;
-//uniform vec2 size;
+//Rotation, mirroring and flipping of the image
+uniform mat2 transform;
+//Size and position of the image in the window, for letterboxing, zoom and pan
+uniform vec2 scale;
+uniform vec2 offset;
 
 varying vec2 UV;
 
 void main(){
-    gl_Position = vec4(position, 0.0, 1.0);
-    UV = (position + 1.0) / 2.0;
+    gl_Position = vec4(position * scale + offset, 0.0, 1.0);
+    UV = (transform * position + 1.0) / 2.0;
 }
 ";
 
-const FRAGMENT_SHADER_SOURCE: &'static str = "
+const FRAGMENT_SHADER_SOURCE: &str = "
 precision highp float;
 varying vec2 UV;
 uniform sampler2D texture1;
+//Fisheye lens in the equidistant model, see lens.rs
+uniform bool undistort;
+uniform vec2 focal;
+uniform vec2 center;
+uniform vec4 distortion;
+//Focal length of the corrected image relative to the lens, smaller values show more of the edges
+uniform float undistortScale;
 
 void main(){
-    gl_FragColor = texture2D(texture1, UV);
+    vec2 uv = UV;
+    if(undistort){
+        //Angle from the optical axis of the ray a pinhole camera would show here, and where the lens puts it
+        vec2 ray = (UV - center) / (focal * undistortScale);
+        float r = length(ray);
+        float theta = atan(r);
+        float theta2 = theta * theta;
+        float thetaD = theta * (1.0 + theta2 * (distortion.x + theta2 * (distortion.y + theta2 * (distortion.z + theta2 * distortion.w))));
+        uv = center + focal * ray * (r > 0.000001 ? thetaD / r : 1.0);
+        if(uv.x < 0.0 || uv.x > 1.0 || uv.y < 0.0 || uv.y > 1.0){
+            gl_FragColor = vec4(0.0, 0.0, 0.0, 1.0);
+            return;
+        }
+    }
+    gl_FragColor = texture2D(texture1, uv);
 }
 ";
 
-pub struct Gui{
+/// Locations of the lens correction uniforms
+struct LensUniforms{
+    undistort: GLint,
+    focal: GLint,
+    center: GLint,
+    distortion: GLint,
+    scale: GLint
+}
 
+/// Fullscreen quad showing the latest frame, with the texture it is uploaded to
+struct VideoQuad{
+    program: Program,
+    vao: GLuint,
+    vbo: GLuint,
+    pos_attr: GLuint,
+    transform_uniform: GLint,
+    scale_uniform: GLint,
+    offset_uniform: GLint,
+    transform: ImageTransform,
+    lens_uniforms: LensUniforms,
+    /// Calibration of the lens, if it is corrected
+    lens: Option<LensCalibration>,
+    undistort: bool,
+    undistort_scale: f32,
+    texture: Texture
 }
 
-impl Gui{
-    pub fn start(camera_image: Arc<ArcSwap<Vec<u8>>>){
-        let event_loop = glutin::event_loop::EventLoop::new();
-        let window = glutin::window::WindowBuilder::new().with_inner_size(dpi::LogicalSize::new(1280, 720));
-        let gl_window = glutin::ContextBuilder::new()
-            .with_gl(GlRequest::Specific(Api::OpenGlEs, (2,0)))
-            .build_windowed(window, &event_loop)
-            .unwrap();
-    
-        // It is essential to make the context current before calling `gl::load_with`.
-        let gl_window = unsafe { gl_window.make_current() }.unwrap();
-    
-        // Load the OpenGL function pointers
-        gl::load_with(|symbol| gl_window.get_proc_address(symbol));
-    
+impl VideoQuad{
+    fn new(transform: &ImageTransform, lens: Option<LensCalibration>, undistort_scale: f32) -> Result<VideoQuad, DashcamError>{
         // Create GLSL shaders
-        let vertex = Shader::new(VERTEX_SHADER_SOURCE, gl::VERTEX_SHADER);
-        let fragment = Shader::new(FRAGMENT_SHADER_SOURCE,gl::FRAGMENT_SHADER);
-        let program = Program::new(vertex, fragment);
+        let vertex = Shader::new(VERTEX_SHADER_SOURCE, gl::VERTEX_SHADER)?;
+        let fragment = Shader::new(FRAGMENT_SHADER_SOURCE,gl::FRAGMENT_SHADER)?;
+        let program = Program::new(vertex, fragment)?;
     
         //Create vao and vbo
         let mut vao = 0;
@@ -77,68 +111,305 @@ impl Gui{
             gl::BufferData(
                 gl::ARRAY_BUFFER,
                 (VERTEX_DATA.len() * mem::size_of::<GLfloat>()) as GLsizeiptr,
-                mem::transmute(&VERTEX_DATA[0]),
+                VERTEX_DATA.as_ptr() as *const _,
                 gl::STATIC_DRAW,
             );
         }
 
+        //Create texture, it is resized to fit the first frame
+        let texture = Texture::new(1280, 720, PixelFormat::Rgb24);
 
-        //Create texture
-        let tex = Texture::new(1280, 720, false);
+        //Look up vertex position attribute, the pointer is set up again for every frame since the overlay uses its own buffer
+        let pos_attr = unsafe{
+            gl::GetAttribLocation(program.handle, CString::new("position").unwrap().as_ptr()) as GLuint
+        };
+        let uniform = |name: &str| unsafe{
+            gl::GetUniformLocation(program.handle, CString::new(name).unwrap().as_ptr())
+        };
+        let (transform_uniform, scale_uniform, offset_uniform) = (uniform("transform"), uniform("scale"), uniform("offset"));
+        let lens_uniforms = LensUniforms{
+            undistort: uniform("undistort"),
+            focal: uniform("focal"),
+            center: uniform("center"),
+            distortion: uniform("distortion"),
+            scale: uniform("undistortScale")
+        };
+        Ok(VideoQuad{
+            program,
+            vao,
+            vbo,
+            pos_attr,
+            transform_uniform,
+            scale_uniform,
+            offset_uniform,
+            transform: transform.clone(),
+            lens_uniforms,
+            undistort: lens.is_some(),
+            lens,
+            undistort_scale,
+            texture
+        })
+    }
 
-        //Use shader program
-        unsafe{
-            // Use shader program
-            gl::UseProgram(program.handle);
-            //gl::BindFragDataLocation(program.handle, 0, CString::new("color").unwrap().as_ptr());
-        }
+    /// Switches the lens correction on and off, returns whether it is on
+    fn toggle_undistortion(&mut self) -> bool{
+        self.undistort = !self.undistort && self.lens.is_some();
+        self.undistort
+    }
 
-        //Enable vertex position attribute array
-        unsafe{
-            let pos_attr = gl::GetAttribLocation(program.handle, CString::new("position").unwrap().as_ptr());
-            gl::EnableVertexAttribArray(pos_attr as GLuint);
+    fn set_transform(&mut self, transform: &ImageTransform){
+        self.transform = transform.clone();
+    }
+
+    fn upload(&mut self, frame: &Frame){
+        self.texture.update(frame);
+    }
+
+    /// Draws the image as placed by `view` in a window with the given aspect ratio (width / height)
+    /// Size, position and orientation of the image in the window, also used to keep the overlays on it
+    fn placement(&self, view: &mut View, window_aspect: f32) -> ImagePlacement{
+        let (scale, offset) = view.quad(self.transform.aspect_ratio(self.texture.width, self.texture.height), window_aspect);
+        ImagePlacement::new(scale, offset, &self.transform)
+    }
+
+    fn draw(&self, placement: &ImagePlacement){
+        unsafe {
+            gl::UseProgram(self.program.handle);
+            //Other layers bind their own textures
+            gl::BindTexture(gl::TEXTURE_2D, self.texture.handle);
+            gl::UniformMatrix2fv(self.transform_uniform, 1, gl::FALSE, placement.transform.as_ptr());
+            gl::Uniform2f(self.scale_uniform, placement.scale[0], placement.scale[1]);
+            gl::Uniform2f(self.offset_uniform, placement.offset[0], placement.offset[1]);
+            match self.lens.as_ref().filter(|_| self.undistort){
+                Some(lens) => {
+                    let uniforms = &self.lens_uniforms;
+                    gl::Uniform1i(uniforms.undistort, 1);
+                    gl::Uniform2f(uniforms.focal, lens.fx as f32, lens.fy as f32);
+                    gl::Uniform2f(uniforms.center, lens.cx as f32, lens.cy as f32);
+                    gl::Uniform4f(uniforms.distortion, lens.k[0] as f32, lens.k[1] as f32, lens.k[2] as f32, lens.k[3] as f32);
+                    gl::Uniform1f(uniforms.scale, self.undistort_scale);
+                },
+                None => gl::Uniform1i(self.lens_uniforms.undistort, 0)
+            }
+            gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo);
+            gl::EnableVertexAttribArray(self.pos_attr);
             gl::VertexAttribPointer(
-                pos_attr as GLuint,
+                self.pos_attr,
                 2,
                 gl::FLOAT,
                 gl::FALSE as GLboolean,
                 0,
                 ptr::null(),
             );
+            // Draw a triangle from the 3 vertices
+            gl::DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
         }
+    }
+}
 
+impl Drop for VideoQuad{
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteBuffers(1, &self.vbo);
+            gl::DeleteVertexArrays(1, &self.vao);
+        }
+    }
+}
+
+pub struct Gui{
+
+}
+
+impl Gui{
+    /// Shows the frames of `source` in a window until it is closed, only returns if the window can't be set up
+    pub fn start(mut source: Box<dyn FrameSource>, config: Config, event_trigger: Option<Sender<()>>) -> Result<(), DashcamError>{
+        let event_loop = glutin::event_loop::EventLoop::new();
+        let mut title = Gui::title(source.metadata().state, None, None);
+        let gl_window = Gui::create_window(&title, &event_loop)?;
+        //Orientation of the image, changed with M, F and R
+        let mut transform = config.transform.clone();
+        let mut video = VideoQuad::new(&transform, Gui::load_lens(&config), config.undistort_scale)?;
+        //Letterboxing changed with A, zoomed with the mouse wheel and moved by dragging
+        let mut view = View::new(config.scale_mode);
+        let mut cursor = (0.0, 0.0);
+        let mut dragging = false;
+
+        //Parking guidelines, calibrated with C, Tab, the arrow keys and S
+        let mut guideline_config = config.guidelines.clone();
+        let mut guidelines = GuidelineOverlay::new(&guideline_config)?;
+        let mut calibrating: Option<GuidelineParameter> = None;
+        //Trajectory lines following the steering angle, if a source is configured
+        let steering = config.steering.source.clone().map(|source| SteeringInput::start(source, &config.steering));
+        let trajectory = TrajectoryOverlay::new()?;
+        //Stream statistics, toggled with O
+        let osd = TextOverlay::new()?;
+        let mut show_osd = config.osd;
+        let mut frame_rate = FrameRate::new();
+        //Last state the camera reported, None until it sends one
+        let mut yellow_wire = None;
+        //Errors of the background threads, the latest one is shown for a while
+        let errors = source.subscribe_errors();
+        let mut last_error: Option<(String, Instant)> = None;
+
+        //Follow the yellow wire to switch between reversing and idle
+        let tcp_messages = source.subscribe_events();
+        let mut reverse_mode = ReverseMode::new(config.reverse.clone());
+        //Size the user gave the normal window, restored after reversing
+        let mut windowed_size = None;
+        Gui::apply_display_state(gl_window.window(), reverse_mode.state(), reverse_mode.state(), &mut windowed_size);
+
+        let mut last_uploaded = None;
         event_loop.run(move |event, _, control_flow| {
-            use glutin::event::{Event, WindowEvent};
+            use glutin::event::{ElementState, Event, KeyboardInput, MouseButton, VirtualKeyCode, WindowEvent};
             use glutin::event_loop::ControlFlow;
             *control_flow = ControlFlow::Poll;
             match event {
-                Event::LoopDestroyed => return,
+                //The process exits right after this without dropping anything, so finish the recordings here
+                Event::LoopDestroyed => source.shutdown(),
                 Event::WindowEvent { event, .. } => match event {
-                    WindowEvent::CloseRequested => {
-                        // Cleanup
-                        unsafe {
-                            gl::DeleteBuffers(1, &vbo);
-                            gl::DeleteVertexArrays(1, &vao);
-                        }
-                        *control_flow = ControlFlow::Exit
-                    },
+                    WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
                     WindowEvent::Resized(size) => {
                         unsafe{
                             gl::Viewport(0,0, size.width as i32, size.height as i32);
                         }
-                    }
+                    },
+                    WindowEvent::KeyboardInput { input: KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(key), .. }, .. } => match key{
+                        VirtualKeyCode::E => {
+                            //Lock an event clip by hand
+                            if let Some(trigger) = event_trigger.as_ref(){
+                                let _ = trigger.send(());
+                            }
+                        },
+                        VirtualKeyCode::C => {
+                            calibrating = match calibrating{
+                                Some(_) => None,
+                                None => Some(GuidelineParameter::HalfMeter)
+                            };
+                            guidelines.update(&guideline_config, calibrating);
+                        },
+                        VirtualKeyCode::Tab if calibrating.is_some() => {
+                            calibrating = calibrating.map(|parameter| parameter.next());
+                            guidelines.update(&guideline_config, calibrating);
+                        },
+                        VirtualKeyCode::Up | VirtualKeyCode::Right | VirtualKeyCode::Down | VirtualKeyCode::Left => {
+                            if let Some(parameter) = calibrating{
+                                let steps = if key == VirtualKeyCode::Up || key == VirtualKeyCode::Right {1} else {-1};
+                                guideline_config.adjust(parameter, steps);
+                                guidelines.update(&guideline_config, calibrating);
+                            }
+                        },
+                        VirtualKeyCode::M | VirtualKeyCode::F | VirtualKeyCode::R => {
+                            match key{
+                                VirtualKeyCode::M => transform.mirror = !transform.mirror,
+                                VirtualKeyCode::F => transform.flip = !transform.flip,
+                                _ => transform.rotation = transform.rotation.next()
+                            }
+                            video.set_transform(&transform);
+                            //Saved right away, the orientation only changes when the camera is mounted differently
+                            if let Err(e) = config.save_settings(&transform.settings()){
+                                eprintln!("{}", e);
+                            }
+                        },
+                        VirtualKeyCode::A => {
+                            view.mode = view.mode.next();
+                            if let Err(e) = config.save_settings(&[("scale_mode", String::from(view.mode.key()))]){
+                                eprintln!("{}", e);
+                            }
+                        },
+                        VirtualKeyCode::Z => view.reset(),
+                        VirtualKeyCode::O => show_osd = !show_osd,
+                        VirtualKeyCode::U => {
+                            let undistort = video.toggle_undistortion();
+                            eprintln!("Lens correction {}", if undistort {"on"} else {"off"});
+                        },
+                        VirtualKeyCode::S if calibrating.is_some() => {
+                            match config.save_settings(&guideline_config.settings()){
+                                Ok(()) => eprintln!("Saved guideline calibration to {}", config.path.display()),
+                                Err(e) => eprintln!("{}", e)
+                            }
+                        },
+                        _ => ()
+                    },
+                    WindowEvent::CursorMoved { position, .. } => {
+                        let position = Gui::cursor_position(gl_window.window(), position);
+                        if dragging{
+                            view.pan_by(position.0 - cursor.0, position.1 - cursor.1);
+                        }
+                        cursor = position;
+                    },
+                    WindowEvent::MouseWheel { delta, .. } => view.zoom_at(Gui::zoom_factor(delta), [cursor.0, cursor.1]),
+                    WindowEvent::MouseInput { state, button: MouseButton::Left, .. } => dragging = state == ElementState::Pressed,
                     _ => (),
                 },
+                Event::MainEventsCleared => {
+                    if let Some(error) = errors.try_iter().last(){
+                        last_error = Some((error.to_string(), Instant::now()));
+                    }
+                    if matches!(&last_error, Some((_, since)) if since.elapsed() > ERROR_DISPLAY_TIME){
+                        last_error = None;
+                    }
+                    //Show connection state changes, errors and the calibration in the window title
+                    let error = last_error.as_ref().map(|(error, _)| error.as_str());
+                    let new_title = Gui::title(source.metadata().state, error, calibrating.map(|parameter| (parameter, &guideline_config)));
+                    if new_title != title{
+                        gl_window.window().set_title(&new_title);
+                        title = new_title;
+                    }
+                    //Handled here instead of on redraw, since minimised windows might not get redrawn
+                    for message in tcp_messages.try_iter(){
+                        match message{
+                            TcpMessage::YellowWireHigh => yellow_wire = Some(true),
+                            TcpMessage::YellowWireLow => yellow_wire = Some(false),
+                            _ => ()
+                        }
+                        reverse_mode.handle_message(&message);
+                    }
+                    let previous_state = reverse_mode.state();
+                    if let Some(state) = reverse_mode.update(){
+                        Gui::apply_display_state(gl_window.window(), previous_state, state, &mut windowed_size);
+                    }
+                    gl_window.window().request_redraw();
+                },
                 Event::RedrawRequested(_) => {
-                    tex.update(&camera_image.load());
+                    let display_state = reverse_mode.state();
                     unsafe {
                         // Clear the screen to black
-                        gl::ClearColor(0.3, 0.3, 0.3, 1.0);
+                        if display_state == DisplayState::Idle(IdleMode::Blank){
+                            gl::ClearColor(0.0, 0.0, 0.0, 1.0);
+                        }else{
+                            gl::ClearColor(0.3, 0.3, 0.3, 1.0);
+                        }
                         gl::Clear(gl::COLOR_BUFFER_BIT);
-                        // Draw a triangle from the 3 vertices
-                        gl::DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
                     }
-                    gl_window.swap_buffers().unwrap();
+                    if display_state != DisplayState::Idle(IdleMode::Blank){
+                        //Only upload frames that weren't shown yet
+                        let frame = source.latest_frame();
+                        if last_uploaded != Some(frame.received){
+                            video.upload(&frame);
+                            last_uploaded = Some(frame.received);
+                            if !frame.is_empty(){
+                                frame_rate.record(frame.decode_duration);
+                            }
+                        }
+                        video.draw(&video.placement(&mut view, Gui::aspect_ratio(gl_window.window())));
+                    }
+                    if display_state == DisplayState::Reversing || calibrating.is_some(){
+                        //Image coordinates, so the lines follow the picture when it is rotated, zoomed or panned
+                        let placement = video.placement(&mut view, Gui::aspect_ratio(gl_window.window()));
+                        guidelines.draw(&placement);
+                        if let Some(angle) = steering.as_ref().and_then(|steering| steering.angle()){
+                            trajectory.draw(angle, &guideline_config, &config.steering, &placement);
+                        }
+                    }
+                    if show_osd && display_state != DisplayState::Idle(IdleMode::Blank){
+                        let size = gl_window.window().inner_size();
+                        let error = last_error.as_ref().map(|(error, _)| error.as_str());
+                        osd.draw(&Gui::osd_lines(&source.metadata(), &frame_rate, yellow_wire, error), (size.width, size.height));
+                    }
+                    if let Err(e) = gl_window.swap_buffers(){
+                        eprintln!("Error showing the frame: {}", e);
+                        *control_flow = ControlFlow::Exit;
+                    }
                 },
                 _ => {
                     gl_window.window().request_redraw();
@@ -147,4 +418,212 @@ impl Gui{
         });
     }
 
+    /// Opens a recorded AVI file in the player instead of showing the camera.
+    ///
+    /// Space plays and pauses, the left and right arrow keys step a single frame, up and down change the speed
+    /// and clicking or dragging on the timeline bar at the bottom seeks. The image is oriented and fitted into
+    /// the window like the camera image, it can be zoomed and moved in the same way.
+    pub fn play(path: PathBuf, config: Config) -> Result<(), DashcamError>{
+        let mut player = Player::open(&path).map_err(|e| DashcamError::io(format!("Error opening {}", path.display()), e))?;
+        let event_loop = glutin::event_loop::EventLoop::new();
+        let mut title = Gui::player_title(&path, &player);
+        let gl_window = Gui::create_window(&title, &event_loop)?;
+        let mut video = VideoQuad::new(&config.transform, Gui::load_lens(&config), config.undistort_scale)?;
+        let mut view = View::new(config.scale_mode);
+        let timeline = TimelineBar::new()?;
+
+        //Cursor position in normalized device coordinates
+        let mut cursor = (0.0, 0.0);
+        let mut seeking = false;
+        let mut dragging = false;
+        event_loop.run(move |event, _, control_flow| {
+            use glutin::event::{ElementState, Event, KeyboardInput, MouseButton, VirtualKeyCode, WindowEvent};
+            use glutin::event_loop::ControlFlow;
+            *control_flow = ControlFlow::Poll;
+            match event {
+                Event::WindowEvent { event, .. } => match event {
+                    WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                    WindowEvent::Resized(size) => {
+                        unsafe{
+                            gl::Viewport(0,0, size.width as i32, size.height as i32);
+                        }
+                    },
+                    WindowEvent::KeyboardInput { input: KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(key), .. }, .. } => match key{
+                        VirtualKeyCode::Space => player.toggle_playing(),
+                        VirtualKeyCode::Right => player.step(1),
+                        VirtualKeyCode::Left => player.step(-1),
+                        VirtualKeyCode::Up => player.faster(),
+                        VirtualKeyCode::Down => player.slower(),
+                        VirtualKeyCode::A => view.mode = view.mode.next(),
+                        VirtualKeyCode::Z => view.reset(),
+                        VirtualKeyCode::U => {
+                            video.toggle_undistortion();
+                        },
+                        _ => ()
+                    },
+                    WindowEvent::CursorMoved { position, .. } => {
+                        let position = Gui::cursor_position(gl_window.window(), position);
+                        //Keep following the cursor while the button is held, even when it leaves the bar
+                        if seeking{
+                            player.seek((position.0 + 1.0) / 2.0);
+                        }else if dragging{
+                            view.pan_by(position.0 - cursor.0, position.1 - cursor.1);
+                        }
+                        cursor = position;
+                    },
+                    WindowEvent::MouseWheel { delta, .. } => view.zoom_at(Gui::zoom_factor(delta), [cursor.0, cursor.1]),
+                    WindowEvent::MouseInput { state, button: MouseButton::Left, .. } => match state{
+                        ElementState::Pressed => match TimelineBar::hit(cursor.0, cursor.1){
+                            Some(progress) => {
+                                seeking = true;
+                                player.seek(progress);
+                            },
+                            None => dragging = true
+                        },
+                        ElementState::Released => {
+                            seeking = false;
+                            dragging = false;
+                        }
+                    },
+                    _ => (),
+                },
+                Event::MainEventsCleared => {
+                    let new_title = Gui::player_title(&path, &player);
+                    if new_title != title{
+                        gl_window.window().set_title(&new_title);
+                        title = new_title;
+                    }
+                    gl_window.window().request_redraw();
+                },
+                Event::RedrawRequested(_) => {
+                    if let Some(frame) = player.update(){
+                        video.upload(frame);
+                    }
+                    unsafe {
+                        gl::ClearColor(0.3, 0.3, 0.3, 1.0);
+                        gl::Clear(gl::COLOR_BUFFER_BIT);
+                    }
+                    video.draw(&video.placement(&mut view, Gui::aspect_ratio(gl_window.window())));
+                    timeline.draw(player.progress());
+                    if let Err(e) = gl_window.swap_buffers(){
+                        eprintln!("Error showing the frame: {}", e);
+                        *control_flow = ControlFlow::Exit;
+                    }
+                },
+                _ => (),
+            }
+        });
+    }
+
+    /// Creates the window with an OpenGL ES 2.0 context and loads the GL functions
+    fn create_window(title: &str, event_loop: &EventLoop<()>) -> Result<WindowedContext<PossiblyCurrent>, DashcamError>{
+        let window = glutin::window::WindowBuilder::new().with_inner_size(dpi::LogicalSize::new(1280, 720));
+        let gl_window = glutin::ContextBuilder::new()
+            .with_gl(GlRequest::Specific(Api::OpenGlEs, (2,0)))
+            .build_windowed(window.with_title(title), event_loop)
+            .map_err(|e| DashcamError::Gl(format!("Error creating window: {}", e)))?;
+    
+        // It is essential to make the context current before calling `gl::load_with`.
+        let gl_window = unsafe { gl_window.make_current() }
+            .map_err(|(_, e)| DashcamError::Gl(format!("Error activating OpenGL context: {}", e)))?;
+    
+        // Load the OpenGL function pointers
+        gl::load_with(|symbol| gl_window.get_proc_address(symbol));
+        Ok(gl_window)
+    }
+
+    /// Loads the configured lens calibration, the image is shown uncorrected if that fails
+    fn load_lens(config: &Config) -> Option<LensCalibration>{
+        match LensCalibration::load(config.lens_calibration.as_ref()?){
+            Ok(lens) => Some(lens),
+            Err(e) => {
+                eprintln!("{}", e);
+                None
+            }
+        }
+    }
+
+    /// Position of the cursor in normalized device coordinates
+    fn cursor_position(window: &Window, position: dpi::PhysicalPosition<f64>) -> (f32, f32){
+        let size = window.inner_size();
+        (position.x as f32 / size.width.max(1) as f32 * 2.0 - 1.0, 1.0 - position.y as f32 / size.height.max(1) as f32 * 2.0)
+    }
+
+    fn aspect_ratio(window: &Window) -> f32{
+        let size = window.inner_size();
+        size.width as f32 / size.height.max(1) as f32
+    }
+
+    /// Zoom factor of a mouse wheel or touchpad scroll, scrolling up zooms in
+    fn zoom_factor(delta: MouseScrollDelta) -> f32{
+        let steps = match delta{
+            MouseScrollDelta::LineDelta(_, y) => y,
+            MouseScrollDelta::PixelDelta(position) => (position.y / PIXELS_PER_SCROLL_STEP) as f32
+        };
+        ZOOM_STEP.powf(steps)
+    }
+
+    /// Changes the window from the `previous` to the new state, remembering the size of the normal window in `windowed_size`
+    fn apply_display_state(window: &Window, previous: DisplayState, state: DisplayState, windowed_size: &mut Option<dpi::PhysicalSize<u32>>){
+        if previous == DisplayState::Idle(IdleMode::Normal){
+            *windowed_size = Some(window.inner_size());
+        }
+        match state{
+            DisplayState::Reversing => {
+                //Bring the window to the front
+                window.set_minimized(false);
+                window.set_visible(true);
+                window.set_always_on_top(true);
+                window.set_fullscreen(Some(Fullscreen::Borderless(window.current_monitor())));
+            },
+            DisplayState::Idle(mode) => {
+                window.set_fullscreen(None);
+                window.set_always_on_top(mode == IdleMode::Thumbnail);
+                if mode == IdleMode::Thumbnail{
+                    window.set_inner_size(dpi::LogicalSize::new(320, 180));
+                }else if let Some(size) = *windowed_size{
+                    window.set_inner_size(size);
+                }
+                window.set_minimized(mode == IdleMode::Minimized);
+            }
+        }
+    }
+
+    fn title(connection_state: ConnectionState, error: Option<&str>, calibration: Option<(GuidelineParameter, &GuidelineConfig)>) -> String{
+        let state = match error{
+            Some(error) => format!("{:?}: {}", connection_state, error),
+            None => format!("{:?}", connection_state)
+        };
+        match calibration{
+            Some((parameter, guidelines)) => format!("Dashcam ({}) - Calibrating {} = {:.2} (Tab: next, arrows: adjust, S: save, C: done)",
+                state, parameter.key(), guidelines.get(parameter)),
+            None => format!("Dashcam ({})", state)
+        }
+    }
+
+    /// Text of the on-screen display
+    fn osd_lines(metadata: &SourceMetadata, frame_rate: &FrameRate, yellow_wire: Option<bool>, error: Option<&str>) -> Vec<String>{
+        let stats = &metadata.stats;
+        let yellow_wire = match yellow_wire{
+            Some(true) => "high",
+            Some(false) => "low",
+            None => "unknown"
+        };
+        let mut lines = vec![
+            format!("{:.1} fps, decoding {:.1} ms", frame_rate.fps(), frame_rate.decode_time().as_secs_f32() * 1000.0),
+            format!("Frames: {} dropped, {} incomplete, {} broken", stats.dropped_frames, stats.incomplete_frames, stats.decode_errors + stats.wrong_size_frames),
+            format!("Packets: {} incomplete, {} out of order", stats.incomplete_packets, stats.out_of_order_packets),
+            format!("Source: {} ({:?}), yellow wire {}", metadata.name, metadata.state, yellow_wire)
+        ];
+        if let Some(error) = error{
+            lines.push(format!("Error: {}", error));
+        }
+        lines
+    }
+
+    fn player_title(path: &Path, player: &Player) -> String{
+        let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        format!("Dashcam - {} (frame {}/{}, {}x{})", name, player.current_frame() + 1, player.frame_count(), player.speed(),
+            if player.is_playing() {""} else {", paused"})
+    }
 }
\ No newline at end of file
@@ -0,0 +1,153 @@
+//Change of a calibration value per key press
+const CALIBRATION_STEP: f32 = 0.01;
+/// Distances of the guideline marks in meters
+pub const MARK_DISTANCES: [f32; 3] = [0.5, 1.0, 2.0];
+
+/// Position of the parking guidelines on the camera image, from -1 to 1 before rotating, mirroring and flipping it.
+///
+/// The side lines run straight from the bottom edge of the image to the 2 m mark,
+/// so the guidelines form a trapezoid matching the perspective of the camera.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GuidelineConfig{
+    /// Horizontal offset of the centre, for cameras that are not mounted in the middle of the car
+    pub center: f32,
+    /// Half the width between the side lines at the bottom edge of the image
+    pub near_width: f32,
+    /// Half the width between the side lines at the 2 m mark
+    pub far_width: f32,
+    /// Height of the 0.5 m, 1 m and 2 m marks
+    pub marks: [f32; 3]
+}
+
+impl Default for GuidelineConfig{
+    fn default() -> Self {
+        GuidelineConfig{
+            center: 0.0,
+            near_width: 0.6,
+            far_width: 0.3,
+            marks: [-0.6, -0.2, 0.2]
+        }
+    }
+}
+
+impl GuidelineConfig{
+    /// The config file entries describing this calibration
+    pub fn settings(&self) -> Vec<(&'static str, String)>{
+        GuidelineParameter::ALL.iter().map(|parameter| (parameter.key(), format!("{:.3}", self.get(*parameter)))).collect()
+    }
+
+    pub fn get(&self, parameter: GuidelineParameter) -> f32{
+        match parameter{
+            GuidelineParameter::HalfMeter => self.marks[0],
+            GuidelineParameter::OneMeter => self.marks[1],
+            GuidelineParameter::TwoMeters => self.marks[2],
+            GuidelineParameter::NearWidth => self.near_width,
+            GuidelineParameter::FarWidth => self.far_width,
+            GuidelineParameter::Center => self.center
+        }
+    }
+
+    /// Moves a value by `steps` calibration steps, keeping it on screen
+    pub fn adjust(&mut self, parameter: GuidelineParameter, steps: i32){
+        let value = match parameter{
+            GuidelineParameter::HalfMeter => &mut self.marks[0],
+            GuidelineParameter::OneMeter => &mut self.marks[1],
+            GuidelineParameter::TwoMeters => &mut self.marks[2],
+            GuidelineParameter::NearWidth => &mut self.near_width,
+            GuidelineParameter::FarWidth => &mut self.far_width,
+            GuidelineParameter::Center => &mut self.center
+        };
+        *value = (*value + steps as f32 * CALIBRATION_STEP).clamp(-1.0, 1.0);
+    }
+
+    /// Half the width between the side lines at screen height `y`
+    pub fn half_width_at(&self, y: f32) -> f32{
+        let height = (self.marks[2] + 1.0).max(f32::EPSILON);
+        self.near_width + (self.far_width - self.near_width) * (y + 1.0) / height
+    }
+
+    /// Screen height of a point `distance` meters behind the bumper, interpolated between the marks
+    pub fn screen_y(&self, distance: f32) -> f32{
+        let mut previous = (0.0, -1.0);
+        for (mark_distance, y) in MARK_DISTANCES.iter().zip(self.marks.iter()){
+            if distance <= *mark_distance{
+                return previous.1 + (y - previous.1) * (distance - previous.0) / (mark_distance - previous.0);
+            }
+            previous = (*mark_distance, *y);
+        }
+        previous.1
+    }
+}
+
+/// A value of the guideline calibration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuidelineParameter{
+    HalfMeter,
+    OneMeter,
+    TwoMeters,
+    NearWidth,
+    FarWidth,
+    Center
+}
+
+impl GuidelineParameter{
+    const ALL: [GuidelineParameter; 6] = [
+        GuidelineParameter::HalfMeter, GuidelineParameter::OneMeter, GuidelineParameter::TwoMeters,
+        GuidelineParameter::NearWidth, GuidelineParameter::FarWidth, GuidelineParameter::Center
+    ];
+
+    /// Key of the value in the config file
+    pub fn key(&self) -> &'static str{
+        match self{
+            GuidelineParameter::HalfMeter => "guideline_0_5m",
+            GuidelineParameter::OneMeter => "guideline_1m",
+            GuidelineParameter::TwoMeters => "guideline_2m",
+            GuidelineParameter::NearWidth => "guideline_near_width",
+            GuidelineParameter::FarWidth => "guideline_far_width",
+            GuidelineParameter::Center => "guideline_center"
+        }
+    }
+
+    /// The parameter after this one, wrapping around
+    pub fn next(&self) -> GuidelineParameter{
+        let index = GuidelineParameter::ALL.iter().position(|parameter| parameter == self).unwrap_or(0);
+        GuidelineParameter::ALL[(index + 1) % GuidelineParameter::ALL.len()]
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32){
+        assert!((actual - expected).abs() < 1e-5, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn maps_distances_onto_the_marks(){
+        let config = GuidelineConfig::default();
+        assert_close(config.screen_y(0.0), -1.0);
+        for (distance, y) in MARK_DISTANCES.iter().zip(config.marks.iter()){
+            assert_close(config.screen_y(*distance), *y);
+        }
+        //Linear between the bumper and the first mark and between the marks
+        assert_close(config.screen_y(0.25), -0.8);
+        assert_close(config.screen_y(1.5), 0.0);
+        //Nothing is drawn past the last mark
+        assert_close(config.screen_y(5.0), config.marks[2]);
+    }
+
+    #[test]
+    fn narrows_side_lines_towards_the_last_mark(){
+        let config = GuidelineConfig::default();
+        assert_close(config.half_width_at(-1.0), config.near_width);
+        assert_close(config.half_width_at(config.marks[2]), config.far_width);
+        assert_close(config.half_width_at(-0.4), (config.near_width + config.far_width) / 2.0);
+    }
+
+    #[test]
+    fn keeps_width_finite_with_marks_at_the_bottom(){
+        let config = GuidelineConfig{ marks: [-1.0, -1.0, -1.0], ..GuidelineConfig::default() };
+        assert!(config.half_width_at(0.0).is_finite());
+    }
+}
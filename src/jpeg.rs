@@ -0,0 +1,338 @@
+//! Minimal baseline JPEG encoder, just enough to produce test pattern frames.
+//! Uses 4:4:4 YCbCr without subsampling and the example Huffman tables of the standard (Annex K).
+
+//Natural (row major) index of each coefficient in zigzag order
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5,
+    12, 19, 26, 33, 40, 48, 41, 34, 27, 20, 13, 6, 7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63
+];
+
+const LUMINANCE_QUANTIZATION: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61,
+    12, 12, 14, 19, 26, 58, 60, 55,
+    14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62,
+    18, 22, 37, 56, 68, 109, 103, 77,
+    24, 35, 55, 64, 81, 104, 113, 92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99
+];
+
+const CHROMINANCE_QUANTIZATION: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99
+];
+
+const DC_LUMINANCE_BITS: [u8; 16] = [0x00, 0x01, 0x05, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+const DC_CHROMINANCE_BITS: [u8; 16] = [0x00, 0x03, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+const DC_VALUES: [u8; 12] = [0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B];
+
+const AC_LUMINANCE_BITS: [u8; 16] = [0x00, 0x02, 0x01, 0x03, 0x03, 0x02, 0x04, 0x03, 0x05, 0x05, 0x04, 0x04, 0x00, 0x00, 0x01, 0x7D];
+const AC_LUMINANCE_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52, 0xD1, 0xF0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7,
+    0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5,
+    0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2,
+    0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA
+];
+const AC_CHROMINANCE_BITS: [u8; 16] = [0x00, 0x02, 0x01, 0x02, 0x04, 0x04, 0x03, 0x04, 0x07, 0x05, 0x04, 0x04, 0x00, 0x01, 0x02, 0x77];
+const AC_CHROMINANCE_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33, 0x52, 0xF0,
+    0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18, 0x19, 0x1A, 0x26,
+    0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5,
+    0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3,
+    0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA,
+    0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA
+];
+
+/// Code and length in bits for every symbol of a Huffman table
+struct HuffmanCodes{
+    codes: [(u16, u8); 256]
+}
+
+impl HuffmanCodes{
+    /// Assigns the canonical codes described by the `bits` counts (Annex C)
+    fn new(bits: &[u8; 16], values: &[u8]) -> HuffmanCodes{
+        let mut codes = [(0, 0); 256];
+        let mut code = 0u16;
+        let mut values = values.iter();
+        for (length, count) in bits.iter().enumerate(){
+            for _ in 0..*count{
+                if let Some(value) = values.next(){
+                    codes[*value as usize] = (code, length as u8 + 1);
+                }
+                code += 1;
+            }
+            code <<= 1;
+        }
+        HuffmanCodes{ codes }
+    }
+}
+
+/// Writes the entropy coded data, inserting the stuffing byte after every 0xFF
+struct BitWriter{
+    out: Vec<u8>,
+    buffer: u32,
+    count: u8
+}
+
+impl BitWriter{
+    fn write(&mut self, bits: u16, length: u8){
+        self.buffer = (self.buffer << length) | (bits as u32 & ((1 << length) - 1));
+        self.count += length;
+        while self.count >= 8{
+            let byte = (self.buffer >> (self.count - 8)) as u8;
+            self.out.push(byte);
+            if byte == 0xFF{
+                self.out.push(0x00);
+            }
+            self.count -= 8;
+        }
+        self.buffer &= (1 << self.count) - 1;
+    }
+
+    fn write_symbol(&mut self, table: &HuffmanCodes, symbol: u8){
+        let (code, length) = table.codes[symbol as usize];
+        self.write(code, length);
+    }
+
+    /// Pads the last byte with one bits
+    fn flush(&mut self){
+        if self.count > 0{
+            let padding = 8 - self.count;
+            self.write((1 << padding) - 1, padding);
+        }
+    }
+}
+
+/// Encodes tightly packed RGB pixels as a baseline JPEG, `quality` works like in libjpeg (1 to 100)
+pub fn encode_rgb(width: usize, height: usize, rgb: &[u8], quality: u8) -> Vec<u8>{
+    assert_eq!(rgb.len(), width * height * 3, "Pixel data doesn't match the image size");
+    assert!(width > 0 && height > 0 && width <= 0xFFFF && height <= 0xFFFF, "Unsupported image size");
+    let tables = [scale_quantization(&LUMINANCE_QUANTIZATION, quality), scale_quantization(&CHROMINANCE_QUANTIZATION, quality)];
+    let dc_codes = [HuffmanCodes::new(&DC_LUMINANCE_BITS, &DC_VALUES), HuffmanCodes::new(&DC_CHROMINANCE_BITS, &DC_VALUES)];
+    let ac_codes = [HuffmanCodes::new(&AC_LUMINANCE_BITS, &AC_LUMINANCE_VALUES), HuffmanCodes::new(&AC_CHROMINANCE_BITS, &AC_CHROMINANCE_VALUES)];
+
+    let mut out = Vec::with_capacity(width * height / 4);
+    write_headers(&mut out, width as u16, height as u16, &tables);
+
+    let cosines = dct_cosines();
+    let mut writer = BitWriter{ out, buffer: 0, count: 0 };
+    let mut previous_dc = [0i32; 3];
+    let mut block = [[0f32; 64]; 3];
+    for block_y in (0..height).step_by(8){
+        for block_x in (0..width).step_by(8){
+            //Convert to YCbCr, repeating the edge pixels where the image doesn't fill the block
+            for y in 0..8{
+                for x in 0..8{
+                    let pixel = ((block_y + y).min(height - 1) * width + (block_x + x).min(width - 1)) * 3;
+                    let (r, g, b) = (rgb[pixel] as f32, rgb[pixel + 1] as f32, rgb[pixel + 2] as f32);
+                    block[0][y * 8 + x] = 0.299 * r + 0.587 * g + 0.114 * b - 128.0;
+                    block[1][y * 8 + x] = -0.168_736 * r - 0.331_264 * g + 0.5 * b;
+                    block[2][y * 8 + x] = 0.5 * r - 0.418_688 * g - 0.081_312 * b;
+                }
+            }
+            for component in 0..3{
+                let table = if component == 0 {0} else {1};
+                let coefficients = quantize(&forward_dct(&block[component], &cosines), &tables[table]);
+                encode_block(&mut writer, &coefficients, &mut previous_dc[component], &dc_codes[table], &ac_codes[table]);
+            }
+        }
+    }
+    writer.flush();
+    let mut out = writer.out;
+    out.extend_from_slice(&[0xFF, 0xD9]);
+    out
+}
+
+fn scale_quantization(table: &[u16; 64], quality: u8) -> [u16; 64]{
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 {5000 / quality} else {200 - quality * 2};
+    let mut scaled = [0; 64];
+    for (scaled, value) in scaled.iter_mut().zip(table.iter()){
+        *scaled = ((*value as u32 * scale + 50) / 100).clamp(1, 255) as u16;
+    }
+    scaled
+}
+
+fn write_headers(out: &mut Vec<u8>, width: u16, height: u16, tables: &[[u16; 64]; 2]){
+    //Start of image and JFIF marker
+    out.extend_from_slice(&[0xFF, 0xD8]);
+    out.extend_from_slice(&[0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F', 0x00, 0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00]);
+    //Quantization tables, in zigzag order
+    out.extend_from_slice(&[0xFF, 0xDB, 0x00, 2 + 2 * 65]);
+    for (id, table) in tables.iter().enumerate(){
+        out.push(id as u8);
+        out.extend(ZIGZAG.iter().map(|index| table[*index] as u8));
+    }
+    //Baseline frame with three components, chrominance uses the second set of tables
+    out.extend_from_slice(&[0xFF, 0xC0, 0x00, 17, 8]);
+    out.extend_from_slice(&height.to_be_bytes());
+    out.extend_from_slice(&width.to_be_bytes());
+    out.extend_from_slice(&[3, 1, 0x11, 0, 2, 0x11, 1, 3, 0x11, 1]);
+    //Huffman tables
+    let huffman_tables: [(u8, &[u8; 16], &[u8]); 4] = [
+        (0x00, &DC_LUMINANCE_BITS, &DC_VALUES),
+        (0x10, &AC_LUMINANCE_BITS, &AC_LUMINANCE_VALUES),
+        (0x01, &DC_CHROMINANCE_BITS, &DC_VALUES),
+        (0x11, &AC_CHROMINANCE_BITS, &AC_CHROMINANCE_VALUES)
+    ];
+    let length = 2 + huffman_tables.iter().map(|(_, _, values)| 17 + values.len()).sum::<usize>();
+    out.extend_from_slice(&[0xFF, 0xC4]);
+    out.extend_from_slice(&(length as u16).to_be_bytes());
+    for (class_and_id, bits, values) in huffman_tables.iter(){
+        out.push(*class_and_id);
+        out.extend_from_slice(*bits);
+        out.extend_from_slice(values);
+    }
+    //Start of scan over all components and coefficients
+    out.extend_from_slice(&[0xFF, 0xDA, 0x00, 12, 3, 1, 0x00, 2, 0x11, 3, 0x11, 0, 63, 0]);
+}
+
+/// Basis functions of the DCT by frequency and position, including the scaling of the JPEG standard
+fn dct_cosines() -> [[f32; 8]; 8]{
+    let mut cosines = [[0f32; 8]; 8];
+    for (frequency, row) in cosines.iter_mut().enumerate(){
+        let scale = if frequency == 0 {0.5 / 2f32.sqrt()} else {0.5};
+        for (position, cosine) in row.iter_mut().enumerate(){
+            *cosine = scale * ((2 * position + 1) as f32 * frequency as f32 * std::f32::consts::PI / 16.0).cos();
+        }
+    }
+    cosines
+}
+
+/// Separable 8x8 DCT-II
+fn forward_dct(block: &[f32; 64], cosines: &[[f32; 8]; 8]) -> [f32; 64]{
+    let mut coefficients = [0f32; 64];
+    //Flat blocks are common in the test pattern and only have a DC coefficient
+    if block.iter().all(|value| *value == block[0]){
+        coefficients[0] = block[0] * 8.0;
+        return coefficients;
+    }
+    //Transform the rows, then the columns
+    let mut rows = [0f32; 64];
+    for y in 0..8{
+        for u in 0..8{
+            rows[y * 8 + u] = (0..8).map(|x| block[y * 8 + x] * cosines[u][x]).sum();
+        }
+    }
+    for v in 0..8{
+        for u in 0..8{
+            coefficients[v * 8 + u] = (0..8).map(|y| rows[y * 8 + u] * cosines[v][y]).sum();
+        }
+    }
+    coefficients
+}
+
+/// Quantizes the coefficients and puts them into zigzag order
+fn quantize(coefficients: &[f32; 64], table: &[u16; 64]) -> [i32; 64]{
+    let mut quantized = [0; 64];
+    for (quantized, index) in quantized.iter_mut().zip(ZIGZAG.iter()){
+        *quantized = (coefficients[*index] / table[*index] as f32).round() as i32;
+    }
+    quantized
+}
+
+fn encode_block(writer: &mut BitWriter, coefficients: &[i32; 64], previous_dc: &mut i32, dc_codes: &HuffmanCodes, ac_codes: &HuffmanCodes){
+    //DC is coded as the difference to the previous block of the same component
+    let difference = coefficients[0] - *previous_dc;
+    *previous_dc = coefficients[0];
+    let (size, bits) = magnitude(difference);
+    writer.write_symbol(dc_codes, size);
+    writer.write(bits, size);
+    //AC as runs of zeros followed by a value
+    let mut zeros = 0;
+    for coefficient in coefficients[1..].iter(){
+        if *coefficient == 0{
+            zeros += 1;
+            continue;
+        }
+        while zeros > 15{
+            //Sixteen zeros
+            writer.write_symbol(ac_codes, 0xF0);
+            zeros -= 16;
+        }
+        let (size, bits) = magnitude(*coefficient);
+        writer.write_symbol(ac_codes, (zeros << 4) | size);
+        writer.write(bits, size);
+        zeros = 0;
+    }
+    if zeros > 0{
+        //End of block
+        writer.write_symbol(ac_codes, 0x00);
+    }
+}
+
+/// Number of bits needed for a value and the bits themselves, negative values are stored as one's complement
+fn magnitude(value: i32) -> (u8, u16){
+    let size = (32 - value.abs().leading_zeros()) as u8;
+    let bits = if value < 0 {value - 1} else {value};
+    (size, (bits & ((1 << size) - 1)) as u16)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use jpeg_decoder::{Decoder, PixelFormat};
+
+    /// Smooth colour gradients, which survive the compression well enough to compare pixels
+    fn gradient(width: usize, height: usize) -> Vec<u8>{
+        let mut rgb = Vec::with_capacity(width * height * 3);
+        for y in 0..height{
+            for x in 0..width{
+                rgb.extend_from_slice(&[(x * 255 / width) as u8, (y * 255 / height) as u8, 128]);
+            }
+        }
+        rgb
+    }
+
+    /// Encodes and decodes a gradient, returns the mean difference per colour value
+    fn round_trip(width: usize, height: usize, quality: u8) -> f64{
+        let rgb = gradient(width, height);
+        let jpeg = encode_rgb(width, height, &rgb, quality);
+        let mut decoder = Decoder::new(&jpeg[..]);
+        let pixels = decoder.decode().unwrap();
+        let info = decoder.info().unwrap();
+        assert_eq!((info.width as usize, info.height as usize), (width, height));
+        assert_eq!(info.pixel_format, PixelFormat::RGB24);
+        assert_eq!(pixels.len(), rgb.len());
+        let difference: u64 = pixels.iter().zip(rgb.iter()).map(|(a, b)| (*a as i64 - *b as i64).unsigned_abs()).sum();
+        difference as f64 / rgb.len() as f64
+    }
+
+    #[test]
+    fn decodes_sizes_that_are_not_multiples_of_8(){
+        for (width, height) in [(1, 1), (13, 7), (17, 9), (33, 31)].iter(){
+            let difference = round_trip(*width, *height, 90);
+            assert!(difference < 3.0, "{}x{}: {}", width, height, difference);
+        }
+    }
+
+    #[test]
+    fn decodes_highest_and_lowest_quality(){
+        let best = round_trip(64, 48, 100);
+        let worst = round_trip(64, 48, 1);
+        assert!(best < 1.0, "{}", best);
+        assert!(worst < 25.0, "{}", worst);
+        assert!(best < worst);
+    }
+}
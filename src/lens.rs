@@ -0,0 +1,78 @@
+use std::{fs, path::Path};
+
+//Keys in the calibration file, in the order of `LensCalibration::parameters`
+const KEYS: [&str; 8] = ["fx", "fy", "cx", "cy", "k1", "k2", "k3", "k4"];
+
+/// Intrinsic parameters of a fisheye lens in the equidistant model.
+///
+/// A ray at angle θ from the optical axis ends up at a distance of `f * θd` from the centre of the image, with
+/// `θd = θ (1 + k1 θ² + k2 θ⁴ + k3 θ⁶ + k4 θ⁸)`. Focal lengths and centre are fractions of the image width
+/// (`fx`, `cx`) and height (`fy`, `cy`), so the calibration holds for every resolution of the camera.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LensCalibration{
+    pub fx: f64,
+    pub fy: f64,
+    pub cx: f64,
+    pub cy: f64,
+    pub k: [f64; 4]
+}
+
+impl LensCalibration{
+    /// Reads a calibration file of `key = value` lines as written by `save`
+    pub fn load(path: &Path) -> Result<LensCalibration, String>{
+        let contents = fs::read_to_string(path).map_err(|e| format!("Error reading lens calibration {}: {}", path.display(), e))?;
+        let mut values = [None; 8];
+        for (line_number, line) in contents.lines().enumerate(){
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty(){
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let index = KEYS.iter().position(|known| *known == key)
+                .ok_or_else(|| format!("{}:{}: Unknown value {}", path.display(), line_number + 1, key))?;
+            let value = parts.next().unwrap_or("").trim();
+            values[index] = Some(value.parse().map_err(|_| format!("{}:{}: Invalid value for {}: {}", path.display(), line_number + 1, key, value))?);
+        }
+        let mut parameters = [0.0; 8];
+        for (i, value) in values.iter().enumerate(){
+            parameters[i] = value.ok_or_else(|| format!("{}: Missing value for {}", path.display(), KEYS[i]))?;
+        }
+        Ok(LensCalibration::from_parameters(&parameters))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String>{
+        let mut contents = String::from("# Equidistant fisheye model, focal lengths and centre relative to the image size\n");
+        for (key, value) in KEYS.iter().zip(self.parameters().iter()){
+            contents.push_str(&format!("{} = {:.6}\n", key, value));
+        }
+        fs::write(path, contents).map_err(|e| format!("Error writing lens calibration {}: {}", path.display(), e))
+    }
+
+    /// fx, fy, cx, cy, k1, k2, k3, k4
+    pub fn parameters(&self) -> [f64; 8]{
+        [self.fx, self.fy, self.cx, self.cy, self.k[0], self.k[1], self.k[2], self.k[3]]
+    }
+
+    pub fn from_parameters(parameters: &[f64; 8]) -> LensCalibration{
+        LensCalibration{
+            fx: parameters[0],
+            fy: parameters[1],
+            cx: parameters[2],
+            cy: parameters[3],
+            k: [parameters[4], parameters[5], parameters[6], parameters[7]]
+        }
+    }
+
+    /// Where a point in camera coordinates (z along the optical axis) ends up in the image, as a fraction of its size
+    pub fn project(&self, point: [f64; 3]) -> [f64; 2]{
+        let r = (point[0] * point[0] + point[1] * point[1]).sqrt();
+        if r < 1e-12{
+            return [self.cx, self.cy];
+        }
+        let theta = r.atan2(point[2]);
+        let theta2 = theta * theta;
+        let theta_d = theta * (1.0 + theta2 * (self.k[0] + theta2 * (self.k[1] + theta2 * (self.k[2] + theta2 * self.k[3]))));
+        [self.cx + self.fx * theta_d * point[0] / r, self.cy + self.fy * theta_d * point[1] / r]
+    }
+}
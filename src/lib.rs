@@ -0,0 +1,44 @@
+//! Receives the motion JPEG stream of wifi reversing cameras compatible with the MRT_Camera app.
+//!
+//! `WifiCam` talks to the camera and decodes its frames, `UDPFrameHeader`, `TcpMessage` and `Reassembler`
+//! are the protocol underneath it for tools that handle the packets themselves.
+//! Recordings, JPEG files, MJPEG streams and test patterns can be used in place of the camera through `FrameSource`.
+//! The binaries `dashcam-gui` and `dashcam-headless` are built on top of this.
+//! The window and its OpenGL overlays are part of the default `gui` feature, without it glutin and gl aren't needed.
+
+pub mod config;
+pub mod wificam;
+#[cfg(feature = "gui")]
+pub mod gui;
+pub mod transform;
+pub mod frame;
+pub mod decode;
+pub mod reassembly;
+pub mod stats;
+pub mod error;
+pub mod capture;
+pub mod avi;
+pub mod recorder;
+pub mod reverse;
+#[cfg(feature = "gui")]
+pub mod overlay;
+pub mod guidelines;
+pub mod steering;
+pub mod server;
+pub mod player;
+pub mod lens;
+pub mod calibrate;
+pub mod source;
+pub mod jpeg;
+#[cfg(feature = "gui")]
+mod program;
+#[cfg(feature = "gui")]
+mod texture;
+#[cfg(feature = "gui")]
+mod osd;
+
+pub use error::DashcamError;
+pub use frame::{EncodedFrame, Frame, PixelFormat};
+pub use source::{FrameSource, SourceMetadata};
+pub use reassembly::{AssembledFrame, Reassembler};
+pub use wificam::{ConnectionState, TcpMessage, TcpMessageReader, UDPFrameHeader, WifiCam, WifiCamConfig};
@@ -0,0 +1,243 @@
+use gl::types::*;
+use std::{collections::VecDeque, ffi::CString, mem, os::raw::c_void, ptr, time::{Duration, Instant}};
+use crate::{error::DashcamError, overlay::{create_buffer, upload_vertices}, program::{Program, Shader}, texture::LUMINANCE};
+
+const VERTEX_SHADER_SOURCE: &str = "
+attribute vec2 position;
+attribute vec2 vertexUV;
+
+varying vec2 UV;
+
+void main(){
+    gl_Position = vec4(position, 0.0, 1.0);
+    UV = vertexUV;
+}
+";
+
+const FRAGMENT_SHADER_SOURCE: &str = "
+precision mediump float;
+varying vec2 UV;
+uniform sampler2D font;
+uniform vec4 textColor;
+uniform vec4 background;
+
+void main(){
+    gl_FragColor = mix(background, textColor, texture2D(font, UV).r);
+}
+";
+
+//Floats per vertex: x, y, u, v
+const VERTEX_SIZE: usize = 4;
+//Each glyph is 5x7 pixels, its cell in the font texture leaves a pixel of space around it
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+const CELL_WIDTH: usize = GLYPH_WIDTH + 1;
+const CELL_HEIGHT: usize = GLYPH_HEIGHT + 2;
+const FIRST_CHARACTER: u8 = b' ';
+//Screen pixels per font pixel
+const PIXEL_SCALE: usize = 2;
+//Distance from the top left corner of the window in pixels
+const MARGIN: usize = 8;
+const TEXT_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+const BACKGROUND: [f32; 4] = [0.0, 0.0, 0.0, 0.6];
+//Frames the frame rate and decode time are averaged over
+const AVERAGING_WINDOW: Duration = Duration::from_secs(1);
+
+/// Printable ASCII characters, one byte per column from left to right, lowest bit at the top
+const FONT: [[u8; GLYPH_WIDTH]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], [0x00, 0x00, 0x5F, 0x00, 0x00], [0x00, 0x07, 0x00, 0x07, 0x00], [0x14, 0x7F, 0x14, 0x7F, 0x14],
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12], [0x23, 0x13, 0x08, 0x64, 0x62], [0x36, 0x49, 0x55, 0x22, 0x50], [0x00, 0x05, 0x03, 0x00, 0x00],
+    [0x00, 0x1C, 0x22, 0x41, 0x00], [0x00, 0x41, 0x22, 0x1C, 0x00], [0x14, 0x08, 0x3E, 0x08, 0x14], [0x08, 0x08, 0x3E, 0x08, 0x08],
+    [0x00, 0x50, 0x30, 0x00, 0x00], [0x08, 0x08, 0x08, 0x08, 0x08], [0x00, 0x60, 0x60, 0x00, 0x00], [0x20, 0x10, 0x08, 0x04, 0x02],
+    //0-9
+    [0x3E, 0x51, 0x49, 0x45, 0x3E], [0x00, 0x42, 0x7F, 0x40, 0x00], [0x42, 0x61, 0x51, 0x49, 0x46], [0x21, 0x41, 0x45, 0x4B, 0x31],
+    [0x18, 0x14, 0x12, 0x7F, 0x10], [0x27, 0x45, 0x45, 0x45, 0x39], [0x3C, 0x4A, 0x49, 0x49, 0x30], [0x01, 0x71, 0x09, 0x05, 0x03],
+    [0x36, 0x49, 0x49, 0x49, 0x36], [0x06, 0x49, 0x49, 0x29, 0x1E],
+    [0x00, 0x36, 0x36, 0x00, 0x00], [0x00, 0x56, 0x36, 0x00, 0x00], [0x08, 0x14, 0x22, 0x41, 0x00], [0x14, 0x14, 0x14, 0x14, 0x14],
+    [0x00, 0x41, 0x22, 0x14, 0x08], [0x02, 0x01, 0x51, 0x09, 0x06], [0x32, 0x49, 0x79, 0x41, 0x3E],
+    //A-Z
+    [0x7E, 0x11, 0x11, 0x11, 0x7E], [0x7F, 0x49, 0x49, 0x49, 0x36], [0x3E, 0x41, 0x41, 0x41, 0x22], [0x7F, 0x41, 0x41, 0x22, 0x1C],
+    [0x7F, 0x49, 0x49, 0x49, 0x41], [0x7F, 0x09, 0x09, 0x09, 0x01], [0x3E, 0x41, 0x49, 0x49, 0x7A], [0x7F, 0x08, 0x08, 0x08, 0x7F],
+    [0x00, 0x41, 0x7F, 0x41, 0x00], [0x20, 0x40, 0x41, 0x3F, 0x01], [0x7F, 0x08, 0x14, 0x22, 0x41], [0x7F, 0x40, 0x40, 0x40, 0x40],
+    [0x7F, 0x02, 0x0C, 0x02, 0x7F], [0x7F, 0x04, 0x08, 0x10, 0x7F], [0x3E, 0x41, 0x41, 0x41, 0x3E], [0x7F, 0x09, 0x09, 0x09, 0x06],
+    [0x3E, 0x41, 0x51, 0x21, 0x5E], [0x7F, 0x09, 0x19, 0x29, 0x46], [0x46, 0x49, 0x49, 0x49, 0x31], [0x01, 0x01, 0x7F, 0x01, 0x01],
+    [0x3F, 0x40, 0x40, 0x40, 0x3F], [0x1F, 0x20, 0x40, 0x20, 0x1F], [0x3F, 0x40, 0x38, 0x40, 0x3F], [0x63, 0x14, 0x08, 0x14, 0x63],
+    [0x07, 0x08, 0x70, 0x08, 0x07], [0x61, 0x51, 0x49, 0x45, 0x43],
+    [0x00, 0x7F, 0x41, 0x41, 0x00], [0x02, 0x04, 0x08, 0x10, 0x20], [0x00, 0x41, 0x41, 0x7F, 0x00], [0x04, 0x02, 0x01, 0x02, 0x04],
+    [0x40, 0x40, 0x40, 0x40, 0x40], [0x00, 0x01, 0x02, 0x04, 0x00],
+    //a-z
+    [0x20, 0x54, 0x54, 0x54, 0x78], [0x7F, 0x48, 0x44, 0x44, 0x38], [0x38, 0x44, 0x44, 0x44, 0x20], [0x38, 0x44, 0x44, 0x48, 0x7F],
+    [0x38, 0x54, 0x54, 0x54, 0x18], [0x08, 0x7E, 0x09, 0x01, 0x02], [0x0C, 0x52, 0x52, 0x52, 0x3E], [0x7F, 0x08, 0x04, 0x04, 0x78],
+    [0x00, 0x44, 0x7D, 0x40, 0x00], [0x20, 0x40, 0x44, 0x3D, 0x00], [0x7F, 0x10, 0x28, 0x44, 0x00], [0x00, 0x41, 0x7F, 0x40, 0x00],
+    [0x7C, 0x04, 0x18, 0x04, 0x78], [0x7C, 0x08, 0x04, 0x04, 0x78], [0x38, 0x44, 0x44, 0x44, 0x38], [0x7C, 0x14, 0x14, 0x14, 0x08],
+    [0x08, 0x14, 0x14, 0x18, 0x7C], [0x7C, 0x08, 0x04, 0x04, 0x08], [0x48, 0x54, 0x54, 0x54, 0x20], [0x04, 0x3F, 0x44, 0x40, 0x20],
+    [0x3C, 0x40, 0x40, 0x20, 0x7C], [0x1C, 0x20, 0x40, 0x20, 0x1C], [0x3C, 0x40, 0x30, 0x40, 0x3C], [0x44, 0x28, 0x10, 0x28, 0x44],
+    [0x0C, 0x50, 0x50, 0x50, 0x3C], [0x44, 0x64, 0x54, 0x4C, 0x44],
+    [0x00, 0x08, 0x36, 0x41, 0x00], [0x00, 0x00, 0x7F, 0x00, 0x00], [0x00, 0x41, 0x36, 0x08, 0x00], [0x08, 0x04, 0x08, 0x10, 0x08]
+];
+
+/// Lines of text drawn in the top left corner of the window, on a dark background so they stay readable on any image
+pub struct TextOverlay{
+    program: Program,
+    position_attr: GLuint,
+    uv_attr: GLuint,
+    text_color_uniform: GLint,
+    background_uniform: GLint,
+    font: GLuint,
+    vbo: GLuint
+}
+
+impl TextOverlay{
+    pub fn new() -> Result<TextOverlay, DashcamError>{
+        let vertex = Shader::new(VERTEX_SHADER_SOURCE, gl::VERTEX_SHADER)?;
+        let fragment = Shader::new(FRAGMENT_SHADER_SOURCE, gl::FRAGMENT_SHADER)?;
+        let program = Program::new(vertex, fragment)?;
+        let attribute = |name: &str| unsafe{
+            gl::GetAttribLocation(program.handle, CString::new(name).unwrap().as_ptr()) as GLuint
+        };
+        let (position_attr, uv_attr) = (attribute("position"), attribute("vertexUV"));
+        let uniform = |name: &str| unsafe{
+            gl::GetUniformLocation(program.handle, CString::new(name).unwrap().as_ptr())
+        };
+        let (text_color_uniform, background_uniform) = (uniform("textColor"), uniform("background"));
+        Ok(TextOverlay{
+            program,
+            position_attr,
+            uv_attr,
+            text_color_uniform,
+            background_uniform,
+            font: TextOverlay::create_font_texture(),
+            vbo: create_buffer(&[], gl::DYNAMIC_DRAW)
+        })
+    }
+
+    /// Uploads all glyphs side by side into a greyscale texture
+    fn create_font_texture() -> GLuint{
+        let (width, height) = (FONT.len() * CELL_WIDTH, CELL_HEIGHT);
+        let mut pixels = vec![0u8; width * height];
+        for (index, glyph) in FONT.iter().enumerate(){
+            for (column, bits) in glyph.iter().enumerate(){
+                for row in 0..GLYPH_HEIGHT{
+                    if bits & (1 << row) != 0{
+                        pixels[(row + 1) * width + index * CELL_WIDTH + column] = 255;
+                    }
+                }
+            }
+        }
+        let mut texture = 0;
+        unsafe{
+            gl::GenTextures(1, &mut texture);
+            gl::BindTexture(gl::TEXTURE_2D, texture);
+            //Sharp pixels when scaled up, and no mipmaps since the size is not a power of two
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            gl::TexImage2D(gl::TEXTURE_2D, 0, LUMINANCE as i32, width as i32, height as i32, 0, LUMINANCE, gl::UNSIGNED_BYTE,
+                pixels.as_ptr() as *const c_void);
+        }
+        texture
+    }
+
+    /// Draws the lines in a window of the given size in pixels, leaves the font texture bound
+    pub fn draw(&self, lines: &[String], window_size: (u32, u32)){
+        let vertices = TextOverlay::text_vertices(lines, window_size);
+        upload_vertices(self.vbo, &vertices, gl::DYNAMIC_DRAW);
+        let stride = (VERTEX_SIZE * mem::size_of::<GLfloat>()) as GLsizei;
+        unsafe{
+            gl::UseProgram(self.program.handle);
+            gl::Uniform4fv(self.text_color_uniform, 1, TEXT_COLOR.as_ptr());
+            gl::Uniform4fv(self.background_uniform, 1, BACKGROUND.as_ptr());
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::BindTexture(gl::TEXTURE_2D, self.font);
+            gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo);
+            gl::EnableVertexAttribArray(self.position_attr);
+            gl::VertexAttribPointer(self.position_attr, 2, gl::FLOAT, gl::FALSE as GLboolean, stride, ptr::null());
+            gl::EnableVertexAttribArray(self.uv_attr);
+            gl::VertexAttribPointer(self.uv_attr, 2, gl::FLOAT, gl::FALSE as GLboolean, stride, (2 * mem::size_of::<GLfloat>()) as *const _);
+            gl::Enable(gl::BLEND);
+            gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+            gl::DrawArrays(gl::TRIANGLES, 0, (vertices.len() / VERTEX_SIZE) as GLsizei);
+            gl::Disable(gl::BLEND);
+            gl::DisableVertexAttribArray(self.uv_attr);
+        }
+    }
+
+    /// Two triangles per character, lines are padded to the same length so the background forms a single box
+    fn text_vertices(lines: &[String], window_size: (u32, u32)) -> Vec<GLfloat>{
+        let columns = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0);
+        let (window_width, window_height) = (window_size.0.max(1) as f32, window_size.1.max(1) as f32);
+        let texture_width = (FONT.len() * CELL_WIDTH) as f32;
+        let mut vertices = Vec::with_capacity(lines.len() * columns * 6 * VERTEX_SIZE);
+        for (row, line) in lines.iter().enumerate(){
+            let characters = line.chars().chain(std::iter::repeat(' ')).take(columns);
+            for (column, character) in characters.enumerate(){
+                //Characters the font doesn't have are shown as question marks
+                let index = match character{
+                    ' '..='~' => character as usize - FIRST_CHARACTER as usize,
+                    _ => (b'?' - FIRST_CHARACTER) as usize
+                };
+                let left = (MARGIN + column * CELL_WIDTH * PIXEL_SCALE) as f32;
+                let top = (MARGIN + row * CELL_HEIGHT * PIXEL_SCALE) as f32;
+                let (x0, x1) = (left / window_width * 2.0 - 1.0, (left + (CELL_WIDTH * PIXEL_SCALE) as f32) / window_width * 2.0 - 1.0);
+                let (y0, y1) = (1.0 - top / window_height * 2.0, 1.0 - (top + (CELL_HEIGHT * PIXEL_SCALE) as f32) / window_height * 2.0);
+                let (u0, u1) = ((index * CELL_WIDTH) as f32 / texture_width, ((index + 1) * CELL_WIDTH) as f32 / texture_width);
+                vertices.extend_from_slice(&[
+                    x0, y0, u0, 0.0, x1, y0, u1, 0.0, x0, y1, u0, 1.0,
+                    x1, y0, u1, 0.0, x1, y1, u1, 1.0, x0, y1, u0, 1.0
+                ]);
+            }
+        }
+        vertices
+    }
+}
+
+impl Drop for TextOverlay{
+    fn drop(&mut self) {
+        unsafe{
+            gl::DeleteBuffers(1, &self.vbo);
+            gl::DeleteTextures(1, &self.font);
+        }
+    }
+}
+
+/// Frame rate and decode time of the frames shown during the last second
+pub struct FrameRate{
+    /// When each frame was shown and how long it took to decode
+    frames: VecDeque<(Instant, Duration)>
+}
+
+impl FrameRate{
+    pub fn new() -> FrameRate{
+        FrameRate{
+            frames: VecDeque::new()
+        }
+    }
+
+    /// Counts a new frame that is shown now
+    pub fn record(&mut self, decode_duration: Duration){
+        let now = Instant::now();
+        self.frames.push_back((now, decode_duration));
+        while let Some((shown, _)) = self.frames.front(){
+            if now.duration_since(*shown) <= AVERAGING_WINDOW{
+                break;
+            }
+            self.frames.pop_front();
+        }
+    }
+
+    /// Frames per second, drops to 0 once frames stop arriving
+    pub fn fps(&self) -> f32{
+        let recent = self.frames.iter().filter(|(shown, _)| shown.elapsed() <= AVERAGING_WINDOW).count();
+        recent as f32 / AVERAGING_WINDOW.as_secs_f32()
+    }
+
+    /// Average time it took to decode a frame
+    pub fn decode_time(&self) -> Duration{
+        if self.frames.is_empty(){
+            return Duration::from_secs(0);
+        }
+        self.frames.iter().map(|(_, duration)| *duration).sum::<Duration>() / self.frames.len() as u32
+    }
+}
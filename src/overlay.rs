@@ -0,0 +1,420 @@
+use gl::types::*;
+use std::{ffi::CString, mem, ptr};
+use crate::{error::DashcamError, guidelines::{GuidelineConfig, GuidelineParameter, MARK_DISTANCES}, program::{Program, Shader}, steering::SteeringConfig, transform::ImageTransform};
+
+const VERTEX_SHADER_SOURCE: &str = "
+attribute vec2 position;
+attribute vec3 color;
+//Rotation, mirroring and flipping of the image, the video looks it up the other way around
+uniform mat2 transform;
+//Size and position of the image in the window
+uniform vec2 scale;
+uniform vec2 offset;
+
+varying vec3 lineColor;
+
+void main(){
+    gl_Position = vec4((position * transform) * scale + offset, 0.0, 1.0);
+    lineColor = color;
+}
+";
+
+const FRAGMENT_SHADER_SOURCE: &str = "
+precision mediump float;
+varying vec3 lineColor;
+
+void main(){
+    gl_FragColor = vec4(lineColor, 1.0);
+}
+";
+
+//Floats per vertex: x, y, r, g, b
+const VERTEX_SIZE: usize = 5;
+const LINE_WIDTH: f32 = 0.012;
+
+const RED: [f32; 3] = [1.0, 0.1, 0.1];
+const YELLOW: [f32; 3] = [1.0, 0.85, 0.1];
+const GREEN: [f32; 3] = [0.1, 0.9, 0.2];
+const TRAJECTORY: [f32; 3] = [0.1, 0.8, 1.0];
+//Colour of the part that is being calibrated
+const HIGHLIGHT: [f32; 3] = [1.0, 1.0, 1.0];
+
+//Upper edge of the timeline bar, the area it reacts to clicks in is a bit larger
+const TIMELINE_TOP: f32 = -0.95;
+const TIMELINE_HIT_TOP: f32 = -0.88;
+const TIMELINE_TRACK: [f32; 3] = [0.2, 0.2, 0.2];
+const TIMELINE_PLAYED: [f32; 3] = [0.9, 0.9, 0.9];
+
+//Segments each trajectory line is made of
+const TRAJECTORY_SEGMENTS: usize = 24;
+
+/// Where the video is drawn, so overlays in image coordinates stay on the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImagePlacement{
+    pub scale: [f32; 2],
+    pub offset: [f32; 2],
+    /// Column major matrix of the `ImageTransform`
+    pub transform: [f32; 4]
+}
+
+impl ImagePlacement{
+    /// Overlays in window coordinates, like the timeline
+    pub const WINDOW: ImagePlacement = ImagePlacement{ scale: [1.0, 1.0], offset: [0.0, 0.0], transform: [1.0, 0.0, 0.0, 1.0] };
+
+    pub fn new(scale: [f32; 2], offset: [f32; 2], transform: &ImageTransform) -> ImagePlacement{
+        ImagePlacement{ scale, offset, transform: transform.matrix() }
+    }
+}
+
+/// Shader program drawing triangles with a colour per vertex
+struct ColorProgram{
+    program: Program,
+    position_attr: GLuint,
+    color_attr: GLuint,
+    transform_uniform: GLint,
+    scale_uniform: GLint,
+    offset_uniform: GLint
+}
+
+impl ColorProgram{
+    fn new() -> Result<ColorProgram, DashcamError>{
+        let vertex = Shader::new(VERTEX_SHADER_SOURCE, gl::VERTEX_SHADER)?;
+        let fragment = Shader::new(FRAGMENT_SHADER_SOURCE, gl::FRAGMENT_SHADER)?;
+        let program = Program::new(vertex, fragment)?;
+        let (position_attr, color_attr);
+        unsafe{
+            position_attr = gl::GetAttribLocation(program.handle, CString::new("position").unwrap().as_ptr()) as GLuint;
+            color_attr = gl::GetAttribLocation(program.handle, CString::new("color").unwrap().as_ptr()) as GLuint;
+        }
+        let uniform = |name: &str| unsafe{
+            gl::GetUniformLocation(program.handle, CString::new(name).unwrap().as_ptr())
+        };
+        let (transform_uniform, scale_uniform, offset_uniform) = (uniform("transform"), uniform("scale"), uniform("offset"));
+        Ok(ColorProgram{
+            program,
+            position_attr,
+            color_attr,
+            transform_uniform,
+            scale_uniform,
+            offset_uniform
+        })
+    }
+
+    /// Draws the vertices in `vbo`, leaves the program and buffer bound
+    fn draw(&self, vbo: GLuint, vertex_count: GLsizei, placement: &ImagePlacement){
+        let stride = (VERTEX_SIZE * mem::size_of::<GLfloat>()) as GLsizei;
+        unsafe{
+            gl::UseProgram(self.program.handle);
+            gl::UniformMatrix2fv(self.transform_uniform, 1, gl::FALSE, placement.transform.as_ptr());
+            gl::Uniform2f(self.scale_uniform, placement.scale[0], placement.scale[1]);
+            gl::Uniform2f(self.offset_uniform, placement.offset[0], placement.offset[1]);
+            gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
+            gl::EnableVertexAttribArray(self.position_attr);
+            gl::VertexAttribPointer(self.position_attr, 2, gl::FLOAT, gl::FALSE as GLboolean, stride, ptr::null());
+            gl::EnableVertexAttribArray(self.color_attr);
+            gl::VertexAttribPointer(self.color_attr, 3, gl::FLOAT, gl::FALSE as GLboolean, stride, (2 * mem::size_of::<GLfloat>()) as *const _);
+            gl::DrawArrays(gl::TRIANGLES, 0, vertex_count);
+            gl::DisableVertexAttribArray(self.color_attr);
+        }
+    }
+}
+
+/// Creates a buffer and fills it with `vertices`
+pub fn create_buffer(vertices: &[GLfloat], usage: GLenum) -> GLuint{
+    let mut vbo = 0;
+    unsafe{
+        gl::GenBuffers(1, &mut vbo);
+    }
+    upload_vertices(vbo, vertices, usage);
+    vbo
+}
+
+pub fn upload_vertices(vbo: GLuint, vertices: &[GLfloat], usage: GLenum){
+    unsafe{
+        gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
+        gl::BufferData(
+            gl::ARRAY_BUFFER,
+            mem::size_of_val(vertices) as GLsizeiptr,
+            vertices.as_ptr() as *const _,
+            usage,
+        );
+    }
+}
+
+/// Parking guidelines drawn as a second pass on top of the camera image
+pub struct GuidelineOverlay{
+    program: ColorProgram,
+    vbo: GLuint,
+    vertex_count: GLsizei
+}
+
+impl GuidelineOverlay{
+    pub fn new(config: &GuidelineConfig) -> Result<GuidelineOverlay, DashcamError>{
+        let vertices = GuidelineOverlay::guideline_vertices(config, None);
+        Ok(GuidelineOverlay{
+            program: ColorProgram::new()?,
+            vbo: create_buffer(&vertices, gl::DYNAMIC_DRAW),
+            vertex_count: (vertices.len() / VERTEX_SIZE) as GLsizei
+        })
+    }
+
+    /// Moves the guidelines, `highlight` is drawn in white while calibrating
+    pub fn update(&mut self, config: &GuidelineConfig, highlight: Option<GuidelineParameter>){
+        let vertices = GuidelineOverlay::guideline_vertices(config, highlight);
+        upload_vertices(self.vbo, &vertices, gl::DYNAMIC_DRAW);
+        self.vertex_count = (vertices.len() / VERTEX_SIZE) as GLsizei;
+    }
+
+    /// Draws the guidelines on the image, leaves the overlay program and buffer bound
+    pub fn draw(&self, placement: &ImagePlacement){
+        self.program.draw(self.vbo, self.vertex_count, placement);
+    }
+
+    /// Perspective trapezoid from the bumper (bottom of the image) towards the horizon,
+    /// with a crossbar and coloured side segments for each distance
+    fn guideline_vertices(config: &GuidelineConfig, highlight: Option<GuidelineParameter>) -> Vec<GLfloat>{
+        //(screen height, colour, parameter) of each distance mark, nearest first
+        let marks = [
+            (config.marks[0], RED, GuidelineParameter::HalfMeter),
+            (config.marks[1], YELLOW, GuidelineParameter::OneMeter),
+            (config.marks[2], GREEN, GuidelineParameter::TwoMeters)
+        ];
+        //Side lines show the widths and the centre
+        let sides_highlighted = matches!(highlight, Some(GuidelineParameter::NearWidth) | Some(GuidelineParameter::FarWidth) | Some(GuidelineParameter::Center));
+        let x = |offset: f32| config.center + offset;
+        let mut vertices = Vec::new();
+        //Start at the bottom edge of the image
+        let mut previous_y = -1.0;
+        for (y, color, parameter) in marks.iter(){
+            let side_color = if sides_highlighted {HIGHLIGHT} else {*color};
+            for side in [-1.0, 1.0].iter(){
+                line(&mut vertices, [x(side * config.half_width_at(previous_y)), previous_y], [x(side * config.half_width_at(*y)), *y], side_color);
+            }
+            let mark_color = if highlight == Some(*parameter) {HIGHLIGHT} else {*color};
+            let half_width = config.half_width_at(*y);
+            line(&mut vertices, [x(-half_width), *y], [x(-half_width * 0.6), *y], mark_color);
+            line(&mut vertices, [x(half_width * 0.6), *y], [x(half_width), *y], mark_color);
+            previous_y = *y;
+        }
+        vertices
+    }
+}
+
+/// Appends a line as two triangles, since wide GL lines are not supported everywhere
+fn line(vertices: &mut Vec<GLfloat>, from: [f32; 2], to: [f32; 2], color: [f32; 3]){
+    let (dx, dy) = (to[0] - from[0], to[1] - from[1]);
+    let length = (dx * dx + dy * dy).sqrt().max(f32::EPSILON);
+    let (nx, ny) = (-dy / length * LINE_WIDTH / 2.0, dx / length * LINE_WIDTH / 2.0);
+    let corners = [
+        [from[0] + nx, from[1] + ny], [from[0] - nx, from[1] - ny], [to[0] + nx, to[1] + ny],
+        [to[0] + nx, to[1] + ny], [from[0] - nx, from[1] - ny], [to[0] - nx, to[1] - ny]
+    ];
+    for corner in corners.iter(){
+        vertices.extend_from_slice(&[corner[0], corner[1], color[0], color[1], color[2]]);
+    }
+}
+
+/// Appends an axis aligned rectangle between two corners
+fn rectangle(vertices: &mut Vec<GLfloat>, from: [f32; 2], to: [f32; 2], color: [f32; 3]){
+    let corners = [
+        [from[0], from[1]], [to[0], from[1]], [from[0], to[1]],
+        [from[0], to[1]], [to[0], from[1]], [to[0], to[1]]
+    ];
+    for corner in corners.iter(){
+        vertices.extend_from_slice(&[corner[0], corner[1], color[0], color[1], color[2]]);
+    }
+}
+
+impl Drop for GuidelineOverlay{
+    fn drop(&mut self) {
+        unsafe{
+            gl::DeleteBuffers(1, &self.vbo);
+        }
+    }
+}
+
+/// Path the car will take when reversing with the current steering angle,
+/// drawn on top of the static guidelines
+pub struct TrajectoryOverlay{
+    program: ColorProgram,
+    vbo: GLuint
+}
+
+impl TrajectoryOverlay{
+    pub fn new() -> Result<TrajectoryOverlay, DashcamError>{
+        Ok(TrajectoryOverlay{
+            program: ColorProgram::new()?,
+            vbo: create_buffer(&[], gl::DYNAMIC_DRAW)
+        })
+    }
+
+    /// Draws the paths of both rear corners for a steering wheel angle in degrees, leaves the overlay program and buffer bound
+    pub fn draw(&self, angle: f32, guidelines: &GuidelineConfig, steering: &SteeringConfig, placement: &ImagePlacement){
+        let vertices = TrajectoryOverlay::trajectory_vertices(angle, guidelines, steering);
+        upload_vertices(self.vbo, &vertices, gl::DYNAMIC_DRAW);
+        self.program.draw(self.vbo, (vertices.len() / VERTEX_SIZE) as GLsizei, placement);
+    }
+
+    /// Follows the arcs of the rear corners up to the 2 m mark, using a single track model
+    /// where the car turns around a point on the extension of the rear axle
+    fn trajectory_vertices(angle: f32, guidelines: &GuidelineConfig, steering: &SteeringConfig) -> Vec<GLfloat>{
+        let direction = if steering.invert {-1.0} else {1.0};
+        let wheel_angle = (direction * angle / steering.steering_ratio).to_radians();
+        //Inverse of the turning radius, positive when turning to the right
+        let curvature = wheel_angle.tan() / steering.wheelbase;
+        let half_vehicle_width = steering.vehicle_width / 2.0;
+        let mut vertices = Vec::new();
+        for side in [-1.0, 1.0].iter(){
+            let offset = side * half_vehicle_width;
+            //(lateral offset, distance behind the bumper) in meters
+            let ground_point = |travelled: f32| {
+                let heading = travelled * curvature;
+                if curvature.abs() < 1e-6{
+                    (offset, travelled)
+                }else{
+                    let radius_factor = 1.0 - curvature * offset;
+                    (offset + radius_factor * (1.0 - heading.cos()) / curvature, radius_factor * heading.sin() / curvature)
+                }
+            };
+            let screen_point = |(lateral, distance): (f32, f32)| {
+                let y = guidelines.screen_y(distance);
+                [guidelines.center + lateral / half_vehicle_width * guidelines.half_width_at(y), y]
+            };
+            let max_distance = MARK_DISTANCES[2];
+            let mut previous = screen_point(ground_point(0.0));
+            for i in 1..=TRAJECTORY_SEGMENTS{
+                let point = ground_point(max_distance * i as f32 / TRAJECTORY_SEGMENTS as f32);
+                //Stop at the 2 m mark or when a tight turn bends the path back towards the car
+                if point.1 > max_distance || point.1 < 0.0{
+                    break;
+                }
+                let point = screen_point(point);
+                line(&mut vertices, previous, point, TRAJECTORY);
+                previous = point;
+            }
+        }
+        vertices
+    }
+}
+
+impl Drop for TrajectoryOverlay{
+    fn drop(&mut self) {
+        unsafe{
+            gl::DeleteBuffers(1, &self.vbo);
+        }
+    }
+}
+
+/// Playback position of the player along the bottom edge of the window
+pub struct TimelineBar{
+    program: ColorProgram,
+    vbo: GLuint
+}
+
+impl TimelineBar{
+    pub fn new() -> Result<TimelineBar, DashcamError>{
+        Ok(TimelineBar{
+            program: ColorProgram::new()?,
+            vbo: create_buffer(&[], gl::DYNAMIC_DRAW)
+        })
+    }
+
+    /// Draws the bar filled up to `progress` (0 to 1), leaves the overlay program and buffer bound
+    pub fn draw(&self, progress: f32){
+        let x = -1.0 + 2.0 * progress.clamp(0.0, 1.0);
+        let mut vertices = Vec::new();
+        rectangle(&mut vertices, [-1.0, -1.0], [x, TIMELINE_TOP], TIMELINE_PLAYED);
+        rectangle(&mut vertices, [x, -1.0], [1.0, TIMELINE_TOP], TIMELINE_TRACK);
+        upload_vertices(self.vbo, &vertices, gl::DYNAMIC_DRAW);
+        self.program.draw(self.vbo, (vertices.len() / VERTEX_SIZE) as GLsizei, &ImagePlacement::WINDOW);
+    }
+
+    /// Position on the timeline (0 to 1) of a point in normalized device coordinates, None if the point is not on the bar
+    pub fn hit(x: f32, y: f32) -> Option<f32>{
+        if y <= TIMELINE_HIT_TOP{
+            Some(((x + 1.0) / 2.0).clamp(0.0, 1.0))
+        }else{
+            None
+        }
+    }
+}
+
+impl Drop for TimelineBar{
+    fn drop(&mut self) {
+        unsafe{
+            gl::DeleteBuffers(1, &self.vbo);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// Start and end point of each line segment in the vertices of `line`, grouped into connected paths
+    fn paths(vertices: &[GLfloat]) -> Vec<Vec<([f32; 2], [f32; 2])>>{
+        let mut paths: Vec<Vec<([f32; 2], [f32; 2])>> = Vec::new();
+        for quad in vertices.chunks(6 * VERTEX_SIZE){
+            let corner = |index: usize| [quad[index * VERTEX_SIZE], quad[index * VERTEX_SIZE + 1]];
+            let middle = |a: [f32; 2], b: [f32; 2]| [(a[0] + b[0]) / 2.0, (a[1] + b[1]) / 2.0];
+            let segment = (middle(corner(0), corner(1)), middle(corner(2), corner(5)));
+            match paths.last_mut(){
+                Some(path) if path.last().map(|(_, end)| (end[0] - segment.0[0]).abs() + (end[1] - segment.0[1]).abs() < 1e-4).unwrap_or(false) => {
+                    path.push(segment)
+                },
+                _ => paths.push(vec![segment])
+            }
+        }
+        paths
+    }
+
+    fn assert_close(actual: f32, expected: f32){
+        assert!((actual - expected).abs() < 1e-4, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn straight_trajectory_follows_the_guidelines(){
+        let (guidelines, steering) = (GuidelineConfig::default(), SteeringConfig::default());
+        let paths = paths(&TrajectoryOverlay::trajectory_vertices(0.0, &guidelines, &steering));
+        assert_eq!(paths.len(), 2);
+        for (side, lines) in [-1.0, 1.0].iter().zip(paths.iter()){
+            assert_eq!(lines.len(), TRAJECTORY_SEGMENTS);
+            assert_close(lines[0].0[1], -1.0);
+            for (i, (_, end)) in lines.iter().enumerate(){
+                let distance = MARK_DISTANCES[2] * (i + 1) as f32 / TRAJECTORY_SEGMENTS as f32;
+                assert_close(end[1], guidelines.screen_y(distance));
+                assert_close(end[0], guidelines.center + side * guidelines.half_width_at(end[1]));
+            }
+        }
+    }
+
+    #[test]
+    fn trajectory_bends_towards_the_steering_direction(){
+        let guidelines = GuidelineConfig::default();
+        let steering = SteeringConfig::default();
+        //Full lock of a typical car, about 35 degrees at the wheels
+        let full_lock = 35.0 * steering.steering_ratio;
+        for (angle, direction) in [(full_lock, 1.0), (-full_lock, -1.0)].iter(){
+            let paths = paths(&TrajectoryOverlay::trajectory_vertices(*angle, &guidelines, &steering));
+            assert_eq!(paths.len(), 2);
+            for (side, lines) in [-1.0, 1.0].iter().zip(paths.iter()){
+                //Both lines start at the rear corners and never turn back below the bumper
+                assert_close(lines[0].0[0], guidelines.center + side * guidelines.near_width);
+                assert!(lines.iter().all(|(_, end)| end[1] >= -1.0 && end[1] <= guidelines.marks[2] + 1e-4));
+                let end = lines.last().unwrap().1;
+                let straight = guidelines.center + side * guidelines.half_width_at(end[1]);
+                assert!((end[0] - straight) * direction > 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn inverted_trajectory_is_mirrored(){
+        let guidelines = GuidelineConfig::default();
+        let steering = SteeringConfig::default();
+        let inverted = SteeringConfig{ invert: true, ..SteeringConfig::default() };
+        let normal = TrajectoryOverlay::trajectory_vertices(90.0, &guidelines, &steering);
+        let mirrored = TrajectoryOverlay::trajectory_vertices(-90.0, &guidelines, &inverted);
+        assert_eq!(normal, mirrored);
+    }
+}
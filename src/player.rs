@@ -0,0 +1,137 @@
+use std::{io, path::Path, time::Instant};
+use crate::{avi::AviReader, decode::decode_jpeg_frame, frame::{EncodedFrame, Frame}};
+
+const MIN_SPEED: f64 = 0.125;
+const MAX_SPEED: f64 = 8.0;
+
+/// Plays back a recorded AVI file, decoding frames as they are needed
+pub struct Player{
+    reader: AviReader,
+    /// Current position in frames, fractional while playing
+    position: f64,
+    playing: bool,
+    speed: f64,
+    last_update: Instant,
+    /// Index of the decoded frame
+    shown: Option<usize>,
+    frame: Option<Frame>
+}
+
+impl Player{
+    pub fn open(path: &Path) -> io::Result<Player>{
+        let reader = AviReader::open(path)?;
+        if reader.frame_count() == 0{
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "File contains no frames"));
+        }
+        eprintln!("Opened {} ({}x{}, {} frames)", path.display(), reader.width(), reader.height(), reader.frame_count());
+        Ok(Player{
+            reader,
+            position: 0.0,
+            playing: true,
+            speed: 1.0,
+            last_update: Instant::now(),
+            shown: None,
+            frame: None
+        })
+    }
+
+    pub fn frame_count(&self) -> usize{
+        self.reader.frame_count()
+    }
+
+    /// Index of the frame that is shown
+    pub fn current_frame(&self) -> usize{
+        (self.position as usize).min(self.frame_count() - 1)
+    }
+
+    pub fn is_playing(&self) -> bool{
+        self.playing
+    }
+
+    pub fn speed(&self) -> f64{
+        self.speed
+    }
+
+    /// Position between 0 (first frame) and 1 (last frame)
+    pub fn progress(&self) -> f32{
+        if self.frame_count() > 1 {(self.position / (self.frame_count() - 1) as f64) as f32} else {0.0}
+    }
+
+    pub fn toggle_playing(&mut self){
+        //Starting again at the end replays the file
+        if !self.playing && self.current_frame() == self.frame_count() - 1{
+            self.position = 0.0;
+        }
+        self.playing = !self.playing;
+    }
+
+    /// Pauses and moves by a number of frames, negative values step backwards
+    pub fn step(&mut self, frames: isize){
+        self.playing = false;
+        let index = (self.current_frame() as isize + frames).clamp(0, self.frame_count() as isize - 1);
+        self.position = index as f64;
+    }
+
+    pub fn faster(&mut self){
+        self.speed = (self.speed * 2.0).min(MAX_SPEED);
+    }
+
+    pub fn slower(&mut self){
+        self.speed = (self.speed / 2.0).max(MIN_SPEED);
+    }
+
+    /// Jumps to a position between 0 (first frame) and 1 (last frame)
+    pub fn seek(&mut self, progress: f32){
+        let progress = progress.clamp(0.0, 1.0) as f64;
+        self.position = (progress * (self.frame_count() - 1) as f64).round();
+    }
+
+    /// Advances the playback, returns the frame to show if it changed since the last call
+    pub fn update(&mut self) -> Option<&Frame>{
+        let now = Instant::now();
+        if self.playing{
+            let elapsed = now.duration_since(self.last_update);
+            self.position += elapsed.as_secs_f64() * self.speed / self.reader.frame_duration().as_secs_f64();
+            let last = (self.frame_count() - 1) as f64;
+            if self.position >= last{
+                //Stop on the last frame
+                self.position = last;
+                self.playing = false;
+            }
+        }
+        self.last_update = now;
+        let index = self.current_frame();
+        if self.shown == Some(index){
+            return None;
+        }
+        self.shown = Some(index);
+        match self.decode(index){
+            //Frames that can't be decoded are skipped, the previous one stays visible
+            Some(frame) => self.frame = Some(frame),
+            None => return None
+        }
+        self.frame.as_ref()
+    }
+
+    fn decode(&mut self, index: usize) -> Option<Frame>{
+        let data = match self.reader.read_frame(index){
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("Error reading frame {}: {}", index, e);
+                return None;
+            }
+        };
+        match decode_jpeg_frame(&EncodedFrame{
+            data,
+            received: Instant::now(),
+            frame_number: index as u8,
+            sub_packets: 0
+        }){
+            Ok(frame) => Some(frame),
+            Err(e) => {
+                eprintln!("Error decoding frame {}: {}", index, e);
+                None
+            }
+        }
+    }
+}
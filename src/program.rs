@@ -1,58 +1,50 @@
 use gl::types::*;
 use std::ffi::CString;
 use std::ptr;
-use std::str;
+use crate::error::DashcamError;
 
 
 pub struct Shader{
-    pub handle: GLuint,
-    pub source: String
+    pub handle: GLuint
 }
 
 impl Shader{
-    pub fn new(source: &str, shader_type: GLenum) -> Shader{
-        let handle = Shader::compile_shader(source, shader_type);
-        let source = String::from(source);
-        Shader{
-            handle,
-            source
-        }
+    pub fn new(source: &str, shader_type: GLenum) -> Result<Shader, DashcamError>{
+        let handle = Shader::compile_shader(source, shader_type)?;
+        Ok(Shader{
+            handle
+        })
     }
 
-    fn compile_shader(src: &str, ty: GLenum) -> GLuint {
+    fn compile_shader(src: &str, ty: GLenum) -> Result<GLuint, DashcamError> {
         let shader;
         unsafe {
             shader = gl::CreateShader(ty);
             // Attempt to compile the shader
-            let c_str = CString::new(src.as_bytes()).unwrap();
+            let c_str = CString::new(src.as_bytes()).map_err(|_| DashcamError::Gl(String::from("Shader source contains a null byte")))?;
             gl::ShaderSource(shader, 1, &c_str.as_ptr(), ptr::null());
             gl::CompileShader(shader);
-    
+
             // Get the compile status
             let mut status = gl::FALSE as GLint;
             gl::GetShaderiv(shader, gl::COMPILE_STATUS, &mut status);
-    
+
             // Fail on error
             if status != (gl::TRUE as GLint) {
                 let mut len = 0;
                 gl::GetShaderiv(shader, gl::INFO_LOG_LENGTH, &mut len);
-                let mut buf = Vec::with_capacity(len as usize);
-                buf.set_len((len as usize) - 1); // subtract 1 to skip the trailing null character
+                let mut buf = vec![0u8; len.max(1) as usize];
                 gl::GetShaderInfoLog(
                     shader,
                     len,
                     ptr::null_mut(),
                     buf.as_mut_ptr() as *mut GLchar,
                 );
-                panic!(
-                    "{}",
-                    str::from_utf8(&buf)
-                        .ok()
-                        .expect("ShaderInfoLog not valid utf8")
-                );
+                gl::DeleteShader(shader);
+                return Err(DashcamError::Gl(format!("Error compiling shader: {}", info_log(&buf))));
             }
         }
-        shader
+        Ok(shader)
     }
 }
 
@@ -71,16 +63,16 @@ pub struct Program{
 }
 
 impl Program{
-    pub fn new(vertex_shader: Shader, fragment_shader: Shader)-> Program{
-        let handle = Program::link_program(vertex_shader.handle,fragment_shader.handle);
-        Program{
+    pub fn new(vertex_shader: Shader, fragment_shader: Shader)-> Result<Program, DashcamError>{
+        let handle = Program::link_program(vertex_shader.handle,fragment_shader.handle)?;
+        Ok(Program{
             handle,
             _vertex_shader: vertex_shader,
             _fragment_shader: fragment_shader
-        }
+        })
     }
 
-    fn link_program(vs: GLuint, fs: GLuint) -> GLuint {
+    fn link_program(vs: GLuint, fs: GLuint) -> Result<GLuint, DashcamError> {
         unsafe {
             let program = gl::CreateProgram();
             gl::AttachShader(program, vs);
@@ -89,27 +81,22 @@ impl Program{
             // Get the link status
             let mut status = gl::FALSE as GLint;
             gl::GetProgramiv(program, gl::LINK_STATUS, &mut status);
-    
+
             // Fail on error
             if status != (gl::TRUE as GLint) {
                 let mut len: GLint = 0;
                 gl::GetProgramiv(program, gl::INFO_LOG_LENGTH, &mut len);
-                let mut buf = Vec::with_capacity(len as usize);
-                buf.set_len((len as usize) - 1); // subtract 1 to skip the trailing null character
+                let mut buf = vec![0u8; len.max(1) as usize];
                 gl::GetProgramInfoLog(
                     program,
                     len,
                     ptr::null_mut(),
                     buf.as_mut_ptr() as *mut GLchar,
                 );
-                panic!(
-                    "{}",
-                    str::from_utf8(&buf)
-                        .ok()
-                        .expect("ProgramInfoLog not valid utf8")
-                );
+                gl::DeleteProgram(program);
+                return Err(DashcamError::Gl(format!("Error linking shader program: {}", info_log(&buf))));
             }
-            program
+            Ok(program)
         }
     }
 }
@@ -120,4 +107,10 @@ impl Drop for Program{
             gl::DeleteProgram(self.handle);
         }
     }
-}
\ No newline at end of file
+}
+
+//Info logs end with a null character
+fn info_log(buf: &[u8]) -> String{
+    let end = buf.iter().position(|byte| *byte == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).trim_end().to_string()
+}
@@ -0,0 +1,301 @@
+use std::{collections::VecDeque, time::{Duration, Instant}};
+use crate::wificam::UDPFrameHeader;
+
+/// A frame whose sub-packets all arrived, in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembledFrame{
+    pub data: Vec<u8>,
+    pub frame_number: u8,
+    pub sub_packets: u8
+}
+
+/// What happened to an incomplete frame that was given up on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DroppedFrame{
+    pub frame_number: u8,
+    pub received: u8,
+    pub required: u8
+}
+
+struct PartialFrame{
+    frame_number: u8,
+    parts: Vec<Option<Vec<u8>>>,
+    received: u8,
+    started: Instant
+}
+
+impl PartialFrame{
+    fn dropped(&self) -> DroppedFrame{
+        DroppedFrame{
+            frame_number: self.frame_number,
+            received: self.received,
+            required: self.parts.len() as u8
+        }
+    }
+}
+
+/// Puts frames back together from their UDP sub-packets.
+///
+/// Sub-packets are buffered by `(frame_number, sub_packet_number)`, so packets may arrive in any order
+/// and packets of up to `window` frames may be interleaved. Frames that are still incomplete after
+/// `timeout`, or that are pushed out of the window by newer frames, are dropped.
+pub struct Reassembler{
+    window: usize,
+    timeout: Duration,
+    frames: Vec<PartialFrame>,
+    /// Numbers of the last `window` completed frames, so their late duplicates are recognised
+    completed: VecDeque<u8>,
+    dropped: Vec<DroppedFrame>,
+    /// Highest packet number seen so far
+    last_packet_number: Option<u16>,
+    out_of_order: u64
+}
+
+impl Reassembler{
+    pub fn new(window: usize, timeout: Duration) -> Reassembler{
+        Reassembler{
+            window: window.max(1),
+            timeout,
+            frames: Vec::new(),
+            completed: VecDeque::new(),
+            dropped: Vec::new(),
+            last_packet_number: None,
+            out_of_order: 0
+        }
+    }
+
+    /// Adds a sub-packet, returns the frame if this packet completed it
+    pub fn push(&mut self, header: &UDPFrameHeader, data: &[u8], now: Instant) -> Option<AssembledFrame>{
+        self.expire(now);
+        self.count_order(header.packet_number);
+        if header.total_sub_packets == 0 || header.sub_packet_number >= header.total_sub_packets{
+            return None;
+        }
+        //Late duplicates of recently completed frames must not start a new one
+        if self.completed.contains(&header.frame_number){
+            return None;
+        }
+        let index = match self.frames.iter().position(|frame| frame.frame_number == header.frame_number){
+            Some(index) if self.frames[index].parts.len() == header.total_sub_packets as usize => index,
+            Some(index) => {
+                //Same frame number but a different packet count, the counter must have wrapped around
+                let frame = self.frames.remove(index);
+                self.dropped.push(frame.dropped());
+                self.start_frame(header, now)
+            },
+            None => self.start_frame(header, now)
+        };
+        let frame = &mut self.frames[index];
+        let part = &mut frame.parts[header.sub_packet_number as usize];
+        if part.is_none(){
+            *part = Some(Vec::from(data));
+            frame.received += 1;
+        }
+        if frame.received as usize == frame.parts.len(){
+            let frame = self.frames.remove(index);
+            self.completed.push_back(frame.frame_number);
+            if self.completed.len() > self.window{
+                self.completed.pop_front();
+            }
+            let sub_packets = frame.received;
+            let data = frame.parts.into_iter().flat_map(|part| part.unwrap_or_default()).collect();
+            return Some(AssembledFrame{
+                data,
+                frame_number: frame.frame_number,
+                sub_packets
+            });
+        }
+        None
+    }
+
+    /// Returns the frames that were given up on since the last call
+    pub fn take_dropped(&mut self) -> Vec<DroppedFrame>{
+        std::mem::take(&mut self.dropped)
+    }
+
+    /// Returns the number of packets that arrived after a packet sent later than them, since the last call
+    pub fn take_out_of_order(&mut self) -> u64{
+        std::mem::take(&mut self.out_of_order)
+    }
+
+    /// Drops all frames that have been waiting for their missing packets for longer than the timeout
+    pub fn expire(&mut self, now: Instant){
+        let timeout = self.timeout;
+        let dropped = &mut self.dropped;
+        self.frames.retain(|frame| {
+            let expired = now.duration_since(frame.started) > timeout;
+            if expired{
+                dropped.push(frame.dropped());
+            }
+            !expired
+        });
+    }
+
+    /// Forgets the highest packet number, for when the camera starts counting again, e.g. after a reconnect
+    pub fn reset_order(&mut self){
+        self.last_packet_number = None;
+    }
+
+    fn count_order(&mut self, packet_number: u16){
+        //Packet numbers count every datagram the camera sends and wrap around
+        let behind = match self.last_packet_number{
+            Some(last) => -(packet_number.wrapping_sub(last) as i16 as i32),
+            None => 0
+        };
+        //A packet can only be late by the packets of the frames in the window, a larger jump back means the camera restarted
+        if behind > 0 && behind as usize <= self.window * u8::MAX as usize{
+            self.out_of_order += 1;
+        }else{
+            self.last_packet_number = Some(packet_number);
+        }
+    }
+
+    fn start_frame(&mut self, header: &UDPFrameHeader, now: Instant) -> usize{
+        //Make room by dropping the oldest frame
+        while self.frames.len() >= self.window{
+            let frame = self.frames.remove(0);
+            self.dropped.push(frame.dropped());
+        }
+        self.frames.push(PartialFrame{
+            frame_number: header.frame_number,
+            parts: vec![None; header.total_sub_packets as usize],
+            received: 0,
+            started: now
+        });
+        self.frames.len() - 1
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn header(packet_number: u16, frame_number: u8, sub_packet_number: u8, total_sub_packets: u8) -> UDPFrameHeader{
+        UDPFrameHeader{
+            packet_number,
+            frame_number,
+            sub_packet_number,
+            total_sub_packets,
+            packet_length: 9 + 1
+        }
+    }
+
+    /// Feeds `(frame_number, sub_packet_number, total_sub_packets)` packets whose payload is the sub packet number
+    fn feed(reassembler: &mut Reassembler, packets: &[(u8, u8, u8)], now: Instant) -> Vec<AssembledFrame>{
+        packets.iter().enumerate().filter_map(|(packet_number, (frame, sub, total))| {
+            reassembler.push(&header(packet_number as u16, *frame, *sub, *total), &[*sub], now)
+        }).collect()
+    }
+
+    #[test]
+    fn assembles_frame_in_order(){
+        let mut reassembler = Reassembler::new(4, Duration::from_millis(500));
+        let frames = feed(&mut reassembler, &[(1, 0, 3), (1, 1, 3), (1, 2, 3)], Instant::now());
+        assert_eq!(frames, vec![AssembledFrame{ data: vec![0, 1, 2], frame_number: 1, sub_packets: 3 }]);
+        assert!(reassembler.take_dropped().is_empty());
+    }
+
+    #[test]
+    fn assembles_single_packet_frame(){
+        let mut reassembler = Reassembler::new(4, Duration::from_millis(500));
+        let frames = feed(&mut reassembler, &[(7, 0, 1)], Instant::now());
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].data, vec![0]);
+    }
+
+    #[test]
+    fn tolerates_reordered_sub_packets(){
+        let mut reassembler = Reassembler::new(4, Duration::from_millis(500));
+        let frames = feed(&mut reassembler, &[(1, 2, 4), (1, 0, 4), (1, 3, 4), (1, 1, 4)], Instant::now());
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].data, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn assembles_interleaved_frames(){
+        let mut reassembler = Reassembler::new(4, Duration::from_millis(500));
+        let frames = feed(&mut reassembler, &[(1, 0, 2), (2, 0, 3), (2, 1, 3), (1, 1, 2), (2, 2, 3)], Instant::now());
+        assert_eq!(frames.iter().map(|frame| frame.frame_number).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(frames[1].data, vec![0, 1, 2]);
+        assert!(reassembler.take_dropped().is_empty());
+    }
+
+    #[test]
+    fn ignores_duplicate_packets(){
+        let mut reassembler = Reassembler::new(4, Duration::from_millis(500));
+        let frames = feed(&mut reassembler, &[(1, 0, 2), (1, 0, 2), (1, 1, 2), (1, 1, 2)], Instant::now());
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].data, vec![0, 1]);
+        assert!(reassembler.take_dropped().is_empty());
+    }
+
+    #[test]
+    fn ignores_stragglers_of_older_completed_frames(){
+        let mut reassembler = Reassembler::new(3, Duration::from_millis(500));
+        let now = Instant::now();
+        let frames = feed(&mut reassembler, &[(1, 0, 2), (1, 1, 2), (2, 0, 1), (3, 0, 1), (4, 0, 2), (5, 0, 2), (6, 0, 2)], now);
+        assert_eq!(frames.len(), 3);
+        //A straggler of frame 1 must not push frame 4 out of the full window
+        assert!(feed(&mut reassembler, &[(1, 1, 2)], now).is_empty());
+        let frames = feed(&mut reassembler, &[(4, 1, 2), (5, 1, 2), (6, 1, 2)], now);
+        assert_eq!(frames.iter().map(|frame| frame.frame_number).collect::<Vec<_>>(), vec![4, 5, 6]);
+        assert!(reassembler.take_dropped().is_empty());
+    }
+
+    #[test]
+    fn drops_incomplete_frame_after_timeout(){
+        let mut reassembler = Reassembler::new(4, Duration::from_millis(500));
+        let start = Instant::now();
+        assert!(feed(&mut reassembler, &[(1, 0, 3), (1, 1, 3)], start).is_empty());
+        reassembler.expire(start + Duration::from_millis(600));
+        assert_eq!(reassembler.take_dropped(), vec![DroppedFrame{ frame_number: 1, received: 2, required: 3 }]);
+        //The missing packet arriving late starts a new frame instead of completing the old one
+        assert!(feed(&mut reassembler, &[(1, 2, 3)], start + Duration::from_millis(700)).is_empty());
+    }
+
+    #[test]
+    fn drops_oldest_frame_outside_window(){
+        let mut reassembler = Reassembler::new(2, Duration::from_millis(500));
+        let frames = feed(&mut reassembler, &[(1, 0, 2), (2, 0, 2), (3, 0, 2), (1, 1, 2), (3, 1, 2)], Instant::now());
+        assert_eq!(frames.iter().map(|frame| frame.frame_number).collect::<Vec<_>>(), vec![3]);
+        let dropped = reassembler.take_dropped();
+        assert_eq!(dropped[0], DroppedFrame{ frame_number: 1, received: 1, required: 2 });
+    }
+
+    #[test]
+    fn ignores_invalid_sub_packet_numbers(){
+        let mut reassembler = Reassembler::new(4, Duration::from_millis(500));
+        assert!(feed(&mut reassembler, &[(1, 3, 2), (1, 0, 0)], Instant::now()).is_empty());
+        assert!(reassembler.take_dropped().is_empty());
+    }
+
+    #[test]
+    fn counts_out_of_order_packets(){
+        let mut reassembler = Reassembler::new(4, Duration::from_millis(500));
+        let now = Instant::now();
+        for (packet_number, sub) in [(65534, 0), (0, 2), (65535, 1), (1, 3)].iter(){
+            reassembler.push(&header(*packet_number, 1, *sub, 4), &[*sub], now);
+        }
+        //65535 overtaken by 0 across the wrap around, the first packet has nothing to compare to
+        assert_eq!(reassembler.take_out_of_order(), 1);
+        assert_eq!(reassembler.take_out_of_order(), 0);
+    }
+
+    #[test]
+    fn restarted_packet_counter_is_not_out_of_order(){
+        let mut reassembler = Reassembler::new(4, Duration::from_millis(500));
+        let now = Instant::now();
+        for packet_number in [20000, 20001, 0, 1, 2].iter(){
+            reassembler.push(&header(*packet_number, 1, 0, 4), &[0], now);
+        }
+        assert_eq!(reassembler.take_out_of_order(), 0);
+        //A small jump back after a reconnect is only recognised through the reset
+        reassembler.reset_order();
+        for packet_number in [0, 1].iter(){
+            reassembler.push(&header(*packet_number, 2, 0, 4), &[0], now);
+        }
+        assert_eq!(reassembler.take_out_of_order(), 0);
+        reassembler.push(&header(0, 3, 0, 4), &[0], now);
+        assert_eq!(reassembler.take_out_of_order(), 1);
+    }
+}
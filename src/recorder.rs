@@ -0,0 +1,544 @@
+use std::{collections::VecDeque, fs, io, path::{Path, PathBuf}, sync::{Arc, mpsc::{channel, Receiver, RecvTimeoutError, Sender}}, thread::{self, JoinHandle}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use jpeg_decoder::Decoder;
+use crate::{avi::AviWriter, frame::EncodedFrame, source::FrameSource, wificam::TcpMessage};
+
+const LOOP_SEGMENT_PREFIX: &str = "loop-";
+const EVENT_CLIP_PREFIX: &str = "event-";
+//Segments end at this size even before their duration is over, larger AVI files can't be played everywhere
+const MAX_SEGMENT_LENGTH: u64 = 1024 * 1024 * 1024;
+
+/// Settings of the loop and event recorders
+#[derive(Debug, Clone)]
+pub struct RecorderConfig{
+    /// Directory the segments are written to, recording is disabled if not set
+    pub directory: Option<PathBuf>,
+    /// Length of a single segment file
+    pub segment_duration: Duration,
+    /// Maximum number of bytes all loop segments may use together
+    pub quota: u64,
+    /// Time before an event that is included in the event clip
+    pub pre_event: Duration,
+    /// Time after an event that is included in the event clip
+    pub post_event: Duration
+}
+
+impl Default for RecorderConfig{
+    fn default() -> Self {
+        RecorderConfig{
+            directory: None,
+            segment_duration: Duration::from_secs(60),
+            quota: 4 * 1024 * 1024 * 1024,
+            pre_event: Duration::from_secs(10),
+            post_event: Duration::from_secs(10)
+        }
+    }
+}
+
+/// Writes the compressed camera frames into rolling AVI segments,
+/// deleting the oldest segments whenever the quota is exceeded.
+pub struct LoopRecorder{
+    directory: PathBuf,
+    segment_duration: Duration,
+    quota: u64,
+    segment: Option<Segment>,
+    /// Segments on disk apart from the one being written and their sizes, oldest first
+    finished: VecDeque<(PathBuf, u64)>
+}
+
+struct Segment{
+    writer: AviWriter,
+    path: PathBuf,
+    first_frame: Instant,
+    last_frame: Instant
+}
+
+impl LoopRecorder{
+    /// Spawns the recorder thread, which runs until the frame channel is closed
+    pub fn start(directory: PathBuf, config: &RecorderConfig, frames: Receiver<Arc<EncodedFrame>>) -> JoinHandle<()>{
+        let mut recorder = LoopRecorder::new(directory, config);
+        thread::spawn(move || {
+            if let Err(e) = fs::create_dir_all(&recorder.directory){
+                eprintln!("Error creating recording directory {}: {}", recorder.directory.display(), e);
+                return;
+            }
+            recorder.load_segments();
+            for frame in frames.iter(){
+                if let Err(e) = recorder.record(&frame){
+                    eprintln!("Error recording frame: {}", e);
+                    //Drop the broken segment, the next frame starts a new one
+                    if let Some(segment) = recorder.segment.take(){
+                        recorder.finished.push_back((segment.path, segment.writer.file_length()));
+                    }
+                }
+            }
+            recorder.finish_segment();
+        })
+    }
+
+    fn new(directory: PathBuf, config: &RecorderConfig) -> LoopRecorder{
+        LoopRecorder{
+            directory,
+            segment_duration: config.segment_duration,
+            quota: config.quota,
+            segment: None,
+            finished: VecDeque::new()
+        }
+    }
+
+    /// Takes over the segments of earlier runs, so they count towards the quota
+    fn load_segments(&mut self){
+        match loop_segments(&self.directory){
+            Ok(mut segments) => {
+                //Segment names start with the timestamp, so sorting by name sorts by age
+                segments.sort();
+                self.finished = segments.into();
+            },
+            Err(e) => eprintln!("Error listing segments in {}: {}", self.directory.display(), e)
+        }
+        self.enforce_quota();
+    }
+
+    fn record(&mut self, frame: &EncodedFrame) -> io::Result<()>{
+        //A quota smaller than a segment ends the segment early instead of deleting it once it is finished
+        let full = self.segment.as_ref().map(|segment| {
+            let length = segment.writer.file_length_with_frame(frame.data.len());
+            frame.received.duration_since(segment.first_frame) >= self.segment_duration || length > self.quota || length > MAX_SEGMENT_LENGTH
+        }).unwrap_or(false);
+        if full{
+            self.finish_segment();
+        }
+        if self.segment.is_none(){
+            self.segment = Some(self.start_segment(frame)?);
+        }
+        if let Some(segment) = self.segment.as_mut(){
+            segment.writer.write_frame(&frame.data)?;
+            segment.last_frame = frame.received;
+        }
+        self.enforce_quota();
+        Ok(())
+    }
+
+    fn start_segment(&self, frame: &EncodedFrame) -> io::Result<Segment>{
+        let (path, writer) = create_avi(&self.directory, LOOP_SEGMENT_PREFIX, &frame.data)?;
+        eprintln!("Recording to {}", path.display());
+        Ok(Segment{
+            writer,
+            path,
+            first_frame: frame.received,
+            last_frame: frame.received
+        })
+    }
+
+    fn finish_segment(&mut self){
+        if let Some(segment) = self.segment.take(){
+            let duration = segment.last_frame.duration_since(segment.first_frame);
+            let frames = segment.writer.frame_count();
+            self.finished.push_back((segment.path.clone(), segment.writer.file_length()));
+            if let Err(e) = segment.writer.finish(duration){
+                eprintln!("Error finishing segment {}: {}", segment.path.display(), e);
+            }else{
+                eprintln!("Finished segment {} ({} frames in {:.1} s)", segment.path.display(), frames, duration.as_secs_f32());
+            }
+            self.enforce_quota();
+        }
+    }
+
+    /// Deletes the oldest finished segments until they fit into the quota together with the segment being written
+    fn enforce_quota(&mut self){
+        let active = self.segment.as_ref().map(|segment| segment.writer.file_length()).unwrap_or(0);
+        let mut total = active + self.finished.iter().map(|(_, size)| size).sum::<u64>();
+        while total > self.quota{
+            let (path, size) = match self.finished.pop_front(){
+                Some(segment) => segment,
+                None => break
+            };
+            //Forget segments that can't be deleted, so they aren't retried on every frame
+            match fs::remove_file(&path){
+                Ok(()) => eprintln!("Deleted old segment {}", path.display()),
+                Err(e) => eprintln!("Error deleting segment {}: {}", path.display(), e)
+            }
+            total -= size;
+        }
+    }
+}
+
+/// Keeps the last few seconds of frames in memory and writes them into a protected clip
+/// whenever the yellow wire goes high or an event is triggered manually.
+///
+/// Event clips are named `event-*.avi`, so the loop recorder never deletes them.
+pub struct EventRecorder{
+    directory: PathBuf,
+    pre_event: Duration,
+    post_event: Duration,
+    buffer: VecDeque<Arc<EncodedFrame>>,
+    event_end: Option<Instant>,
+    clip: Option<EventClip>
+}
+
+struct EventClip{
+    writer: AviWriter,
+    path: PathBuf,
+    first_frame: Instant,
+    last_frame: Instant,
+    end: Instant
+}
+
+impl EventRecorder{
+    /// Spawns the event recorder thread, which runs until the frame channel is closed.
+    /// Returns a sender for triggering events by hand, e.g. from a hotkey.
+    pub fn start(directory: PathBuf, config: &RecorderConfig, frames: Receiver<Arc<EncodedFrame>>, tcp_messages: Receiver<TcpMessage>) -> (JoinHandle<()>, Sender<()>){
+        let (trigger_sender, triggers) = channel();
+        let mut recorder = EventRecorder::new(directory, config);
+        let event_thread = thread::spawn(move || {
+            if let Err(e) = fs::create_dir_all(&recorder.directory){
+                eprintln!("Error creating recording directory {}: {}", recorder.directory.display(), e);
+                return;
+            }
+            loop{
+                let frame = match frames.recv_timeout(Duration::from_millis(100)){
+                    Ok(frame) => Some(frame),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break
+                };
+                let wire_events = tcp_messages.try_iter().filter(|message| matches!(message, TcpMessage::YellowWireHigh)).count();
+                let manual_events = triggers.try_iter().count();
+                if wire_events + manual_events > 0{
+                    recorder.trigger();
+                }
+                if let Some(frame) = frame{
+                    recorder.record(frame);
+                }
+                recorder.check_clip_end();
+            }
+            recorder.finish_clip();
+        });
+        (event_thread, trigger_sender)
+    }
+
+    fn new(directory: PathBuf, config: &RecorderConfig) -> EventRecorder{
+        EventRecorder{
+            directory,
+            pre_event: config.pre_event,
+            post_event: config.post_event,
+            buffer: VecDeque::new(),
+            event_end: None,
+            clip: None
+        }
+    }
+
+    fn trigger(&mut self){
+        let end = Instant::now() + self.post_event;
+        match self.clip.as_mut(){
+            Some(clip) => {
+                //Another event during the post event time extends the running clip
+                eprintln!("Extending event clip {}", clip.path.display());
+                clip.end = end;
+            },
+            None => {
+                eprintln!("Event triggered");
+                self.event_end = Some(end);
+            }
+        }
+    }
+
+    fn record(&mut self, frame: Arc<EncodedFrame>){
+        //Keep only the frames needed for the next event
+        self.buffer.push_back(frame.clone());
+        while self.buffer.front().map(|buffered| frame.received.duration_since(buffered.received) > self.pre_event).unwrap_or(false){
+            self.buffer.pop_front();
+        }
+        if let Some(clip) = self.clip.as_mut(){
+            if let Err(e) = clip.writer.write_frame(&frame.data){
+                eprintln!("Error writing event clip {}: {}", clip.path.display(), e);
+                self.clip = None;
+                return;
+            }
+            clip.last_frame = frame.received;
+        }else if let Some(end) = self.event_end.take(){
+            match self.start_clip(end){
+                Ok(clip) => self.clip = Some(clip),
+                Err(e) => eprintln!("Error writing event clip: {}", e)
+            }
+        }
+    }
+
+    /// Finishes the running clip once the post event time is over
+    fn check_clip_end(&mut self){
+        if self.clip.as_ref().map(|clip| Instant::now() >= clip.end).unwrap_or(false){
+            self.finish_clip();
+        }
+        //No frame arrived during the whole event, e.g. because the camera was lost.
+        //Keep what was buffered right before it, but never start a clip of the frames arriving much later.
+        if let Some(end) = self.event_end{
+            if Instant::now() > end{
+                self.event_end = None;
+                let triggered = end - self.post_event;
+                let recent = self.buffer.back().map(|frame| triggered.saturating_duration_since(frame.received) <= self.pre_event).unwrap_or(false);
+                if recent{
+                    match self.start_clip(end){
+                        Ok(clip) => self.clip = Some(clip),
+                        Err(e) => eprintln!("Error writing event clip: {}", e)
+                    }
+                    self.finish_clip();
+                }else{
+                    eprintln!("No frames around the event, no clip recorded");
+                }
+            }
+        }
+    }
+
+    /// Starts a new clip containing all buffered frames
+    fn start_clip(&self, end: Instant) -> io::Result<EventClip>{
+        let first_frame = self.buffer.front().ok_or(io::Error::new(io::ErrorKind::InvalidInput, "No frames buffered"))?;
+        let (path, writer) = create_avi(&self.directory, EVENT_CLIP_PREFIX, &first_frame.data)?;
+        let mut clip = EventClip{
+            writer,
+            path,
+            first_frame: first_frame.received,
+            last_frame: first_frame.received,
+            end
+        };
+        eprintln!("Recording event clip {}", clip.path.display());
+        for frame in self.buffer.iter(){
+            clip.writer.write_frame(&frame.data)?;
+            clip.last_frame = frame.received;
+        }
+        Ok(clip)
+    }
+
+    fn finish_clip(&mut self){
+        if let Some(clip) = self.clip.take(){
+            let frames = clip.writer.frame_count();
+            if let Err(e) = clip.writer.finish(clip.last_frame.duration_since(clip.first_frame)){
+                eprintln!("Error finishing event clip {}: {}", clip.path.display(), e);
+            }else{
+                eprintln!("Finished event clip {} ({} frames)", clip.path.display(), frames);
+            }
+        }
+    }
+}
+
+/// Starts the loop and event recorders on the frames of `source` if a recording directory is configured,
+/// shutting down the source waits for them to finish their files. Returns the sender for triggering events by hand.
+pub fn start_recorders(source: &mut dyn FrameSource, config: &RecorderConfig) -> Option<Sender<()>>{
+    let directory = config.directory.clone()?;
+    let loop_thread = LoopRecorder::start(directory.clone(), config, source.subscribe_frames(64));
+    source.join_on_shutdown(loop_thread);
+    let (event_thread, trigger) = EventRecorder::start(directory, config, source.subscribe_frames(64), source.subscribe_events());
+    source.join_on_shutdown(event_thread);
+    Some(trigger)
+}
+
+/// Creates a new AVI file named after the current time, with the video dimensions taken from the first frame
+fn create_avi(directory: &Path, prefix: &str, first_jpeg: &[u8]) -> io::Result<(PathBuf, AviWriter)>{
+    let mut decoder = Decoder::new(first_jpeg);
+    decoder.read_info().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+    let info = decoder.info().ok_or(io::Error::new(io::ErrorKind::InvalidData, "JPEG without header"))?;
+    let name = format!("{}{}", prefix, format_timestamp(SystemTime::now()));
+    let mut sequence = 0;
+    loop{
+        //Files started within the same second get a number, `_` sorts after the `.` of the first one
+        let path = match sequence{
+            0 => directory.join(format!("{}.avi", name)),
+            _ => directory.join(format!("{}_{:03}.avi", name, sequence))
+        };
+        match AviWriter::create_new(&path, info.width as u32, info.height as u32){
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => sequence += 1,
+            result => return result.map(|writer| (path, writer))
+        }
+    }
+}
+
+fn loop_segments(directory: &Path) -> io::Result<Vec<(PathBuf, u64)>>{
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(directory)?{
+        let entry = entry?;
+        let is_segment = entry.file_name().to_str().map(|name| name.starts_with(LOOP_SEGMENT_PREFIX) && name.ends_with(".avi")).unwrap_or(false);
+        if is_segment{
+            segments.push((entry.path(), entry.metadata()?.len()));
+        }
+    }
+    Ok(segments)
+}
+
+/// Formats a time as `YYYYMMDD-HHMMSS` in UTC, so file names sort chronologically
+pub fn format_timestamp(time: SystemTime) -> String{
+    let seconds = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    let (days, seconds_of_day) = ((seconds / 86400) as i64, seconds % 86400);
+    //Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {month_index + 3} else {month_index - 9};
+    let year = year_of_era + era * 400 + if month <= 2 {1} else {0};
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", year, month, day, seconds_of_day / 3600, (seconds_of_day / 60) % 60, seconds_of_day % 60)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::env;
+    use crate::{avi::AviReader, jpeg};
+
+    fn temp_directory(name: &str) -> PathBuf{
+        let directory = env::temp_dir().join(format!("dashcam-test-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn config(segment_duration: Duration, quota: u64) -> RecorderConfig{
+        RecorderConfig{
+            directory: None,
+            segment_duration,
+            quota,
+            pre_event: Duration::from_secs(1),
+            post_event: Duration::from_secs(0)
+        }
+    }
+
+    /// Frames of a small grey image, `interval` apart
+    fn frames(count: usize, interval: Duration) -> Vec<Arc<EncodedFrame>>{
+        let data = jpeg::encode_rgb(16, 16, &[128; 16 * 16 * 3], 50);
+        let start = Instant::now();
+        (0..count).map(|i| Arc::new(EncodedFrame{
+            data: data.clone(),
+            received: start + interval * i as u32,
+            frame_number: i as u8,
+            sub_packets: 1
+        })).collect()
+    }
+
+    /// Size of a segment of `count` frames as written by `frames`
+    fn segment_length(count: usize) -> u64{
+        let path = temp_directory("measure").join("segment.avi");
+        let mut writer = AviWriter::create(&path, 16, 16).unwrap();
+        for frame in frames(count, Duration::from_millis(0)).iter(){
+            writer.write_frame(&frame.data).unwrap();
+        }
+        let length = writer.file_length();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        length
+    }
+
+    /// Names and frame counts of the files starting with `prefix`, sorted by name
+    fn recordings(directory: &Path, prefix: &str) -> Vec<(String, usize)>{
+        let mut recordings: Vec<(String, usize)> = fs::read_dir(directory).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.file_name().unwrap().to_string_lossy().starts_with(prefix))
+            .map(|path| (path.file_name().unwrap().to_string_lossy().into_owned(), AviReader::open(&path).unwrap().frame_count()))
+            .collect();
+        recordings.sort();
+        recordings
+    }
+
+    fn loop_segments_length(directory: &Path) -> u64{
+        loop_segments(directory).unwrap().iter().map(|(_, length)| length).sum()
+    }
+
+    #[test]
+    fn rotates_segments_started_in_the_same_second(){
+        let directory = temp_directory("rotate");
+        let mut recorder = LoopRecorder::new(directory.clone(), &config(Duration::from_secs(1), u64::MAX));
+        for frame in frames(25, Duration::from_millis(100)).iter(){
+            recorder.record(frame).unwrap();
+        }
+        recorder.finish_segment();
+        let counts: Vec<usize> = recordings(&directory, LOOP_SEGMENT_PREFIX).iter().map(|(_, frames)| *frames).collect();
+        assert_eq!(counts, vec![10, 10, 5]);
+        assert_eq!(recorder.finished.len(), 3);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn quota_deletes_oldest_segments(){
+        let directory = temp_directory("quota");
+        let quota = segment_length(10) * 5 / 2;
+        let mut recorder = LoopRecorder::new(directory.clone(), &config(Duration::from_secs(1), quota));
+        for frame in frames(50, Duration::from_millis(100)).iter(){
+            recorder.record(frame).unwrap();
+            assert!(loop_segments_length(&directory) <= quota);
+        }
+        recorder.finish_segment();
+        assert_eq!(recordings(&directory, LOOP_SEGMENT_PREFIX).len(), 2);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn ends_segment_early_when_over_quota(){
+        let directory = temp_directory("early-finish");
+        let quota = segment_length(10) * 3 / 2;
+        let mut recorder = LoopRecorder::new(directory.clone(), &config(Duration::from_secs(60), quota));
+        for frame in frames(30, Duration::from_millis(100)).iter(){
+            recorder.record(frame).unwrap();
+            //The segment being written is never the one deleted
+            assert!(recorder.segment.as_ref().unwrap().path.exists());
+            assert!(loop_segments_length(&directory) <= quota);
+        }
+        recorder.finish_segment();
+        let segments = recordings(&directory, LOOP_SEGMENT_PREFIX);
+        assert_eq!(segments.len(), 1);
+        assert!((10..=15).contains(&segments[0].1), "{:?}", segments);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn takes_over_segments_of_earlier_runs(){
+        let directory = temp_directory("existing");
+        for (name, length) in [("loop-20000101-000001.avi", 1000), ("loop-20000101-000000.avi", 1000), ("event-20000101-000000.avi", 5000), ("notes.txt", 5000)].iter(){
+            fs::write(directory.join(name), vec![0; *length]).unwrap();
+        }
+        let mut recorder = LoopRecorder::new(directory.clone(), &config(Duration::from_secs(60), 1500));
+        recorder.load_segments();
+        let mut names: Vec<String> = fs::read_dir(&directory).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        names.sort();
+        //Only the oldest loop segment is deleted, event clips and other files are left alone
+        assert_eq!(names, vec!["event-20000101-000000.avi", "loop-20000101-000001.avi", "notes.txt"]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn event_clip_starts_with_buffered_frames(){
+        let directory = temp_directory("event");
+        let mut recorder = EventRecorder::new(directory.clone(), &config(Duration::from_secs(60), u64::MAX));
+        let frames = frames(31, Duration::from_millis(100));
+        for frame in frames[..30].iter(){
+            recorder.record(frame.clone());
+        }
+        recorder.trigger();
+        recorder.record(frames[30].clone());
+        //Without a post event time the clip ends right away
+        recorder.check_clip_end();
+        assert!(recorder.clip.is_none());
+        //The frames up to a second before the last one
+        let clips = recordings(&directory, EVENT_CLIP_PREFIX);
+        assert_eq!(clips.iter().map(|(_, frames)| *frames).collect::<Vec<usize>>(), vec![11]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn later_event_extends_running_clip(){
+        let directory = temp_directory("event-extended");
+        let mut recorder = EventRecorder::new(directory.clone(), &RecorderConfig{
+            post_event: Duration::from_secs(60),
+            ..config(Duration::from_secs(60), u64::MAX)
+        });
+        let frames = frames(10, Duration::from_millis(100));
+        recorder.trigger();
+        for (i, frame) in frames.iter().enumerate(){
+            if i == 5{
+                recorder.trigger();
+            }
+            recorder.record(frame.clone());
+            recorder.check_clip_end();
+        }
+        assert!(recorder.clip.is_some());
+        recorder.finish_clip();
+        let clips = recordings(&directory, EVENT_CLIP_PREFIX);
+        assert_eq!(clips.iter().map(|(_, frames)| *frames).collect::<Vec<usize>>(), vec![10]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+use std::{str::FromStr, time::{Duration, Instant}};
+use crate::wificam::TcpMessage;
+
+/// What the window does while the car is not reversing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleMode{
+    /// Regular window showing the camera image
+    Normal,
+    /// Window is minimised
+    Minimized,
+    /// Small window showing the camera image, kept on top of other windows
+    Thumbnail,
+    /// Window stays open but shows nothing
+    Blank
+}
+
+impl FromStr for IdleMode{
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s{
+            "normal" => Ok(IdleMode::Normal),
+            "minimized" | "minimised" => Ok(IdleMode::Minimized),
+            "thumbnail" => Ok(IdleMode::Thumbnail),
+            "blank" => Ok(IdleMode::Blank),
+            _ => Err(())
+        }
+    }
+}
+
+/// Settings of the automatic reverse gear mode
+#[derive(Debug, Clone)]
+pub struct ReverseConfig{
+    pub idle_mode: IdleMode,
+    /// Time the yellow wire has to stay low before returning to the idle state
+    pub holdoff: Duration
+}
+
+impl Default for ReverseConfig{
+    fn default() -> Self {
+        ReverseConfig{
+            idle_mode: IdleMode::Normal,
+            holdoff: Duration::from_millis(2000)
+        }
+    }
+}
+
+/// State the window should be in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayState{
+    /// Fullscreen with parking guidelines
+    Reversing,
+    Idle(IdleMode)
+}
+
+/// Follows the yellow wire, which the camera drives from the reverse light,
+/// and decides when the window switches between reversing and idle.
+pub struct ReverseMode{
+    config: ReverseConfig,
+    state: DisplayState,
+    wire_high: bool,
+    wire_low_since: Instant
+}
+
+impl ReverseMode{
+    pub fn new(config: ReverseConfig) -> ReverseMode{
+        ReverseMode{
+            state: DisplayState::Idle(config.idle_mode),
+            config,
+            wire_high: false,
+            wire_low_since: Instant::now()
+        }
+    }
+
+    pub fn state(&self) -> DisplayState{
+        self.state
+    }
+
+    pub fn handle_message(&mut self, message: &TcpMessage){
+        match message{
+            TcpMessage::YellowWireHigh => self.wire_high = true,
+            TcpMessage::YellowWireLow => {
+                if self.wire_high{
+                    self.wire_low_since = Instant::now();
+                }
+                self.wire_high = false;
+            },
+            _ => ()
+        }
+    }
+
+    /// Returns the new display state if it changed since the last call
+    pub fn update(&mut self) -> Option<DisplayState>{
+        let state = if self.wire_high{
+            DisplayState::Reversing
+        }else if self.state == DisplayState::Reversing && self.wire_low_since.elapsed() < self.config.holdoff{
+            //Stay in reverse mode for a moment, so a flickering wire does not toggle the window
+            DisplayState::Reversing
+        }else{
+            DisplayState::Idle(self.config.idle_mode)
+        };
+        if state != self.state{
+            self.state = state;
+            Some(state)
+        }else{
+            None
+        }
+    }
+}
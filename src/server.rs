@@ -0,0 +1,178 @@
+use std::{fmt::Write as _, io::{self, prelude::*}, net::{SocketAddr, TcpListener, TcpStream}, sync::{Arc, Mutex, mpsc::{sync_channel, Receiver, SyncSender, TrySendError}}, thread::{self, JoinHandle}, time::Duration};
+use arc_swap::ArcSwapOption;
+use crate::{frame::EncodedFrame, source::SourceMonitor, wificam::ConnectionState};
+
+const BOUNDARY: &str = "dashcamframe";
+//Time a client may take to send its request or to accept more data
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const INDEX_PAGE: &str = "<!DOCTYPE html>
+<html>
+<head><title>Dashcam</title><meta name=\"viewport\" content=\"width=device-width\"></head>
+<body style=\"margin:0;background:#000\">
+<img src=\"/stream.mjpg\" style=\"width:100%;height:100vh;object-fit:contain\">
+</body>
+</html>
+";
+
+/// Serves the camera frames over HTTP without decoding them again.
+///
+/// * `/` is a page showing the stream
+/// * `/stream.mjpg` is the stream as `multipart/x-mixed-replace`, which browsers play natively
+/// * `/snapshot.jpg` is the latest frame
+/// * `/metrics` are the counters of the camera link in the Prometheus text format
+pub struct StreamServer{
+    last_frame: ArcSwapOption<EncodedFrame>,
+    /// Channels of the `/stream.mjpg` viewers, `None` once the source has no more frames
+    clients: Mutex<Option<Vec<SyncSender<Arc<EncodedFrame>>>>>,
+    camera: SourceMonitor
+}
+
+impl StreamServer{
+    /// Spawns the server threads, `frames` should be a subscription to the camera frames
+    pub fn start(address: SocketAddr, frames: Receiver<Arc<EncodedFrame>>, camera: SourceMonitor) -> io::Result<JoinHandle<()>>{
+        let listener = TcpListener::bind(address)?;
+        eprintln!("Serving camera stream on http://{}/", address);
+        let server = Arc::new(StreamServer{
+            last_frame: ArcSwapOption::from(None),
+            clients: Mutex::new(Some(Vec::new())),
+            camera
+        });
+        //Distribute frames to all connected clients
+        let distributor = server.clone();
+        thread::spawn(move || {
+            for frame in frames.iter(){
+                distributor.last_frame.store(Some(frame.clone()));
+                //Slow clients skip frames, disconnected ones are forgotten
+                if let Some(clients) = distributor.clients.lock().unwrap().as_mut(){
+                    clients.retain(|client| !matches!(client.try_send(frame.clone()), Err(TrySendError::Disconnected(_))));
+                }
+            }
+            //End the streams once the source is done, e.g. at the end of a file
+            distributor.clients.lock().unwrap().take();
+        });
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming(){
+                match stream{
+                    Ok(stream) => {
+                        let server = server.clone();
+                        thread::spawn(move || {
+                            match server.handle_client(stream){
+                                Ok(()) => (),
+                                //Viewers closing the stream is the normal way for it to end
+                                Err(e) if e.kind() == io::ErrorKind::BrokenPipe || e.kind() == io::ErrorKind::ConnectionReset => (),
+                                Err(e) => eprintln!("HTTP client error: {}", e)
+                            }
+                        });
+                    },
+                    Err(e) => eprintln!("Error accepting HTTP connection: {}", e)
+                }
+            }
+        }))
+    }
+
+    fn handle_client(&self, mut stream: TcpStream) -> io::Result<()>{
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        //Viewers that stop reading would otherwise block their thread forever
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        let path = match StreamServer::read_request_path(&mut stream)?{
+            Some(path) => path,
+            None => return StreamServer::respond(&mut stream, "400 Bad Request", "text/plain", b"Bad request")
+        };
+        //Ignore query strings, some players append them to avoid caching
+        match path.split('?').next().unwrap_or(""){
+            "/" | "/index.html" => StreamServer::respond(&mut stream, "200 OK", "text/html", INDEX_PAGE.as_bytes()),
+            "/snapshot.jpg" => match self.last_frame.load_full(){
+                Some(frame) => StreamServer::respond(&mut stream, "200 OK", "image/jpeg", &frame.data),
+                None => StreamServer::respond(&mut stream, "503 Service Unavailable", "text/plain", b"No frame received yet")
+            },
+            "/stream.mjpg" => self.stream(stream),
+            "/metrics" => StreamServer::respond(&mut stream, "200 OK", "text/plain; version=0.0.4", self.metrics().as_bytes()),
+            _ => StreamServer::respond(&mut stream, "404 Not Found", "text/plain", b"Not found")
+        }
+    }
+
+    /// Counters and state of the camera link in the Prometheus text exposition format
+    fn metrics(&self) -> String{
+        let stats = self.camera.stats();
+        let counters = [
+            ("packets_received", "UDP datagrams of the video stream received", stats.packets_received),
+            ("received_bytes", "Bytes of UDP datagrams received including headers", stats.bytes_received),
+            ("incomplete_packets", "Datagrams without a complete header or not matching the length in their header", stats.incomplete_packets),
+            ("out_of_order_packets", "Datagrams that arrived after one sent later", stats.out_of_order_packets),
+            ("frames_assembled", "Frames whose packets all arrived", stats.frames_assembled),
+            ("incomplete_frames", "Frames given up on because packets were missing", stats.incomplete_frames),
+            ("dropped_frames", "Frames dropped because the decoder couldn't keep up", stats.dropped_frames),
+            ("frames_decoded", "Frames decoded successfully", stats.frames_decoded),
+            ("decode_errors", "Frames that couldn't be decoded", stats.decode_errors),
+            ("wrong_size_frames", "Frames whose decoded pixels don't match their size", stats.wrong_size_frames),
+            ("keepalives_sent", "Keepalive messages sent to the camera", stats.keepalives_sent),
+            ("keepalives_acknowledged", "Keepalive messages acknowledged by the camera", stats.keepalives_acknowledged)
+        ];
+        let mut metrics = String::new();
+        for (name, help, value) in counters.iter(){
+            let _ = write!(metrics, "# HELP dashcam_{0}_total {1}\n# TYPE dashcam_{0}_total counter\ndashcam_{0}_total {2}\n", name, help, value);
+        }
+        //One series per state, the current one is 1
+        let state = self.camera.connection_state();
+        metrics.push_str("# HELP dashcam_connection_state State of the TCP control connection\n# TYPE dashcam_connection_state gauge\n");
+        for candidate in ConnectionState::ALL.iter(){
+            let _ = writeln!(metrics, "dashcam_connection_state{{state=\"{}\"}} {}", candidate.key(), (*candidate == state) as u8);
+        }
+        if let Some(frame) = self.last_frame.load_full(){
+            metrics.push_str("# HELP dashcam_last_frame_age_seconds Time since the last frame was received\n# TYPE dashcam_last_frame_age_seconds gauge\n");
+            let _ = writeln!(metrics, "dashcam_last_frame_age_seconds {:.3}", frame.received.elapsed().as_secs_f64());
+        }
+        metrics
+    }
+
+    /// Reads the request head and returns the path of a GET request
+    fn read_request_path(stream: &mut TcpStream) -> io::Result<Option<String>>{
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n"){
+            let length = stream.read(&mut buf)?;
+            if length == 0 || request.len() > 16 * 1024{
+                return Ok(None);
+            }
+            request.extend_from_slice(&buf[0..length]);
+        }
+        let request = String::from_utf8_lossy(&request);
+        let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+        match (request_line.next(), request_line.next()){
+            (Some("GET"), Some(path)) => Ok(Some(String::from(path))),
+            _ => Ok(None)
+        }
+    }
+
+    fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> io::Result<()>{
+        write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n", status, content_type, body.len())?;
+        stream.write_all(body)?;
+        stream.flush()
+    }
+
+    /// Sends frames to the client until it disconnects
+    fn stream(&self, mut stream: TcpStream) -> io::Result<()>{
+        let (sender, frames) = sync_channel(2);
+        //After the end of the source the stream only shows the last frame
+        if let Some(clients) = self.clients.lock().unwrap().as_mut(){
+            clients.push(sender);
+        }
+        write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n", BOUNDARY)?;
+        //Start with the latest frame, so the picture shows up immediately
+        if let Some(frame) = self.last_frame.load_full(){
+            StreamServer::write_part(&mut stream, &frame)?;
+        }
+        for frame in frames.iter(){
+            StreamServer::write_part(&mut stream, &frame)?;
+        }
+        Ok(())
+    }
+
+    fn write_part(stream: &mut TcpStream, frame: &EncodedFrame) -> io::Result<()>{
+        write!(stream, "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n", BOUNDARY, frame.data.len())?;
+        stream.write_all(&frame.data)?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()
+    }
+}
@@ -0,0 +1,50 @@
+use std::{path::Path, thread, time::Instant};
+use crate::{avi::AviReader, error::DashcamError, frame::EncodedFrame, wificam::{ConnectionState, WifiCamConfig}};
+use super::{FrameSource, SourceCore};
+
+/// Plays a recorded AVI file (loop segment or event clip) once at its original frame rate
+pub struct AviFileSource{
+    core: SourceCore
+}
+
+impl FrameSource for AviFileSource{
+    fn core(&self) -> &SourceCore{
+        &self.core
+    }
+
+    fn core_mut(&mut self) -> &mut SourceCore{
+        &mut self.core
+    }
+}
+
+impl AviFileSource{
+    pub fn open(path: &Path, config: &WifiCamConfig) -> Result<AviFileSource, DashcamError>{
+        let mut reader = AviReader::open(path)
+            .map_err(|e| DashcamError::io(format!("Error opening recording {}", path.display()), e))?;
+        let mut core = SourceCore::new(path.display().to_string(), config);
+        let sink = core.sink();
+        core.add_thread(thread::spawn(move || {
+            sink.set_state(ConnectionState::Replaying);
+            let start = Instant::now();
+            for index in 0..reader.frame_count(){
+                if !sink.sleep_until(start + reader.frame_duration() * index as u32){
+                    break;
+                }
+                match reader.read_frame(index){
+                    Ok(data) => sink.push_frame(EncodedFrame{
+                        data,
+                        received: Instant::now(),
+                        frame_number: index as u8,
+                        sub_packets: 0
+                    }),
+                    Err(e) => {
+                        sink.report(DashcamError::io(format!("Error reading frame {} of the recording", index), e));
+                        break;
+                    }
+                }
+            }
+            sink.set_state(ConnectionState::Disconnected);
+        }));
+        Ok(AviFileSource{ core })
+    }
+}
@@ -0,0 +1,65 @@
+use std::{fs, io, path::{Path, PathBuf}, thread, time::{Duration, Instant}};
+use crate::{error::DashcamError, frame::EncodedFrame, wificam::{ConnectionState, WifiCamConfig}};
+use super::{FrameSource, SourceCore};
+
+/// Shows the `.jpg` and `.jpeg` files of a directory once, sorted by name, at a fixed frame rate
+pub struct DirectorySource{
+    core: SourceCore
+}
+
+impl FrameSource for DirectorySource{
+    fn core(&self) -> &SourceCore{
+        &self.core
+    }
+
+    fn core_mut(&mut self) -> &mut SourceCore{
+        &mut self.core
+    }
+}
+
+impl DirectorySource{
+    pub fn open(directory: &Path, frame_rate: f64, config: &WifiCamConfig) -> Result<DirectorySource, DashcamError>{
+        let paths = jpeg_files(directory)
+            .and_then(|paths| if paths.is_empty() {Err(io::Error::new(io::ErrorKind::NotFound, "No JPEG files"))} else {Ok(paths)})
+            .map_err(|e| DashcamError::io(format!("Error opening {}", directory.display()), e))?;
+        let frame_duration = Duration::from_secs_f64(1.0 / frame_rate.max(0.001));
+        let mut core = SourceCore::new(directory.display().to_string(), config);
+        let sink = core.sink();
+        core.add_thread(thread::spawn(move || {
+            sink.set_state(ConnectionState::Replaying);
+            let start = Instant::now();
+            for (index, path) in paths.iter().enumerate(){
+                if !sink.sleep_until(start + frame_duration * index as u32){
+                    break;
+                }
+                //Files that can't be read are skipped, the decoder reports broken ones
+                match fs::read(path){
+                    Ok(data) => sink.push_frame(EncodedFrame{
+                        data,
+                        received: Instant::now(),
+                        frame_number: index as u8,
+                        sub_packets: 0
+                    }),
+                    Err(e) => sink.report(DashcamError::io(format!("Error reading {}", path.display()), e))
+                }
+            }
+            sink.set_state(ConnectionState::Disconnected);
+        }));
+        Ok(DirectorySource{ core })
+    }
+}
+
+fn jpeg_files(directory: &Path) -> io::Result<Vec<PathBuf>>{
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(directory)?{
+        let path = entry?.path();
+        let is_jpeg = path.extension().and_then(|extension| extension.to_str())
+            .map(|extension| extension.eq_ignore_ascii_case("jpg") || extension.eq_ignore_ascii_case("jpeg"))
+            .unwrap_or(false);
+        if is_jpeg{
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
@@ -0,0 +1,364 @@
+use std::{io::{self, prelude::*, BufReader}, net::{TcpStream, ToSocketAddrs}, thread, time::{Duration, Instant}};
+use crate::{error::DashcamError, frame::EncodedFrame, stats::WifiCamStats, wificam::{ConnectionState, WifiCamConfig, MIN_RECONNECT_DELAY}};
+use super::{FrameSink, FrameSource, SourceCore, SHUTDOWN_POLL_INTERVAL};
+
+//A part this long is no JPEG frame but a stream that lost track of its boundaries
+const MAX_PART_LENGTH: usize = 16 * 1024 * 1024;
+//Longest status, header or delimiter line, so a server that never sends a line break can't fill the memory
+const MAX_LINE_LENGTH: usize = 8 * 1024;
+
+/// Receives an MJPEG stream (`multipart/x-mixed-replace`) over HTTP, e.g. from `dashcam-headless` or an IP camera.
+/// Reconnects with the same backoff as the camera when the stream breaks down.
+pub struct HttpSource{
+    core: SourceCore
+}
+
+impl FrameSource for HttpSource{
+    fn core(&self) -> &SourceCore{
+        &self.core
+    }
+
+    fn core_mut(&mut self) -> &mut SourceCore{
+        &mut self.core
+    }
+}
+
+/// The parts of an `http://host[:port][/path]` URL, https isn't supported
+pub(super) struct StreamUrl{
+    url: String,
+    /// Host and port as given, for the `Host` header
+    authority: String,
+    host: String,
+    port: u16,
+    path: String
+}
+
+impl StreamUrl{
+    pub(super) fn parse(url: &str) -> Option<StreamUrl>{
+        let rest = url.strip_prefix("http://")?;
+        let (authority, path) = match rest.find('/'){
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/")
+        };
+        //The port follows the last colon, unless that is part of an IPv6 address in brackets
+        let (host, port) = match authority.rfind(':'){
+            Some(index) if !authority.ends_with(']') => (&authority[..index], authority[index + 1..].parse().ok()?),
+            _ => (authority, 80)
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty(){
+            return None;
+        }
+        Some(StreamUrl{
+            url: String::from(url),
+            authority: String::from(authority),
+            host: String::from(host),
+            port,
+            path: String::from(path)
+        })
+    }
+}
+
+impl HttpSource{
+    pub fn open(url: &str, config: &WifiCamConfig) -> Result<HttpSource, DashcamError>{
+        let url = StreamUrl::parse(url)
+            .ok_or_else(|| DashcamError::network(format!("Invalid stream URL {}", url), io::Error::new(io::ErrorKind::InvalidInput, "Expected http://<host>[:port]/<path>")))?;
+        let mut core = SourceCore::new(url.url.clone(), config);
+        let sink = core.sink();
+        let config = config.clone();
+        core.add_thread(thread::spawn(move || {
+            let mut reconnect_delay = MIN_RECONNECT_DELAY;
+            while !sink.is_shutting_down(){
+                sink.set_state(ConnectionState::Connecting);
+                let result = HttpSource::receive(&url, &config, &sink, &mut reconnect_delay);
+                if sink.is_shutting_down(){
+                    break;
+                }
+                sink.set_state(ConnectionState::Lost);
+                sink.report(DashcamError::reconnecting(result.err(), reconnect_delay));
+                sink.sleep_until(Instant::now() + reconnect_delay);
+                reconnect_delay = (reconnect_delay * 2).min(config.max_reconnect_delay);
+            }
+            sink.set_state(ConnectionState::Disconnected);
+        }));
+        Ok(HttpSource{ core })
+    }
+
+    /// Requests the stream and passes its frames on until it breaks down.
+    /// Resets `reconnect_delay` once a frame arrived, so the backoff only grows while the server is unreachable.
+    fn receive(url: &StreamUrl, config: &WifiCamConfig, sink: &FrameSink, reconnect_delay: &mut Duration) -> Result<(), DashcamError>{
+        let connect_error = |e| DashcamError::network(format!("Error connecting to {}", url.url), e);
+        let address = (url.host.as_str(), url.port).to_socket_addrs().map_err(connect_error)?.next()
+            .ok_or_else(|| connect_error(io::Error::new(io::ErrorKind::NotFound, "Host has no address")))?;
+        let mut stream = TcpStream::connect_timeout(&address, config.connection_timeout).map_err(connect_error)?;
+        stream.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL)).map_err(connect_error)?;
+        //HTTP/1.0, so the server doesn't answer with a chunked body
+        write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: multipart/x-mixed-replace\r\nConnection: close\r\n\r\n", url.path, url.authority).map_err(connect_error)?;
+
+        let receive_error = |e| DashcamError::network(format!("Error receiving from {}", url.url), e);
+        let mut reader = BufReader::new(PatientReader{ stream, sink: sink.clone(), timeout: config.connection_timeout });
+        let status = read_line(&mut reader).map_err(receive_error)?;
+        if status.split_whitespace().nth(1) != Some("200"){
+            return Err(DashcamError::Protocol(format!("{} answered {}", url.url, status)));
+        }
+        let headers = read_headers(&mut reader).map_err(receive_error)?;
+        let content_type = header(&headers, "content-type").unwrap_or("");
+        let boundary = multipart_boundary(content_type)
+            .ok_or_else(|| DashcamError::Protocol(format!("{} doesn't send an MJPEG stream (Content-Type: {})", url.url, content_type)))?;
+
+        let mut parts = MultipartReader{ reader, boundary: String::from(boundary), delimiter_read: false };
+        let mut frame_number: u8 = 0;
+        loop{
+            let data = parts.next_part().map_err(receive_error)?;
+            WifiCamStats::add(&sink.stats().bytes_received, data.len() as u64);
+            if data.is_empty(){
+                continue;
+            }
+            if sink.state() != ConnectionState::Streaming{
+                sink.set_state(ConnectionState::Streaming);
+                *reconnect_delay = MIN_RECONNECT_DELAY;
+            }
+            sink.push_frame(EncodedFrame{
+                data,
+                received: Instant::now(),
+                frame_number,
+                sub_packets: 0
+            });
+            frame_number = frame_number.wrapping_add(1);
+        }
+    }
+}
+
+/// Retries reads that time out, so a shutdown is noticed quickly without giving up on a slow stream too early
+struct PatientReader{
+    stream: TcpStream,
+    sink: FrameSink,
+    /// Time without any data after which the stream is considered lost
+    timeout: Duration
+}
+
+impl Read for PatientReader{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>{
+        let start = Instant::now();
+        loop{
+            match self.stream.read(buf){
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    if self.sink.is_shutting_down(){
+                        return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Shutting down"));
+                    }
+                    if start.elapsed() > self.timeout{
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "Server stopped sending"));
+                    }
+                },
+                result => return result
+            }
+        }
+    }
+}
+
+/// Splits the body of a `multipart/x-mixed-replace` response into its parts
+struct MultipartReader<R>{
+    reader: R,
+    /// Boundary parameter without the leading dashes
+    boundary: String,
+    /// Parts without a length end at the next delimiter, which is then already read
+    delimiter_read: bool
+}
+
+impl<R: BufRead> MultipartReader<R>{
+    /// Returns the body of the next part
+    fn next_part(&mut self) -> io::Result<Vec<u8>>{
+        //Skip everything up to the delimiter, e.g. the line break after the previous part
+        while !self.delimiter_read{
+            let line = read_line_bytes(&mut self.reader)?;
+            self.delimiter_read = self.is_delimiter(&line);
+        }
+        self.delimiter_read = false;
+        let headers = read_headers(&mut self.reader)?;
+        match header(&headers, "content-length"){
+            Some(length) => {
+                let length: usize = length.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid Content-Length {}", length)))?;
+                if length > MAX_PART_LENGTH{
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Part of {} bytes is too long", length)));
+                }
+                let mut data = vec![0; length];
+                self.reader.read_exact(&mut data)?;
+                Ok(data)
+            },
+            //Without a length the part ends with the line break before the next delimiter
+            None => {
+                let mut data = Vec::new();
+                loop{
+                    let line_start = data.len();
+                    //One byte more than allowed, so the check below notices a part that is too long
+                    if read_until_limit(&mut self.reader, &mut data, MAX_PART_LENGTH + 1 - line_start)? == 0{
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed by server"));
+                    }
+                    if self.is_delimiter(&data[line_start..]){
+                        data.truncate(line_start);
+                        if data.ends_with(b"\n"){
+                            data.pop();
+                        }
+                        if data.ends_with(b"\r"){
+                            data.pop();
+                        }
+                        self.delimiter_read = true;
+                        return Ok(data);
+                    }
+                    if data.len() > MAX_PART_LENGTH{
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Part without an end"));
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_delimiter(&self, line: &[u8]) -> bool{
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end();
+        line.starts_with("--") && line.trim_start_matches('-').trim_end_matches('-') == self.boundary
+    }
+}
+
+/// Boundary parameter of a multipart content type without the leading dashes
+fn multipart_boundary(content_type: &str) -> Option<&str>{
+    if !content_type.starts_with("multipart/"){
+        return None;
+    }
+    //Some servers put the dashes of the delimiter into the parameter as well
+    content_type.split(';').map(str::trim).find_map(|parameter| parameter.strip_prefix("boundary="))
+        .map(|boundary| boundary.trim_matches('"').trim_start_matches('-'))
+        .filter(|boundary| !boundary.is_empty())
+}
+
+/// Appends up to `limit` bytes to `buf`, stopping after a line break, returns the number of bytes read
+fn read_until_limit<R: BufRead>(reader: &mut R, buf: &mut Vec<u8>, limit: usize) -> io::Result<usize>{
+    reader.take(limit as u64).read_until(b'\n', buf)
+}
+
+/// Reads a line including the line break, fails if it is longer than `MAX_LINE_LENGTH`
+fn read_line_bytes<R: BufRead>(reader: &mut R) -> io::Result<Vec<u8>>{
+    let mut line = Vec::new();
+    if read_until_limit(reader, &mut line, MAX_LINE_LENGTH)? == 0{
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed by server"));
+    }
+    if !line.ends_with(b"\n") && line.len() == MAX_LINE_LENGTH{
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Line too long"));
+    }
+    Ok(line)
+}
+
+/// Reads a line of a header without the line break
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String>{
+    Ok(String::from_utf8_lossy(&read_line_bytes(reader)?).trim_end().to_string())
+}
+
+/// Reads header lines up to the empty line ending them, names are lowercase
+fn read_headers<R: BufRead>(reader: &mut R) -> io::Result<Vec<(String, String)>>{
+    let mut headers = Vec::new();
+    loop{
+        let line = read_line(reader)?;
+        if line.is_empty(){
+            return Ok(headers);
+        }
+        if let Some(index) = line.find(':'){
+            headers.push((line[..index].trim().to_ascii_lowercase(), String::from(line[index + 1..].trim())));
+        }
+    }
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str>{
+    headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn parts<'a>(boundary: &str, body: &'a [u8]) -> MultipartReader<&'a [u8]>{
+        MultipartReader{ reader: body, boundary: String::from(boundary), delimiter_read: false }
+    }
+
+    #[test]
+    fn parses_urls(){
+        let url = StreamUrl::parse("http://192.168.1.20:8080/stream.mjpg").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.path.as_str(), url.authority.as_str()), ("192.168.1.20", 8080, "/stream.mjpg", "192.168.1.20:8080"));
+        let url = StreamUrl::parse("http://camera.local").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.path.as_str()), ("camera.local", 80, "/"));
+    }
+
+    #[test]
+    fn parses_ipv6_urls(){
+        let url = StreamUrl::parse("http://[::1]:8080/stream.mjpg").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.authority.as_str()), ("::1", 8080, "[::1]:8080"));
+        let url = StreamUrl::parse("http://[fe80::1]/").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("fe80::1", 80));
+    }
+
+    #[test]
+    fn rejects_invalid_urls(){
+        assert!(StreamUrl::parse("https://camera.local/").is_none());
+        assert!(StreamUrl::parse("http:///stream.mjpg").is_none());
+        assert!(StreamUrl::parse("http://camera.local:port/").is_none());
+        assert!(StreamUrl::parse("http://camera.local:70000/").is_none());
+    }
+
+    #[test]
+    fn finds_boundary(){
+        assert_eq!(multipart_boundary("multipart/x-mixed-replace; boundary=frame"), Some("frame"));
+        assert_eq!(multipart_boundary("multipart/x-mixed-replace;boundary=\"--frame\""), Some("frame"));
+        assert_eq!(multipart_boundary("multipart/x-mixed-replace"), None);
+        assert_eq!(multipart_boundary("image/jpeg; boundary=frame"), None);
+    }
+
+    #[test]
+    fn reads_parts_with_length(){
+        let body = b"--frame\r\nContent-Type: image/jpeg\r\nContent-Length: 4\r\n\r\n\xff\r\n\x01\r\n--frame\r\nContent-Length: 2\r\n\r\nab\r\n";
+        let mut reader = parts("frame", body);
+        //The length allows line breaks and anything looking like a delimiter in the data
+        assert_eq!(reader.next_part().unwrap(), b"\xff\r\n\x01");
+        assert_eq!(reader.next_part().unwrap(), b"ab");
+        assert_eq!(reader.next_part().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn reads_parts_without_length(){
+        let body = b"preamble\r\n--frame\r\nContent-Type: image/jpeg\r\n\r\nfirst\nline\r\n--frame\r\n\r\nsecond\r\n--frame--\r\n";
+        let mut reader = parts("frame", body);
+        assert_eq!(reader.next_part().unwrap(), b"first\nline");
+        assert_eq!(reader.next_part().unwrap(), b"second");
+    }
+
+    #[test]
+    fn accepts_delimiters_with_extra_dashes(){
+        //The parameter was given as --frame, so the delimiters start with four dashes
+        let mut reader = parts("frame", b"----frame\r\n\r\nfirst\r\n----frame\r\nContent-Length: 6\r\n\r\nsecond");
+        assert_eq!(reader.next_part().unwrap(), b"first");
+        assert_eq!(reader.next_part().unwrap(), b"second");
+    }
+
+    #[test]
+    fn limits_part_length(){
+        let body = format!("--frame\r\nContent-Length: {}\r\n\r\n", MAX_PART_LENGTH + 1);
+        assert_eq!(parts("frame", body.as_bytes()).next_part().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut body = b"--frame\r\n\r\n".to_vec();
+        body.resize(body.len() + MAX_PART_LENGTH + 1, b'x');
+        body.extend_from_slice(b"\r\n");
+        assert_eq!(parts("frame", &body).next_part().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn limits_line_length(){
+        let mut line = vec![b'x'; MAX_LINE_LENGTH * 2];
+        assert_eq!(read_line(&mut &line[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        //A preamble or header line without an end
+        assert_eq!(parts("frame", &line).next_part().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut body = b"--frame\r\nContent-Type: ".to_vec();
+        body.append(&mut line);
+        assert_eq!(parts("frame", &body).next_part().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        //Lines up to the limit are fine
+        let mut line = vec![b'x'; MAX_LINE_LENGTH - 2];
+        line.extend_from_slice(b"\r\n");
+        assert_eq!(read_line(&mut &line[..]).unwrap().len(), MAX_LINE_LENGTH - 2);
+    }
+}
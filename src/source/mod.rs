@@ -0,0 +1,387 @@
+//! Where the frames come from: the camera, a recording, a directory of JPEG files, an MJPEG stream over HTTP or a test pattern.
+//!
+//! Every source pushes JPEG frames into a `SourceCore`, which decodes them and passes them on,
+//! so the window, the recorders and the HTTP server work the same with all of them.
+
+use std::{path::PathBuf, str::FromStr, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}, mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError}}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+use arc_swap::ArcSwap;
+use crate::{decode::{self, FrameQueue}, error::{DashcamError, ErrorEvents}, frame::{EncodedFrame, Frame}, stats::{WifiCamStats, WifiCamStatsSnapshot}, wificam::{ConnectionState, TcpMessage, WifiCam, WifiCamConfig}};
+
+mod avi_file;
+mod directory;
+mod http;
+mod test_pattern;
+
+pub use avi_file::AviFileSource;
+pub use directory::DirectorySource;
+pub use http::HttpSource;
+pub use test_pattern::{test_pattern, TestPatternSource};
+
+//Longest time a thread blocks before it notices a shutdown
+pub(crate) const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+//How often `next_frame` looks for a newly decoded frame
+const NEXT_FRAME_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Which source the frames are taken from
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceKind{
+    /// `camera`, the Wi-Fi camera or the replay of a capture file
+    Camera,
+    /// `file:<path>`, a recorded AVI file
+    File(PathBuf),
+    /// `directory:<path>`, the JPEG files of a directory sorted by name
+    Directory(PathBuf),
+    /// `http://<host>[:port]/<path>`, an MJPEG stream like the one served by `dashcam-headless`
+    Http(String),
+    /// `test-pattern`, generated colour bars with a moving square
+    TestPattern
+}
+
+impl FromStr for SourceKind{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let (kind, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        match kind{
+            "camera" => Ok(SourceKind::Camera),
+            "test-pattern" => Ok(SourceKind::TestPattern),
+            "file" if !target.is_empty() => Ok(SourceKind::File(PathBuf::from(target))),
+            "directory" if !target.is_empty() => Ok(SourceKind::Directory(PathBuf::from(target))),
+            "http" if http::StreamUrl::parse(s).is_some() => Ok(SourceKind::Http(String::from(s))),
+            _ => Err(String::from("Expected camera, file:<path>, directory:<path>, http://<host>[:port]/<path> or test-pattern"))
+        }
+    }
+}
+
+/// Settings of the sources other than the camera, which uses `WifiCamConfig`
+#[derive(Debug, Clone)]
+pub struct SourceConfig{
+    pub kind: SourceKind,
+    /// Frames per second of the directory and test pattern sources
+    pub frame_rate: f64
+}
+
+impl Default for SourceConfig{
+    fn default() -> Self {
+        SourceConfig{
+            kind: SourceKind::Camera,
+            frame_rate: 25.0
+        }
+    }
+}
+
+/// Opens the configured source, the decoding and reconnection settings of `camera` apply to all of them
+pub fn open(config: &SourceConfig, camera: &WifiCamConfig) -> Result<Box<dyn FrameSource>, DashcamError>{
+    Ok(match &config.kind{
+        SourceKind::Camera => Box::new(WifiCam::new(camera.clone())?),
+        SourceKind::File(path) => Box::new(AviFileSource::open(path, camera)?),
+        SourceKind::Directory(path) => Box::new(DirectorySource::open(path, config.frame_rate, camera)?),
+        SourceKind::Http(url) => Box::new(HttpSource::open(url, camera)?),
+        SourceKind::TestPattern => Box::new(TestPatternSource::new(config.frame_rate, camera))
+    })
+}
+
+/// Anything frames can be shown, recorded and served from.
+///
+/// Implementations start their threads when they are created and push their frames into a `SourceCore`,
+/// the provided methods all go through it.
+pub trait FrameSource: Send{
+    fn core(&self) -> &SourceCore;
+
+    fn core_mut(&mut self) -> &mut SourceCore;
+
+    /// Name, state and counters of the source
+    fn metadata(&self) -> SourceMetadata{
+        self.monitor().metadata()
+    }
+
+    /// The latest decoded frame, empty until the first one arrived
+    fn latest_frame(&self) -> Arc<Frame>{
+        self.core().last_frame.load_full()
+    }
+
+    /// Waits for the next decoded frame after the current one, None if none arrived within `timeout`.
+    /// Frames decoded while the caller is busy are skipped, use `subscribe_frames` to see every frame.
+    fn next_frame(&self, timeout: Duration) -> Option<Arc<Frame>>{
+        let current = self.latest_frame();
+        let deadline = Instant::now() + timeout;
+        loop{
+            let frame = self.latest_frame();
+            if !Arc::ptr_eq(&frame, &current){
+                return Some(frame);
+            }
+            let now = Instant::now();
+            if now >= deadline{
+                return None;
+            }
+            thread::sleep(NEXT_FRAME_POLL_INTERVAL.min(deadline - now));
+        }
+    }
+
+    /// Returns a channel receiving every following frame with the original JPEG bytes.
+    /// Frames are dropped for this subscriber if more than `capacity` frames are waiting.
+    fn subscribe_frames(&self, capacity: usize) -> Receiver<Arc<EncodedFrame>>{
+        let (sender, receiver) = sync_channel(capacity);
+        self.core().sink.jpeg_subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Returns a channel receiving the messages of the camera, e.g. about the yellow wire. Other sources don't send any.
+    fn subscribe_events(&self) -> Receiver<TcpMessage>{
+        let (sender, receiver) = channel();
+        self.core().sink.event_subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Returns a channel receiving the errors of the background threads
+    fn subscribe_errors(&self) -> Receiver<Arc<DashcamError>>{
+        self.core().sink.errors.subscribe()
+    }
+
+    /// Returns a handle to follow the state and counters from another thread
+    fn monitor(&self) -> SourceMonitor{
+        let core = self.core();
+        SourceMonitor{
+            name: core.name.clone(),
+            stats: core.sink.stats.clone(),
+            connection_state: core.sink.connection_state.clone()
+        }
+    }
+
+    /// Lets `shutdown` wait for a thread that reads from one of the subscriptions until it is closed
+    fn join_on_shutdown(&mut self, thread: JoinHandle<()>){
+        self.core_mut().subscriber_threads.push(thread);
+    }
+
+    /// Stops the threads of the source and the decoder, closes all subscriptions and waits for the subscriber threads,
+    /// so recordings are finished
+    fn shutdown(&mut self){
+        self.core_mut().shutdown();
+    }
+
+    /// Blocks until the source runs out of frames, e.g. at the end of a file, then shuts down
+    fn run(&mut self){
+        let core = self.core_mut();
+        SourceCore::join_all(&mut core.source_threads);
+        core.shutdown();
+    }
+}
+
+/// Name, state and counters of a source at one point in time
+#[derive(Debug, Clone)]
+pub struct SourceMetadata{
+    /// Where the frames come from, e.g. the address of the camera or a file name
+    pub name: String,
+    pub state: ConnectionState,
+    pub stats: WifiCamStatsSnapshot
+}
+
+/// Read-only view of the state and counters of a source, which can be handed to other threads
+#[derive(Clone)]
+pub struct SourceMonitor{
+    name: String,
+    stats: Arc<WifiCamStats>,
+    connection_state: Arc<ArcSwap<ConnectionState>>
+}
+
+impl SourceMonitor{
+    pub fn stats(&self) -> WifiCamStatsSnapshot{
+        self.stats.snapshot()
+    }
+
+    pub fn connection_state(&self) -> ConnectionState{
+        **self.connection_state.load()
+    }
+
+    pub fn metadata(&self) -> SourceMetadata{
+        SourceMetadata{
+            name: self.name.clone(),
+            state: self.connection_state(),
+            stats: self.stats()
+        }
+    }
+}
+
+/// Handed to the threads of a source to pass on what they receive, cheap to clone
+#[derive(Clone)]
+pub struct FrameSink{
+    decode_queue: Arc<FrameQueue>,
+    stats: Arc<WifiCamStats>,
+    jpeg_subscribers: Arc<Mutex<Vec<SyncSender<Arc<EncodedFrame>>>>>,
+    event_subscribers: Arc<Mutex<Vec<Sender<TcpMessage>>>>,
+    connection_state: Arc<ArcSwap<ConnectionState>>,
+    //Counts the connections to the camera, so the threads can tell a new session apart from a state change
+    sessions: Arc<AtomicU64>,
+    errors: ErrorEvents,
+    shutdown: Arc<AtomicBool>
+}
+
+impl FrameSink{
+    /// Passes a complete JPEG on to the subscribers and the decoder
+    pub fn push_frame(&self, frame: EncodedFrame){
+        WifiCamStats::increment(&self.stats.frames_assembled);
+        let frame = Arc::new(frame);
+        {
+            //Forget subscribers that went away, skip the ones that can't keep up
+            self.jpeg_subscribers.lock().unwrap().retain(|subscriber| !matches!(subscriber.try_send(frame.clone()), Err(TrySendError::Disconnected(_))));
+        }
+        //If the decoder falls behind the oldest waiting frame is dropped
+        if !self.decode_queue.push(frame){
+            WifiCamStats::increment(&self.stats.dropped_frames);
+        }
+    }
+
+    /// Passes a message of the camera on to all subscribers that are still listening
+    pub fn send_event(&self, message: TcpMessage){
+        self.event_subscribers.lock().unwrap().retain(|subscriber| subscriber.send(message.clone()).is_ok());
+    }
+
+    pub fn state(&self) -> ConnectionState{
+        **self.connection_state.load()
+    }
+
+    pub fn set_state(&self, state: ConnectionState){
+        self.connection_state.store(Arc::new(state));
+    }
+
+    /// Called on every new connection, returns the number of the session
+    pub fn start_session(&self) -> u64{
+        self.sessions.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Number of the current session, 0 before the first connection
+    pub fn session(&self) -> u64{
+        self.sessions.load(Ordering::SeqCst)
+    }
+
+    pub fn report(&self, error: DashcamError){
+        self.errors.report(error);
+    }
+
+    /// Counters of the source, `push_frame` counts the assembled and dropped frames itself
+    pub fn stats(&self) -> &WifiCamStats{
+        &self.stats
+    }
+
+    /// Threads of the source have to return soon after this turns true
+    pub fn is_shutting_down(&self) -> bool{
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Sleeps until `due` unless the source is shut down in the meantime, returns false if it was
+    pub fn sleep_until(&self, due: Instant) -> bool{
+        loop{
+            if self.is_shutting_down(){
+                return false;
+            }
+            let now = Instant::now();
+            if now >= due{
+                return true;
+            }
+            thread::sleep((due - now).min(SHUTDOWN_POLL_INTERVAL));
+        }
+    }
+}
+
+/// The parts all sources share: decoding the frames on worker threads, passing frames and events on
+/// to the subscribers, and stopping and joining every thread on shutdown or when dropped
+pub struct SourceCore{
+    name: String,
+    sink: FrameSink,
+    last_frame: Arc<ArcSwap<Frame>>,
+    //Threads of the source itself, which return once the sink is shutting down
+    source_threads: Vec<JoinHandle<()>>,
+    decode_threads: Vec<JoinHandle<()>>,
+    //Threads fed by the subscriptions, e.g. recorders, which finish once their channels are closed
+    subscriber_threads: Vec<JoinHandle<()>>
+}
+
+impl SourceCore{
+    /// Starts the decode workers, `name` describes the source in its metadata
+    pub fn new(name: impl Into<String>, config: &WifiCamConfig) -> SourceCore{
+        let last_frame = Arc::new(ArcSwap::from_pointee(Frame::empty()));
+        //Decoding runs on its own threads, so slow frames don't keep the source from receiving
+        let sink = FrameSink{
+            decode_queue: Arc::new(FrameQueue::new(config.decode_queue_length)),
+            stats: Arc::new(WifiCamStats::default()),
+            jpeg_subscribers: Arc::new(Mutex::new(Vec::new())),
+            event_subscribers: Arc::new(Mutex::new(Vec::new())),
+            connection_state: Arc::new(ArcSwap::from_pointee(ConnectionState::Disconnected)),
+            sessions: Arc::new(AtomicU64::new(0)),
+            errors: ErrorEvents::default(),
+            shutdown: Arc::new(AtomicBool::new(false))
+        };
+        let decode_threads = decode::start_decode_workers(sink.decode_queue.clone(), config.decode_workers, last_frame.clone(), sink.stats.clone(), sink.errors.clone());
+        SourceCore{
+            name: name.into(),
+            sink,
+            last_frame,
+            source_threads: Vec::new(),
+            decode_threads,
+            subscriber_threads: Vec::new()
+        }
+    }
+
+    /// Returns a sink for a thread of the source to push its frames into
+    pub fn sink(&self) -> FrameSink{
+        self.sink.clone()
+    }
+
+    /// Lets the shutdown wait for a thread of the source, which has to return once `FrameSink::is_shutting_down`
+    pub fn add_thread(&mut self, thread: JoinHandle<()>){
+        self.source_threads.push(thread);
+    }
+
+    fn shutdown(&mut self){
+        self.sink.shutdown.store(true, Ordering::SeqCst);
+        SourceCore::join_all(&mut self.source_threads);
+        self.sink.decode_queue.close();
+        SourceCore::join_all(&mut self.decode_threads);
+        //Disconnected channels end the loops of the subscribers
+        self.sink.jpeg_subscribers.lock().unwrap().clear();
+        self.sink.event_subscribers.lock().unwrap().clear();
+        SourceCore::join_all(&mut self.subscriber_threads);
+    }
+
+    fn join_all(threads: &mut Vec<JoinHandle<()>>){
+        for thread in threads.drain(..){
+            if thread.join().is_err(){
+                eprintln!("Source thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for SourceCore{
+    fn drop(&mut self){
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn next_frame_waits_for_a_new_frame(){
+        let mut source = TestPatternSource::new(50.0, &WifiCamConfig::default());
+        let first = source.next_frame(Duration::from_secs(5)).unwrap();
+        let second = source.next_frame(Duration::from_secs(5)).unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        source.shutdown();
+        assert!(source.next_frame(Duration::from_millis(50)).is_none());
+    }
+
+    #[test]
+    fn sessions_are_counted_across_clones(){
+        let core = SourceCore::new("test", &WifiCamConfig::default());
+        let (control, receiver) = (core.sink(), core.sink());
+        assert_eq!(receiver.session(), 0);
+        assert_eq!(control.start_session(), 1);
+        //The state may pass through several values between two checks, the counter can't be missed
+        control.set_state(ConnectionState::Streaming);
+        assert_eq!(control.start_session(), 2);
+        assert_eq!(receiver.session(), 2);
+    }
+}
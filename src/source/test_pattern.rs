@@ -0,0 +1,71 @@
+use std::{thread, time::{Duration, Instant}};
+use crate::{frame::EncodedFrame, jpeg, wificam::{ConnectionState, WifiCamConfig}};
+use super::{FrameSource, SourceCore};
+
+const WIDTH: usize = 640;
+const HEIGHT: usize = 360;
+const QUALITY: u8 = 80;
+
+/// Generates test pattern frames at a fixed frame rate, to try out the window, recorders and server without a camera
+pub struct TestPatternSource{
+    core: SourceCore
+}
+
+impl FrameSource for TestPatternSource{
+    fn core(&self) -> &SourceCore{
+        &self.core
+    }
+
+    fn core_mut(&mut self) -> &mut SourceCore{
+        &mut self.core
+    }
+}
+
+impl TestPatternSource{
+    pub fn new(frame_rate: f64, config: &WifiCamConfig) -> TestPatternSource{
+        let frame_duration = Duration::from_secs_f64(1.0 / frame_rate.max(0.001));
+        let mut core = SourceCore::new("test pattern", config);
+        let sink = core.sink();
+        core.add_thread(thread::spawn(move || {
+            sink.set_state(ConnectionState::Streaming);
+            let start = Instant::now();
+            let mut index = 0;
+            while sink.sleep_until(start + frame_duration * index as u32){
+                sink.push_frame(EncodedFrame{
+                    data: jpeg::encode_rgb(WIDTH, HEIGHT, &test_pattern(WIDTH, HEIGHT, index), QUALITY),
+                    received: Instant::now(),
+                    frame_number: index as u8,
+                    sub_packets: 0
+                });
+                index += 1;
+            }
+            sink.set_state(ConnectionState::Disconnected);
+        }));
+        TestPatternSource{ core }
+    }
+}
+
+/// Colour bars over a grey ramp, with a white square moving across so dropped or stuck frames are visible
+pub fn test_pattern(width: usize, height: usize, index: usize) -> Vec<u8>{
+    const BARS: [[u8; 3]; 7] = [
+        [191, 191, 191], [191, 191, 0], [0, 191, 191], [0, 191, 0], [191, 0, 191], [191, 0, 0], [0, 0, 191]
+    ];
+    let size = (height / 6).max(1);
+    let square_x = (index * 4) % (width.saturating_sub(size) + 1);
+    let square_y = height / 2 - size / 2;
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for y in 0..height{
+        for x in 0..width{
+            let color = if x >= square_x && x < square_x + size && y >= square_y && y < square_y + size{
+                [255, 255, 255]
+            }else if y < height * 3 / 4{
+                BARS[x * BARS.len() / width]
+            }else{
+                let grey = (x * 255 / width) as u8;
+                [grey, grey, grey]
+            };
+            pixels.extend_from_slice(&color);
+        }
+    }
+    pixels
+}
@@ -0,0 +1,73 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters of the camera link since the start, updated by the network and decode threads
+#[derive(Debug, Default)]
+pub struct WifiCamStats{
+    pub packets_received: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub incomplete_packets: AtomicU64,
+    pub out_of_order_packets: AtomicU64,
+    pub frames_assembled: AtomicU64,
+    pub incomplete_frames: AtomicU64,
+    pub dropped_frames: AtomicU64,
+    pub frames_decoded: AtomicU64,
+    pub decode_errors: AtomicU64,
+    pub wrong_size_frames: AtomicU64,
+    pub keepalives_sent: AtomicU64,
+    pub keepalives_acknowledged: AtomicU64
+}
+
+impl WifiCamStats{
+    pub fn add(counter: &AtomicU64, count: u64){
+        counter.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn increment(counter: &AtomicU64){
+        WifiCamStats::add(counter, 1);
+    }
+
+    /// Current values of all counters
+    pub fn snapshot(&self) -> WifiCamStatsSnapshot{
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        WifiCamStatsSnapshot{
+            packets_received: load(&self.packets_received),
+            bytes_received: load(&self.bytes_received),
+            incomplete_packets: load(&self.incomplete_packets),
+            out_of_order_packets: load(&self.out_of_order_packets),
+            frames_assembled: load(&self.frames_assembled),
+            incomplete_frames: load(&self.incomplete_frames),
+            dropped_frames: load(&self.dropped_frames),
+            frames_decoded: load(&self.frames_decoded),
+            decode_errors: load(&self.decode_errors),
+            wrong_size_frames: load(&self.wrong_size_frames),
+            keepalives_sent: load(&self.keepalives_sent),
+            keepalives_acknowledged: load(&self.keepalives_acknowledged)
+        }
+    }
+}
+
+/// Values of the `WifiCamStats` counters at one point in time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WifiCamStatsSnapshot{
+    /// UDP datagrams of the video stream, including broken ones
+    pub packets_received: u64,
+    /// Size of all received datagrams including their headers
+    pub bytes_received: u64,
+    /// Datagrams without a complete header, or shorter or longer than their header says
+    pub incomplete_packets: u64,
+    /// Datagrams that arrived after one the camera sent later
+    pub out_of_order_packets: u64,
+    /// Frames whose sub-packets all arrived
+    pub frames_assembled: u64,
+    /// Frames given up on because some of their packets never arrived
+    pub incomplete_frames: u64,
+    /// Assembled frames dropped because the decoder couldn't keep up
+    pub dropped_frames: u64,
+    pub frames_decoded: u64,
+    /// Frames that aren't valid JPEG images or have an unsupported pixel format
+    pub decode_errors: u64,
+    /// Frames whose decoded pixels don't match the size in their header
+    pub wrong_size_frames: u64,
+    pub keepalives_sent: u64,
+    pub keepalives_acknowledged: u64
+}
@@ -0,0 +1,277 @@
+use std::{fs::File, io::{self, prelude::*, BufReader}, net::{SocketAddr, TcpListener, UdpSocket}, path::PathBuf, str::{self, FromStr}, sync::Arc, thread, time::{Duration, Instant}};
+use arc_swap::ArcSwapOption;
+
+//Readings older than this are not shown, so the lines disappear when the source stops sending
+const MAX_READING_AGE: Duration = Duration::from_secs(1);
+//How often a file source is checked for new lines after reaching its end
+const FILE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Where the steering angle comes from.
+///
+/// Sockets and files carry the angle of the steering wheel in degrees as text, one value per datagram or line.
+#[derive(Clone)]
+pub enum SteeringSource{
+    /// `udp:<ip:port>`, datagrams received on a local port
+    Udp(SocketAddr),
+    /// `tcp:<ip:port>`, lines sent by a client connecting to a local port
+    Tcp(SocketAddr),
+    /// `file:<path>`, lines appended to a file or written into a named pipe
+    File(PathBuf),
+    /// `candump:<path>`, output of `candump` (e.g. from a `vcan` interface), `-` reads it from stdin
+    Candump(PathBuf)
+}
+
+impl FromStr for SteeringSource{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let (kind, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        let address = || target.parse().map_err(|_| format!("Invalid address {}", target));
+        match kind{
+            "udp" => Ok(SteeringSource::Udp(address()?)),
+            "tcp" => Ok(SteeringSource::Tcp(address()?)),
+            "file" if !target.is_empty() => Ok(SteeringSource::File(PathBuf::from(target))),
+            "candump" if !target.is_empty() => Ok(SteeringSource::Candump(PathBuf::from(target))),
+            _ => Err(String::from("Expected udp:<ip:port>, tcp:<ip:port>, file:<path> or candump:<path>"))
+        }
+    }
+}
+
+/// Steering input and the vehicle geometry needed to turn it into a trajectory
+#[derive(Clone)]
+pub struct SteeringConfig{
+    pub source: Option<SteeringSource>,
+    /// CAN frame carrying the steering angle as a signed big endian 16 bit value
+    pub can_id: u32,
+    /// Position of the value in the CAN frame data
+    pub can_offset: usize,
+    /// Degrees per unit of the CAN value
+    pub can_scale: f32,
+    /// Steering wheel angle per angle of the front wheels
+    pub steering_ratio: f32,
+    /// Distance between front and rear axle in meters
+    pub wheelbase: f32,
+    /// Width of the car in meters, which the static guidelines are calibrated to
+    pub vehicle_width: f32,
+    /// Bend the lines the other way, depending on the mounting of the camera
+    pub invert: bool
+}
+
+impl Default for SteeringConfig{
+    fn default() -> Self {
+        SteeringConfig{
+            source: None,
+            can_id: 0x25,
+            can_offset: 0,
+            can_scale: 1.0,
+            steering_ratio: 16.0,
+            wheelbase: 2.7,
+            vehicle_width: 1.8,
+            invert: false
+        }
+    }
+}
+
+struct SteeringReading{
+    /// Steering wheel angle in degrees, positive to the right
+    angle: f32,
+    received: Instant
+}
+
+/// Latest steering angle, read from a `SteeringSource` on a separate thread
+pub struct SteeringInput{
+    reading: Arc<ArcSwapOption<SteeringReading>>
+}
+
+impl SteeringInput{
+    pub fn start(source: SteeringSource, config: &SteeringConfig) -> SteeringInput{
+        let reading = Arc::new(ArcSwapOption::from(None));
+        let publish = {
+            let reading = reading.clone();
+            move |angle: f32| reading.store(Some(Arc::new(SteeringReading{ angle, received: Instant::now() })))
+        };
+        let (can_id, can_offset, can_scale) = (config.can_id, config.can_offset, config.can_scale);
+        thread::spawn(move || {
+            let result = match source{
+                SteeringSource::Udp(address) => read_udp(address, publish),
+                SteeringSource::Tcp(address) => read_tcp(address, publish),
+                SteeringSource::File(path) => read_file(path, publish),
+                SteeringSource::Candump(path) => read_candump(path, |id, data| {
+                    if id != can_id{
+                        return;
+                    }
+                    if let Some(value) = can_value(data, can_offset){
+                        publish(value as f32 * can_scale);
+                    }
+                })
+            };
+            if let Err(e) = result{
+                eprintln!("Steering input stopped: {}", e);
+            }
+        });
+        SteeringInput{ reading }
+    }
+
+    /// Steering wheel angle in degrees, None if the source hasn't sent anything recently
+    pub fn angle(&self) -> Option<f32>{
+        match &*self.reading.load(){
+            Some(reading) if reading.received.elapsed() < MAX_READING_AGE => Some(reading.angle),
+            _ => None
+        }
+    }
+}
+
+fn parse_angle(text: &str) -> Option<f32>{
+    text.trim().parse().ok().filter(|angle: &f32| angle.is_finite())
+}
+
+/// Signed big endian 16 bit value at `offset`, None if the frame is too short
+fn can_value(data: &[u8], offset: usize) -> Option<i16>{
+    match data.get(offset..offset.saturating_add(2))?{
+        &[high, low] => Some(i16::from_be_bytes([high, low])),
+        _ => None
+    }
+}
+
+fn read_udp(address: SocketAddr, publish: impl Fn(f32)) -> io::Result<()>{
+    let socket = UdpSocket::bind(address)?;
+    let mut buf = [0; 64];
+    loop{
+        let length = socket.recv(&mut buf)?;
+        if let Some(angle) = parse_angle(&String::from_utf8_lossy(&buf[0..length])){
+            publish(angle);
+        }
+    }
+}
+
+fn read_tcp(address: SocketAddr, publish: impl Fn(f32)) -> io::Result<()>{
+    let listener = TcpListener::bind(address)?;
+    //One client at a time, the next one is accepted once it disconnects
+    for stream in listener.incoming(){
+        //A client that fails during the handshake shouldn't stop the listener
+        let stream = match stream{
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Steering input accept error: {}", e);
+                continue;
+            }
+        };
+        for line in BufReader::new(stream).lines(){
+            match line{
+                Ok(line) => if let Some(angle) = parse_angle(&line){
+                    publish(angle);
+                },
+                Err(e) => {
+                    eprintln!("Steering input connection error: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Follows a file like `tail -f`
+fn read_file(path: PathBuf, publish: impl Fn(f32)) -> io::Result<()>{
+    let mut reader = BufReader::new(File::open(&path)?);
+    let mut line = Vec::new();
+    loop{
+        //Bytes, so a line that isn't valid text is skipped instead of ending the thread
+        if reader.read_until(b'\n', &mut line)? == 0{
+            thread::sleep(FILE_POLL_INTERVAL);
+            continue;
+        }
+        //Wait for the rest of a line that is still being written
+        if !line.ends_with(b"\n"){
+            continue;
+        }
+        if let Some(angle) = parse_angle(&String::from_utf8_lossy(&line)){
+            publish(angle);
+        }
+        line.clear();
+    }
+}
+
+fn read_candump(path: PathBuf, mut handle_frame: impl FnMut(u32, &[u8])) -> io::Result<()>{
+    let reader: Box<dyn BufRead> = if path.as_os_str() == "-"{
+        Box::new(BufReader::new(io::stdin()))
+    }else{
+        Box::new(BufReader::new(File::open(&path)?))
+    };
+    //Lines that aren't valid text are skipped like any other line that isn't a frame
+    for line in reader.split(b'\n'){
+        if let Some((id, data)) = parse_candump_line(&String::from_utf8_lossy(&line?)){
+            handle_frame(id, &data);
+        }
+    }
+    Ok(())
+}
+
+/// Parses a frame in the log format of `candump -L` (`(1612345678.123456) vcan0 025#0A1B`)
+/// or the default format of `candump` (`vcan0  025   [2]  0A 1B`)
+fn parse_candump_line(line: &str) -> Option<(u32, Vec<u8>)>{
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    if let Some(frame) = tokens.iter().find(|token| token.contains('#')){
+        let mut parts = frame.splitn(2, '#');
+        let id = u32::from_str_radix(parts.next()?, 16).ok()?;
+        let data = parts.next()?;
+        //Remote frames carry no data, CAN FD frames have a flags digit after a second #
+        if data.starts_with('R') || data.starts_with('#'){
+            return None;
+        }
+        //Work on bytes, slicing the str could split a multi-byte character of garbage input
+        if data.len() % 2 != 0{
+            return None;
+        }
+        let data = data.as_bytes().chunks(2)
+            .map(|digits| str::from_utf8(digits).ok().and_then(|digits| u8::from_str_radix(digits, 16).ok()))
+            .collect::<Option<Vec<u8>>>()?;
+        return Some((id, data));
+    }
+    let length_index = tokens.iter().position(|token| token.starts_with('[') && token.ends_with(']'))?;
+    let id = u32::from_str_radix(tokens.get(length_index.checked_sub(1)?)?, 16).ok()?;
+    let data = tokens[length_index + 1..].iter().map(|byte| u8::from_str_radix(byte, 16).ok()).collect::<Option<Vec<u8>>>()?;
+    Some((id, data))
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn parses_candump_log_format(){
+        assert_eq!(parse_candump_line("(1612345678.123456) vcan0 025#0A1BFF"), Some((0x25, vec![0x0A, 0x1B, 0xFF])));
+        assert_eq!(parse_candump_line("(1612345678.123456) vcan0 1F334455#"), Some((0x1F334455, vec![])));
+    }
+
+    #[test]
+    fn parses_candump_default_format(){
+        assert_eq!(parse_candump_line("  vcan0  025   [2]  0A 1B"), Some((0x25, vec![0x0A, 0x1B])));
+        assert_eq!(parse_candump_line("  vcan0  123   [0]"), Some((0x123, vec![])));
+    }
+
+    #[test]
+    fn skips_remote_and_can_fd_frames(){
+        assert_eq!(parse_candump_line("(1612345678.123456) vcan0 025#R"), None);
+        assert_eq!(parse_candump_line("(1612345678.123456) vcan0 025##10A1B"), None);
+        assert_eq!(parse_candump_line("  vcan0  025   [2]  remote request"), None);
+    }
+
+    #[test]
+    fn reads_can_value_inside_frame_only(){
+        assert_eq!(can_value(&[0x00, 0xFF, 0x38], 1), Some(-200));
+        assert_eq!(can_value(&[0x00, 0xFF], 1), None);
+        assert_eq!(can_value(&[0x00, 0xFF], usize::MAX), None);
+    }
+
+    #[test]
+    fn rejects_garbage_without_panicking(){
+        assert_eq!(parse_candump_line("(1612345678.123456) vcan0 025#0Aé"), None);
+        assert_eq!(parse_candump_line("(1612345678.123456) vcan0 025#0Aé1"), None);
+        assert_eq!(parse_candump_line("(1612345678.123456) vcan0 025#0A1"), None);
+        assert_eq!(parse_candump_line("vcan0 025 [2] 0A ü"), None);
+        assert_eq!(parse_candump_line("\u{FFFD}\u{FFFD}#\u{FFFD}"), None);
+        assert_eq!(parse_candump_line(""), None);
+    }
+}
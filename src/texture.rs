@@ -1,58 +1,76 @@
 use gl::types::*;
-use gl;
 use std::os::raw::c_void;
+use crate::frame::{Frame, PixelFormat};
 
+//Only part of OpenGL ES and compatibility profiles, so the generated bindings don't have it
+pub const LUMINANCE: GLenum = 0x1909;
 
 pub struct Texture{
     pub handle: GLuint,
     pub width: usize,
     pub height: usize,
-    pub alpha: bool
+    pub format: PixelFormat
 }
 
 impl Texture{
-    pub fn new(width: usize, height: usize, alpha: bool) -> Texture{
-        let mut textureId = 0;
-        let data = vec![0u8; width * height * if alpha {4} else {3}];
-        let format = match alpha {
-            false => gl::RGB,
-            true => gl::RGBA
-        };
-        
-
+    pub fn new(width: usize, height: usize, format: PixelFormat) -> Texture{
+        let mut texture_id = 0;
         unsafe{
-            gl::GenTextures(1, &mut textureId);
-            gl::BindTexture(gl::TEXTURE_2D, textureId);
-            gl::TexImage2D(gl::TEXTURE_2D, 0, format as i32, width as i32, height as i32,
-                0, format, gl::UNSIGNED_BYTE, &data[0] as *const u8 as *const c_void);
-            gl::GenerateMipmap(gl::TEXTURE_2D);
-
+            gl::GenTextures(1, &mut texture_id);
+            gl::BindTexture(gl::TEXTURE_2D, texture_id);
             gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
             gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
             gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
             gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
         }
-        Texture{
-            handle: textureId,
+        let mut texture = Texture{
+            handle: texture_id,
             width,
             height,
-            alpha
-        }
+            format
+        };
+        texture.allocate(&vec![0u8; width * height * format.bytes_per_pixel()]);
+        texture
     }
 
-    pub fn update(&self, data: &[u8]){
-        if data.is_empty(){
+    /// Uploads a frame, reallocating the texture if its size or format changed
+    pub fn update(&mut self, frame: &Frame){
+        if frame.is_empty(){
+            return;
+        }
+        if frame.width != self.width || frame.height != self.height || frame.format != self.format{
+            self.width = frame.width;
+            self.height = frame.height;
+            self.format = frame.format;
+            self.allocate(&frame.pixels);
             return;
         }
-        let format = match self.alpha {
-            false => gl::RGB,
-            true => gl::RGBA
-        };
         unsafe{
             gl::BindTexture(gl::TEXTURE_2D, self.handle);
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            gl::TexSubImage2D(gl::TEXTURE_2D, 0, 0, 0, self.width as i32, self.height as i32,
+                Texture::gl_format(self.format), gl::UNSIGNED_BYTE, &frame.pixels[0] as *const u8 as *const c_void);
+            gl::GenerateMipmap(gl::TEXTURE_2D);
+        }
+    }
+
+    fn allocate(&mut self, data: &[u8]){
+        let format = Texture::gl_format(self.format);
+        let pixels = if data.is_empty() {std::ptr::null()} else {&data[0] as *const u8 as *const c_void};
+        unsafe{
+            gl::BindTexture(gl::TEXTURE_2D, self.handle);
+            //Rows of odd width RGB or greyscale images are not 4 byte aligned
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
             gl::TexImage2D(gl::TEXTURE_2D, 0, format as i32, self.width as i32, self.height as i32,
-                0, format, gl::UNSIGNED_BYTE, &data[0] as *const u8 as *const c_void);
+                0, format, gl::UNSIGNED_BYTE, pixels);
             gl::GenerateMipmap(gl::TEXTURE_2D);
         }
     }
+
+    fn gl_format(format: PixelFormat) -> GLenum{
+        match format{
+            PixelFormat::Rgb24 => gl::RGB,
+            PixelFormat::Luma8 => LUMINANCE
+        }
+    }
 }
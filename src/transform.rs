@@ -0,0 +1,315 @@
+use std::str::FromStr;
+
+/// Clockwise rotation of the camera image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation{
+    None,
+    Quarter,
+    Half,
+    ThreeQuarters
+}
+
+impl FromStr for Rotation{
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s{
+            "0" => Ok(Rotation::None),
+            "90" => Ok(Rotation::Quarter),
+            "180" => Ok(Rotation::Half),
+            "270" => Ok(Rotation::ThreeQuarters),
+            _ => Err(())
+        }
+    }
+}
+
+impl Rotation{
+    pub fn degrees(&self) -> u32{
+        match self{
+            Rotation::None => 0,
+            Rotation::Quarter => 90,
+            Rotation::Half => 180,
+            Rotation::ThreeQuarters => 270
+        }
+    }
+
+    /// The rotation turned by another 90 degrees clockwise
+    pub fn next(&self) -> Rotation{
+        match self{
+            Rotation::None => Rotation::Quarter,
+            Rotation::Quarter => Rotation::Half,
+            Rotation::Half => Rotation::ThreeQuarters,
+            Rotation::ThreeQuarters => Rotation::None
+        }
+    }
+}
+
+/// Orientation of the camera image on screen, to match how the camera is mounted.
+///
+/// The image is rotated first, then mirrored and flipped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageTransform{
+    /// Swap left and right, like a rear-view mirror
+    pub mirror: bool,
+    /// Swap top and bottom
+    pub flip: bool,
+    pub rotation: Rotation
+}
+
+impl Default for ImageTransform{
+    fn default() -> Self {
+        ImageTransform{
+            mirror: false,
+            flip: false,
+            rotation: Rotation::None
+        }
+    }
+}
+
+impl ImageTransform{
+    /// The config file entries describing this transform
+    pub fn settings(&self) -> Vec<(&'static str, String)>{
+        vec![
+            ("mirror", self.mirror.to_string()),
+            ("flip", self.flip.to_string()),
+            ("rotation", self.rotation.degrees().to_string())
+        ]
+    }
+
+    /// Column major matrix from a position on screen to the texture coordinate shown there,
+    /// both relative to the centre
+    pub fn matrix(&self) -> [f32; 4]{
+        //Looking up the texture undoes the transform: mirror and flip first, then rotate counterclockwise
+        let (cos, sin) = match self.rotation{
+            Rotation::None => (1.0, 0.0),
+            Rotation::Quarter => (0.0, 1.0),
+            Rotation::Half => (-1.0, 0.0),
+            Rotation::ThreeQuarters => (0.0, -1.0)
+        };
+        let x = if self.mirror {-1.0} else {1.0};
+        let y = if self.flip {-1.0} else {1.0};
+        [cos * x, sin * x, -sin * y, cos * y]
+    }
+
+    /// Aspect ratio (width / height) of an image of the given size once it is rotated
+    pub fn aspect_ratio(&self, width: usize, height: usize) -> f32{
+        match self.rotation{
+            Rotation::Quarter | Rotation::ThreeQuarters => height as f32 / width.max(1) as f32,
+            Rotation::None | Rotation::Half => width as f32 / height.max(1) as f32
+        }
+    }
+}
+
+/// How the image is fitted into a window of a different aspect ratio
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScaleMode{
+    /// Show the whole image with bars at the sides or at the top and bottom
+    Fit,
+    /// Cover the whole window, cutting off parts of the image
+    Fill,
+    /// Cover the whole window, distorting the image
+    Stretch
+}
+
+impl FromStr for ScaleMode{
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s{
+            "fit" => Ok(ScaleMode::Fit),
+            "fill" => Ok(ScaleMode::Fill),
+            "stretch" => Ok(ScaleMode::Stretch),
+            _ => Err(())
+        }
+    }
+}
+
+impl ScaleMode{
+    /// Value in the config file
+    pub fn key(&self) -> &'static str{
+        match self{
+            ScaleMode::Fit => "fit",
+            ScaleMode::Fill => "fill",
+            ScaleMode::Stretch => "stretch"
+        }
+    }
+
+    pub fn next(&self) -> ScaleMode{
+        match self{
+            ScaleMode::Fit => ScaleMode::Fill,
+            ScaleMode::Fill => ScaleMode::Stretch,
+            ScaleMode::Stretch => ScaleMode::Fit
+        }
+    }
+}
+
+const MIN_ZOOM: f32 = 1.0;
+const MAX_ZOOM: f32 = 8.0;
+
+/// Size and position of the image in the window, in normalized device coordinates
+#[derive(Debug, Clone)]
+pub struct View{
+    pub mode: ScaleMode,
+    zoom: f32,
+    pan: [f32; 2],
+    //Aspect ratios (image, window) of the last `quad`, which decide how far the image can be moved
+    aspect_ratios: (f32, f32)
+}
+
+impl View{
+    pub fn new(mode: ScaleMode) -> View{
+        View{
+            mode,
+            zoom: MIN_ZOOM,
+            pan: [0.0, 0.0],
+            aspect_ratios: (1.0, 1.0)
+        }
+    }
+
+    /// Zooms by `factor`, keeping the point under the cursor in place
+    pub fn zoom_at(&mut self, factor: f32, cursor: [f32; 2]){
+        let zoom = (self.zoom * factor).clamp(MIN_ZOOM, MAX_ZOOM);
+        let factor = zoom / self.zoom;
+        self.zoom = zoom;
+        self.pan_to([cursor[0] - (cursor[0] - self.pan[0]) * factor, cursor[1] - (cursor[1] - self.pan[1]) * factor]);
+    }
+
+    /// Moves the image by a distance in normalized device coordinates
+    pub fn pan_by(&mut self, dx: f32, dy: f32){
+        self.pan_to([self.pan[0] + dx, self.pan[1] + dy]);
+    }
+
+    pub fn reset(&mut self){
+        self.zoom = MIN_ZOOM;
+        self.pan = [0.0, 0.0];
+    }
+
+    //An axis where the image is larger than the window can be moved until the image edge reaches the window edge,
+    //a smaller one stays centred
+    fn pan_to(&mut self, pan: [f32; 2]){
+        let scale = self.scale();
+        let limit = [(scale[0] * self.zoom - 1.0).max(0.0), (scale[1] * self.zoom - 1.0).max(0.0)];
+        self.pan = [pan[0].clamp(-limit[0], limit[0]), pan[1].clamp(-limit[1], limit[1])];
+    }
+
+    //Size of the unzoomed image relative to the window
+    fn scale(&self) -> [f32; 2]{
+        //Above 1 when the image is wider than the window
+        let ratio = self.aspect_ratios.0 / self.aspect_ratios.1;
+        match self.mode{
+            ScaleMode::Fit if ratio > 1.0 => [1.0, 1.0 / ratio],
+            ScaleMode::Fit => [ratio, 1.0],
+            ScaleMode::Fill if ratio > 1.0 => [ratio, 1.0],
+            ScaleMode::Fill => [1.0, 1.0 / ratio],
+            ScaleMode::Stretch => [1.0, 1.0]
+        }
+    }
+
+    /// Scale and offset of the fullscreen quad, for an image and a window with the given aspect ratios (width / height).
+    ///
+    /// Panning is limited to the image as placed here, so this is called again whenever the window, image or mode change.
+    pub fn quad(&mut self, image_aspect: f32, window_aspect: f32) -> ([f32; 2], [f32; 2]){
+        self.aspect_ratios = (image_aspect, window_aspect);
+        //The window or the image may have become larger than the current offset allows
+        self.pan_to(self.pan);
+        let scale = self.scale();
+        ([scale[0] * self.zoom, scale[1] * self.zoom], self.pan)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn transform(mirror: bool, flip: bool, rotation: Rotation) -> ImageTransform{
+        ImageTransform{mirror, flip, rotation}
+    }
+
+    //Texture coordinate looked up at a screen position, relative to the centre
+    fn lookup(transform: &ImageTransform, position: [f32; 2]) -> [f32; 2]{
+        let m = transform.matrix();
+        [m[0] * position[0] + m[2] * position[1], m[1] * position[0] + m[3] * position[1]]
+    }
+
+    #[test]
+    fn matrix_without_transform_is_identity(){
+        assert_eq!(ImageTransform::default().matrix(), [1.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn matrix_mirrors_and_flips(){
+        assert_eq!(lookup(&transform(true, false, Rotation::None), [1.0, 0.5]), [-1.0, 0.5]);
+        assert_eq!(lookup(&transform(false, true, Rotation::None), [1.0, 0.5]), [1.0, -0.5]);
+        assert_eq!(lookup(&transform(true, true, Rotation::None), [1.0, 0.5]), [-1.0, -0.5]);
+    }
+
+    #[test]
+    fn matrix_rotates_clockwise(){
+        //Undoing a clockwise rotation turns the lookup counterclockwise
+        assert_eq!(lookup(&transform(false, false, Rotation::Quarter), [1.0, 0.0]), [0.0, 1.0]);
+        assert_eq!(lookup(&transform(false, false, Rotation::Half), [1.0, 0.0]), [-1.0, 0.0]);
+        assert_eq!(lookup(&transform(false, false, Rotation::ThreeQuarters), [1.0, 0.0]), [0.0, -1.0]);
+    }
+
+    #[test]
+    fn matrix_rotates_before_mirroring(){
+        //Mirroring applies to the screen, so the left of the screen shows what was on the right
+        let rotated = transform(true, false, Rotation::Quarter);
+        assert_eq!(lookup(&rotated, [-1.0, 0.0]), [0.0, 1.0]);
+        assert_eq!(lookup(&rotated, [0.0, 1.0]), [-1.0, 0.0]);
+    }
+
+    #[test]
+    fn quad_fits_fills_and_stretches(){
+        let mut view = View::new(ScaleMode::Fit);
+        assert_eq!(view.quad(2.0, 1.0), ([1.0, 0.5], [0.0, 0.0]));
+        assert_eq!(view.quad(0.5, 1.0), ([0.5, 1.0], [0.0, 0.0]));
+        view.mode = ScaleMode::Fill;
+        assert_eq!(view.quad(2.0, 1.0), ([2.0, 1.0], [0.0, 0.0]));
+        assert_eq!(view.quad(0.5, 1.0), ([1.0, 2.0], [0.0, 0.0]));
+        view.mode = ScaleMode::Stretch;
+        assert_eq!(view.quad(2.0, 1.0), ([1.0, 1.0], [0.0, 0.0]));
+    }
+
+    #[test]
+    fn zoom_keeps_point_under_cursor(){
+        let mut view = View::new(ScaleMode::Stretch);
+        view.quad(1.0, 1.0);
+        view.zoom_at(2.0, [0.5, 0.5]);
+        //The image point at the cursor was at 0.5 and stays there: pan + 0.5 * zoom == 0.5
+        assert_eq!(view.quad(1.0, 1.0), ([2.0, 2.0], [-0.5, -0.5]));
+        view.zoom_at(100.0, [0.0, 0.0]);
+        assert_eq!(view.quad(1.0, 1.0).0, [MAX_ZOOM, MAX_ZOOM]);
+        view.zoom_at(0.001, [0.0, 0.0]);
+        assert_eq!(view.quad(1.0, 1.0), ([MIN_ZOOM, MIN_ZOOM], [0.0, 0.0]));
+    }
+
+    #[test]
+    fn pan_is_limited_per_axis(){
+        //A wide image fitted into a square window is letterboxed, at zoom 2 it fills the height exactly
+        let mut view = View::new(ScaleMode::Fit);
+        view.quad(2.0, 1.0);
+        view.zoom_at(2.0, [0.0, 0.0]);
+        view.pan_by(5.0, 5.0);
+        assert_eq!(view.quad(2.0, 1.0), ([2.0, 1.0], [1.0, 0.0]));
+        view.pan_by(-5.0, -5.0);
+        assert_eq!(view.quad(2.0, 1.0).1, [-1.0, 0.0]);
+    }
+
+    #[test]
+    fn filled_image_can_pan_without_zoom(){
+        //The cut off sides of a filled wide image can be brought into view
+        let mut view = View::new(ScaleMode::Fill);
+        view.quad(2.0, 1.0);
+        view.pan_by(5.0, 5.0);
+        assert_eq!(view.quad(2.0, 1.0).1, [1.0, 0.0]);
+        //Once the window is as wide as the image there is nothing left to show
+        assert_eq!(view.quad(2.0, 2.0).1, [0.0, 0.0]);
+    }
+
+    #[test]
+    fn rotation_swaps_aspect_ratio(){
+        assert_eq!(transform(false, false, Rotation::None).aspect_ratio(640, 480), 640.0 / 480.0);
+        assert_eq!(transform(false, false, Rotation::Quarter).aspect_ratio(640, 480), 480.0 / 640.0);
+    }
+}
@@ -1,319 +1,409 @@
-use std::{collections::VecDeque, net::{TcpStream, UdpSocket}, thread::{self, JoinHandle}, time::{Duration, SystemTime, UNIX_EPOCH}, u16};
-use std::sync::mpsc::{channel, Sender,Receiver};
-use arc_swap::ArcSwap;
-use jpeg_decoder::Decoder;
-use ringbuf::{RingBuffer, Producer, Consumer};
-use smallvec::SmallVec;
+use std::{net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, UdpSocket}, path::PathBuf, thread::{self, JoinHandle}, time::{Duration, Instant}};
 use std::io::prelude::*;
 use std::sync::Arc;
+use crate::{capture::{CaptureReader, CaptureWriter, RecordKind}, error::DashcamError, frame::EncodedFrame, reassembly::{AssembledFrame, Reassembler}, recorder, source::{FrameSink, FrameSource, SourceCore, SHUTDOWN_POLL_INTERVAL}, stats::WifiCamStats};
 
-pub struct WifiCam{
-    udp_thread: JoinHandle<()>,
-    tcp_thread: JoinHandle<()>,
-    //jpeg_thread: JoinHandle<()>,
-    pub tcp_messages: Receiver<TcpMessage>,
-    pub last_frame: Arc<ArcSwap<Vec<u8>>>
+/// Network settings used to talk to the camera
+#[derive(Debug, Clone)]
+pub struct WifiCamConfig{
+    /// Address of the camera itself
+    pub camera_ip: IpAddr,
+    /// TCP port the camera listens on for the init sequence and keepalives
+    pub control_port: u16,
+    /// Local address the video stream is received on
+    pub bind_address: IpAddr,
+    /// Local UDP port the camera sends the video stream to
+    pub bind_port: u16,
+    /// Time between two keepalive messages
+    pub keepalive_interval: Duration,
+    /// Time without any answer from the camera after which the connection is considered lost
+    pub connection_timeout: Duration,
+    /// Upper limit for the exponential backoff between reconnection attempts
+    pub max_reconnect_delay: Duration,
+    /// Number of frames that can be reassembled at the same time when their packets arrive interleaved
+    pub reorder_window: usize,
+    /// Time after which a frame with missing packets is given up on
+    pub frame_timeout: Duration,
+    /// Number of frames waiting for the decoder before the oldest one is dropped
+    pub decode_queue_length: usize,
+    /// Number of threads decoding frames
+    pub decode_workers: usize,
+    /// File every packet of the session is recorded into
+    pub capture_file: Option<PathBuf>,
+    /// Capture file that is played back instead of talking to the camera
+    pub replay_file: Option<PathBuf>,
+    /// Playback speed of the capture file relative to the original timing, 0 plays it back as fast as possible
+    pub replay_speed: f64
 }
 
-impl WifiCam{
-    pub fn run(self){
-        self.udp_thread.join();
-        self.tcp_thread.join();
-        //self.jpeg_thread.join();
+impl Default for WifiCamConfig{
+    fn default() -> Self {
+        WifiCamConfig{
+            camera_ip: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)),
+            control_port: 5252,
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            bind_port: 5555,
+            keepalive_interval: Duration::from_millis(1000),
+            connection_timeout: Duration::from_millis(5000),
+            max_reconnect_delay: Duration::from_millis(10000),
+            reorder_window: 4,
+            frame_timeout: Duration::from_millis(500),
+            decode_queue_length: 2,
+            decode_workers: 1,
+            capture_file: None,
+            replay_file: None,
+            replay_speed: 1.0
+        }
+    }
+}
+
+impl WifiCamConfig{
+    pub fn camera_address(&self) -> SocketAddr{
+        SocketAddr::new(self.camera_ip, self.control_port)
+    }
+
+    pub fn bind_address(&self) -> SocketAddr{
+        SocketAddr::new(self.bind_address, self.bind_port)
     }
+}
+
+//Magic sequence captured from the app MRT_Camera with wireshark
+const INIT_SEQUENCE: [u8; 20] = [
+    0x01, 0x01, 0x02, 0x10,
+    0x02, 0x01, 0x03, 0x20, 0x02, 0x01, 0x03, 0x20,
+    0x0e, 0x01, 0xaf, 0xe0, 0x24, 0x01, 0xc0, 0x42
+];
+const KEEPALIVE_SEQUENCE: [u8; 4] = [
+    0x0e, 0x01, 0xaf, 0xe0
+];
+pub(crate) const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(250);
+
+/// State of the TCP control connection to the camera, other sources use it for their connection or playback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState{
+    /// No connection attempt was made yet
+    Disconnected,
+    /// Trying to open the TCP connection
+    Connecting,
+    /// Init sequence was sent, waiting for the camera to answer
+    Initialized,
+    /// Camera answered and keeps acknowledging keepalives
+    Streaming,
+    /// Connection broke down, waiting before the next attempt
+    Lost,
+    /// Packets come from a capture file instead of the camera, or frames from a recording
+    Replaying
+}
+
+impl ConnectionState{
+    pub const ALL: [ConnectionState; 6] = [
+        ConnectionState::Disconnected,
+        ConnectionState::Connecting,
+        ConnectionState::Initialized,
+        ConnectionState::Streaming,
+        ConnectionState::Lost,
+        ConnectionState::Replaying
+    ];
 
-    pub fn new() -> WifiCam{
-        let last_frame = Arc::new(ArcSwap::from_pointee(Vec::new()));
-        let (udp_thread, consumer) = WifiCam::start_udp_receiver(last_frame.clone());
-        let (tcp_thread, tcp_messages) = WifiCam::send_init_sequence();
-        //let (last_frame, jpeg_thread) = WifiCam::start_jpeg_thread(consumer);
-        WifiCam{
-            udp_thread,
-            tcp_thread,
-            tcp_messages,
-            //jpeg_thread,
-            last_frame
+    /// Lowercase name, e.g. for labels
+    pub fn key(&self) -> &'static str{
+        match self{
+            ConnectionState::Disconnected => "disconnected",
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Initialized => "initialized",
+            ConnectionState::Streaming => "streaming",
+            ConnectionState::Lost => "lost",
+            ConnectionState::Replaying => "replaying"
         }
     }
+}
 
-    fn start_udp_receiver(last_frame: Arc<ArcSwap<Vec<u8>>>) -> (JoinHandle<()>, Consumer<u8>){
-        //Create ringbuffer to communicate with decoder
-        let ringbuffer = RingBuffer::new(1024 * 1024);
-        let (mut producer, consumer) = ringbuffer.split();
-        let mut raw_jpeg_buffer = vec![0u8; 128 * 1024];
-        let mut raw_jpeg_buffer_length = 0;
-        let mut frame_reception_state = FrameReceptionState::WaitingForFrameStart;
-        //Spawn udp receiver thread
-        let udp_thread = thread::spawn(move || {
-            //Bind to address
-            let socket = UdpSocket::bind("192.168.1.2:5555").expect("Error binding to UDP socket");
-            //Receive packets and write to ringbuffer
-            let mut buf = vec![0; 1024 * 32];
-            loop{
-                let (length, src) = socket.recv_from(&mut buf).expect("Error receiving UDP data");
-                if length < 9{
-                    eprintln!("Received less than 9 bytes (no header)");
-                    continue;
-                }
-                let header = UDPFrameHeader::from(&buf[0..]);
-                let data = &buf[9..length];
-                if data.len() + 9 != header.packet_length as usize{
-                    eprintln!("Received incomplete packet! {} != {}", data.len(), header.packet_length - 9);
-                }
+/// Talks to the camera and decodes its video stream, or replays a capture file instead
+pub struct WifiCam{
+    core: SourceCore
+}
 
-                match frame_reception_state{
-                    FrameReceptionState::WaitingForFrameStart => {
-                        if header.sub_packet_number != 0{ //Received packet from another image, discard
-                            continue;
-                        }else{
-                            (&mut raw_jpeg_buffer[0..]).write_all(data).expect("Packet way longer than expected (memory corruption??)");
-                            //eprintln!("initial bytes: {:?}", &raw_jpeg_buffer[0..5]);
-                            //eprintln!("inital data bytes: {:?}", &data[0..5]);
-                            raw_jpeg_buffer_length = data.len();
-                            if header.total_sub_packets == 1{
-                                if let Some(frame) = WifiCam::decode_jpeg_frame(&raw_jpeg_buffer){
-                                    last_frame.store(Arc::new(frame));
-                                }
-                                frame_reception_state = FrameReceptionState::WaitingForFrameStart;
-                                continue;
-                            }else{
-                                frame_reception_state = FrameReceptionState::AssemblingSubpackets{
-                                    received: 1,
-                                    required: header.total_sub_packets,
-                                    last_packet_number: header.packet_number,
-                                    frame_number: header.frame_number
-                                }
-                            }
-                        }
-                    },
-                    FrameReceptionState::AssemblingSubpackets { received, required, last_packet_number, frame_number } => {
-                        if header.packet_number != (last_packet_number.wrapping_add(1)){
-                            eprintln!("Received packet out of order!");
-                            frame_reception_state = FrameReceptionState::WaitingForFrameStart;
-                            continue;
-                        }else{
-                            if header.frame_number != frame_number{
-                                eprintln!("Received subpacket for other frame!");
-                                frame_reception_state = FrameReceptionState::WaitingForFrameStart;
-                                continue;
-                            }else{
-                                if header.sub_packet_number != received{
-                                    eprintln!("Received subpacket out of order! Got {} expected {}", header.sub_packet_number, received.wrapping_add(1));
-                                    frame_reception_state = FrameReceptionState::WaitingForFrameStart;
-                                    continue;
-                                }else{
-                                    (&mut raw_jpeg_buffer[raw_jpeg_buffer_length..]).write_all(&data).expect(&format!("Packet way longer than expected (memory corruption??) subpacket_count={}, total_buffer_length={}, buffer_rest_length={}, data_length={}", required, raw_jpeg_buffer.len(), raw_jpeg_buffer[raw_jpeg_buffer_length..].len(), data.len()));
-                                    raw_jpeg_buffer_length += data.len();
-                                    if received + 1 == required{
-                                        if let Some(frame) = WifiCam::decode_jpeg_frame(&raw_jpeg_buffer){
-                                            last_frame.store(Arc::new(frame));
-                                        }
-                                        frame_reception_state = FrameReceptionState::WaitingForFrameStart;
-                                        continue;
-                                    }else{
-                                        frame_reception_state = FrameReceptionState::AssemblingSubpackets{
-                                            received: received.wrapping_add(1),
-                                            required: header.total_sub_packets,
-                                            last_packet_number: header.packet_number,
-                                            frame_number: header.frame_number
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+impl FrameSource for WifiCam{
+    fn core(&self) -> &SourceCore{
+        &self.core
+    }
 
+    fn core_mut(&mut self) -> &mut SourceCore{
+        &mut self.core
+    }
+}
 
-                //producer.write_all(&buf[0..length]).expect("Error writing to ringbuffer");
-                //eprintln!("Received {} bytes from {}", length, src);
+impl WifiCam{
+    /// Opens the video socket (or the capture file to replay) and starts talking to the camera.
+    /// Errors after this are reported to `subscribe_errors`, the threads keep trying to reconnect.
+    /// Shutting down may take up to `connection_timeout` while the control connection is being opened.
+    pub fn new(config: WifiCamConfig) -> Result<WifiCam, DashcamError>{
+        let reassembler = Reassembler::new(config.reorder_window, config.frame_timeout);
+        match config.replay_file.clone(){
+            Some(path) => {
+                let capture = CaptureReader::open(&path)
+                    .map_err(|e| DashcamError::io(format!("Error opening capture file {}", path.display()), e))?;
+                let mut core = SourceCore::new(path.display().to_string(), &config);
+                core.add_thread(WifiCam::start_replay(capture, config.replay_speed, reassembler, core.sink()));
+                Ok(WifiCam{ core })
+            },
+            None => {
+                let socket = UdpSocket::bind(config.bind_address())
+                    .map_err(|e| DashcamError::network(format!("Error binding to UDP socket {}", config.bind_address()), e))?;
+                //The read timeout lets the receiver notice a shutdown
+                socket.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))
+                    .map_err(|e| DashcamError::network("Error setting UDP read timeout", e))?;
+                let capture = config.capture_file.as_ref().and_then(|path| match CaptureWriter::create(path){
+                    Ok(capture) => Some(Arc::new(capture)),
+                    Err(e) => {
+                        eprintln!("Error creating capture file {}: {}", path.display(), e);
+                        None
+                    }
+                });
+                let mut core = SourceCore::new(config.camera_address().to_string(), &config);
+                core.add_thread(WifiCam::start_udp_receiver(socket, reassembler, capture.clone(), core.sink()));
+                core.add_thread(WifiCam::send_init_sequence(config, capture, core.sink()));
+                Ok(WifiCam{ core })
             }
-        });
-        
-        (udp_thread, consumer)
+        }
     }
 
-    fn send_init_sequence() -> (JoinHandle<()>,Receiver<TcpMessage>){
-        //Create channel to keep a hold of tcp messages
-        let (sender, receiver) = channel::<TcpMessage>();
-        //Spawn tcp transceiver thread
-        let tcp_thread = thread::spawn(move || {
-            //Magic sequence captured from the app MRT_Camera with wireshark
-            const INIT_SEQUENCE: [u8; 20] = [
-                0x01, 0x01, 0x02, 0x10,
-                0x02, 0x01, 0x03, 0x20, 0x02, 0x01, 0x03, 0x20,
-                0x0e, 0x01, 0xaf, 0xe0, 0x24, 0x01, 0xc0, 0x42
-            ];
-            const KEEPALIVE_SEQUENCE: [u8; 4] = [
-                0x0e, 0x01, 0xaf, 0xe0
-            ];
-            const KEEPALIVE_INTERVAL_MS: u128 = 1000;
-            //Connect to tcp port
-            let mut stream = TcpStream::connect("192.168.1.1:5252").expect("Error binding to TCP socket");
-            stream.set_read_timeout(Some(Duration::from_millis(100))).expect("Error setting receive timeout");
-            //Write magic sequence
-            stream.write_all(&INIT_SEQUENCE).expect("Error sending TCP data");
-            //Start reading
-            let mut last_keepalive = SystemTime::now();
-            loop {
-                 //Send keepalive sequence if more than one second since last keepalive
-                 if SystemTime::now().duration_since(last_keepalive).map(|duration| duration.as_millis() > KEEPALIVE_INTERVAL_MS).unwrap_or(false){
-                    //eprintln!("Sending keepalive");
-                    stream.write_all(&KEEPALIVE_SEQUENCE).expect("Error sending keepalive sequence");
-                    last_keepalive = SystemTime::now();
+    fn start_udp_receiver(socket: UdpSocket, mut reassembler: Reassembler, capture: Option<Arc<CaptureWriter>>, sink: FrameSink) -> JoinHandle<()>{
+        //Spawn udp receiver thread
+        thread::spawn(move || {
+            //Receive packets and reassemble them into frames, the read timeout lets the loop check for a shutdown
+            let mut buf = vec![0; 1024 * 32];
+            let mut session = sink.session();
+            while !sink.is_shutting_down(){
+                //The camera counts packets from 0 again in every session
+                let current_session = sink.session();
+                if current_session != session{
+                    reassembler.reset_order();
+                    session = current_session;
                 }
-                //Read answer
-                let mut buf = [0;256];
-                let length_read =
-                match stream.read(&mut buf){
-                    Ok(number) => number,
+                let length = match socket.recv(&mut buf){
+                    Ok(length) => length,
+                    Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut | std::io::ErrorKind::Interrupted) => continue,
                     Err(e) => {
-                        let kind = e.kind();
-                        match kind{
-                            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut=> {
-                                //eprintln!("Read timeout");
-                            },
-                            _ => {
-                                eprintln!("Error reading tcp data: {:?}",e);
-                            }
-                        }
+                        sink.report(DashcamError::network("Error receiving UDP data", e));
+                        //Don't spin on an error that persists
+                        sink.sleep_until(Instant::now() + MIN_RECONNECT_DELAY);
                         continue;
                     }
                 };
-                //After receiving, transform message into TcpMessage and pass on
-                let message: TcpMessage = buf[0..length_read].into();
-                eprintln!("Received {} bytes from remote TCP (Message = {:?})",length_read, message);
-                sender.send(message).expect("Error sending TCP message to queue");
+                if let Some(capture) = &capture{
+                    capture.record(RecordKind::Video, &buf[0..length]);
+                }
+                WifiCam::handle_datagram(&buf[0..length], Instant::now(), &mut reassembler, &sink);
             }
-        });
-        
-        (tcp_thread, receiver)
+        })
+    }
+
+    /// Feeds a datagram of the video stream into the reassembly, `now` is when it was received
+    fn handle_datagram(datagram: &[u8], now: Instant, reassembler: &mut Reassembler, sink: &FrameSink){
+        let stats = sink.stats();
+        WifiCamStats::increment(&stats.packets_received);
+        WifiCamStats::add(&stats.bytes_received, datagram.len() as u64);
+        let header = match UDPFrameHeader::parse(datagram){
+            Some(header) => header,
+            //No header
+            None => {
+                WifiCamStats::increment(&stats.incomplete_packets);
+                return;
+            }
+        };
+        let data = &datagram[UDPFrameHeader::LENGTH..];
+        if data.len() + UDPFrameHeader::LENGTH != header.packet_length as usize{
+            WifiCamStats::increment(&stats.incomplete_packets);
+        }
+
+        if let Some(frame) = reassembler.push(&header, data, now){
+            WifiCam::complete_frame(frame, sink);
+        }
+        WifiCamStats::add(&stats.out_of_order_packets, reassembler.take_out_of_order());
+        WifiCamStats::add(&stats.incomplete_frames, reassembler.take_dropped().len() as u64);
     }
 
-    fn start_jpeg_thread(mut bytestream: Consumer<u8>) -> (Arc<ArcSwap<Vec<u8>>>, JoinHandle<()>){
-        let last_frame = Arc::new(ArcSwap::from_pointee(Vec::new()));
-        let frame_reference = last_frame.clone();
-        let jpeg_thread = thread::spawn(move || {
-            //Storage for our frames
-            let mut frame_bytes = vec![0; 1024 * 32 * 8]; //Lets make this larger than usually needed, so multiple frames can find place here
-            let find_magic_bytes = |start_offset, buffer: &[u8]| {
-                const JPEG_MAGIC_NUMBER:[u8; 3] = [0xFF, 0xD8, 0xFF];
-                for i in start_offset..(buffer.len() - 3){
-                    if buffer[i..i+3] == JPEG_MAGIC_NUMBER{
-                        return Some(i);
+    /// Plays back a capture file through the same reassembly as the live stream, without any network
+    fn start_replay(capture: CaptureReader, speed: f64, mut reassembler: Reassembler, sink: FrameSink) -> JoinHandle<()>{
+        thread::spawn(move || {
+            eprintln!("Replaying capture started {}", recorder::format_timestamp(capture.start_time));
+            sink.set_state(ConnectionState::Replaying);
+            let start = Instant::now();
+            let mut messages = TcpMessageReader::default();
+            for record in capture{
+                let record = match record{
+                    Ok(record) => record,
+                    Err(e) => {
+                        eprintln!("Error reading capture file: {}", e);
+                        break;
                     }
+                };
+                if sink.is_shutting_down(){
+                    break;
                 }
-                None
-            };
-            loop{
-                let bytes_read = bytestream.read(&mut frame_bytes).unwrap_or(0);
-                if bytes_read == 0{
-                    thread::sleep(Duration::from_millis(1));
-                    continue;
+                if speed > 0.0 && !sink.sleep_until(start + record.timestamp.div_f64(speed)){
+                    break;
                 }
-                if let Some(mut first_magic_number) = find_magic_bytes(0, &frame_bytes[0..bytes_read]){
-                    eprintln!("first_magic_number found");
-                    let mut buffer_end = bytes_read;
-                    while buffer_end < frame_bytes.len() - (1024 * 32){ //Always leave space for a second frame
-                        let bytes_read = bytestream.read(&mut frame_bytes[buffer_end..]).unwrap_or(0);
-                        if bytes_read == 0{
-                            thread::sleep(Duration::from_millis(1));
-                            continue;
-                        }
-                        if let Some(second_magic_number) = find_magic_bytes(buffer_end, &frame_bytes[0..(buffer_end + bytes_read)]){
-                            //Found a jpeg frame!
-                            if let Some(frame) = WifiCam::decode_jpeg_frame(&frame_bytes[first_magic_number .. second_magic_number]){
-                                frame_reference.store(Arc::new(frame));
-                            }
-                            //Copy rest of buffer into beginning and continue inner loop
-                            let number_of_overhanging_bytes = (buffer_end + bytes_read) - second_magic_number;
-                            for i in 0 .. number_of_overhanging_bytes{
-                                frame_bytes[i] = frame_bytes[i + second_magic_number];
-                            }
-                            buffer_end = number_of_overhanging_bytes;
-                            first_magic_number = 0;
-                        }else{
-                            //If we didn't find a second magic number we didn't load enough of the previous frame yet
-                            //So we just move the end of the buffer forward by the required amount and load more bytes
-                            buffer_end += bytes_read;
+                //Reassembly sees the original timing, so frames are dropped exactly like in the captured session
+                let received = start + record.timestamp;
+                match record.kind{
+                    RecordKind::Video => WifiCam::handle_datagram(&record.data, received, &mut reassembler, &sink),
+                    RecordKind::ControlReceived => {
+                        for message in messages.push(&record.data){
+                            sink.send_event(message);
                         }
-                    }
-                    //If we end up here, we ran out of buffer space
-                    eprintln!("Ran out of buffer space");
-                    continue;
-                }else{ 
-                    //Magic number not in this packet, let's wait for the next one
-                    continue;
+                    },
+                    RecordKind::ControlSent => ()
                 }
             }
+            eprintln!("Replay finished");
+            sink.set_state(ConnectionState::Disconnected);
+        })
+    }
 
-            
+    /// Passes a fully reassembled JPEG on to the subscribers and the decoder
+    fn complete_frame(frame: AssembledFrame, sink: &FrameSink){
+        sink.push_frame(EncodedFrame{
+            data: frame.data,
+            received: Instant::now(),
+            frame_number: frame.frame_number,
+            sub_packets: frame.sub_packets
+        });
+    }
+
+    fn send_init_sequence(config: WifiCamConfig, capture: Option<Arc<CaptureWriter>>, sink: FrameSink) -> JoinHandle<()>{
+        //Spawn tcp supervisor thread, which keeps reconnecting whenever the session breaks down
+        let tcp_thread = thread::spawn(move || {
+            let mut reconnect_delay = MIN_RECONNECT_DELAY;
+            while !sink.is_shutting_down(){
+                sink.set_state(ConnectionState::Connecting);
+                let result = WifiCam::run_control_session(&config, capture.as_deref(), &sink, &mut reconnect_delay);
+                if sink.is_shutting_down(){
+                    if let Err(e) = result{
+                        sink.report(e);
+                    }
+                    break;
+                }
+                sink.set_state(ConnectionState::Lost);
+                sink.report(DashcamError::reconnecting(result.err(), reconnect_delay));
+                sink.sleep_until(Instant::now() + reconnect_delay);
+                reconnect_delay = (reconnect_delay * 2).min(config.max_reconnect_delay);
+            }
+            sink.set_state(ConnectionState::Disconnected);
         });
 
-        (last_frame, jpeg_thread)
+        tcp_thread
     }
 
-    fn decode_jpeg_frame(bytes: &[u8]) -> Option<Vec<u8>>{
-        //eprintln!("Decoding frame starting with {:?}", &bytes[0..5]);
-        let mut decoder = Decoder::new(bytes);
-        match decoder.decode(){
-            Ok(pixels) => {
-                let metadata = decoder.info().expect("Error reading metadata");
-                if metadata.width != 1280{
-                    eprintln!("Width wrong");
-                    return None;
+    /// Connects to the camera, sends the init sequence and keeps the session alive until an error occurs or the camera is shut down.
+    /// Resets `reconnect_delay` once the camera has answered, so the backoff only grows while the camera is unreachable.
+    fn run_control_session(config: &WifiCamConfig, capture: Option<&CaptureWriter>, sink: &FrameSink, reconnect_delay: &mut Duration) -> Result<(), DashcamError>{
+        let stats = sink.stats();
+        let send_error = |e| DashcamError::network("Error sending to camera", e);
+        //Connect to tcp port
+        let mut stream = TcpStream::connect_timeout(&config.camera_address(), config.connection_timeout)
+            .map_err(|e| DashcamError::network(format!("Error connecting to camera {}", config.camera_address()), e))?;
+        sink.start_session();
+        stream.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL)).map_err(send_error)?;
+        //Write magic sequence
+        stream.write_all(&INIT_SEQUENCE).map_err(send_error)?;
+        if let Some(capture) = capture{
+            capture.record(RecordKind::ControlSent, &INIT_SEQUENCE);
+        }
+        sink.set_state(ConnectionState::Initialized);
+        //Start reading
+        let mut messages = TcpMessageReader::default();
+        let mut last_keepalive = Instant::now();
+        let mut last_answer = Instant::now();
+        while !sink.is_shutting_down(){
+            //Send keepalive sequence if more than one interval since last keepalive
+            if last_keepalive.elapsed() > config.keepalive_interval{
+                stream.write_all(&KEEPALIVE_SEQUENCE).map_err(send_error)?;
+                WifiCamStats::increment(&stats.keepalives_sent);
+                if let Some(capture) = capture{
+                    capture.record(RecordKind::ControlSent, &KEEPALIVE_SEQUENCE);
                 }
-                if metadata.height != 720{
-                    eprintln!("Height wrong");
-                    return None;
+                last_keepalive = Instant::now();
+            }
+            //Give up on the session if the camera stopped answering
+            if last_answer.elapsed() > config.connection_timeout{
+                return Err(DashcamError::Protocol(String::from("Camera stopped answering keepalives")));
+            }
+            //Read answer
+            let mut buf = [0;256];
+            let length_read =
+            match stream.read(&mut buf){
+                Ok(0) => return Err(DashcamError::Protocol(String::from("Connection closed by camera"))),
+                Ok(number) => number,
+                Err(e) => {
+                    match e.kind(){
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut | std::io::ErrorKind::Interrupted => continue,
+                        _ => return Err(DashcamError::network("Error receiving from camera", e))
+                    }
                 }
-                if pixels.len() != 1280 * 720 * 3{
-                    eprintln!("Pixel length wrong");
-                    return None;
+            };
+            last_answer = Instant::now();
+            if let Some(capture) = capture{
+                capture.record(RecordKind::ControlReceived, &buf[0..length_read]);
+            }
+            //After receiving, split the data into TcpMessages and pass them on
+            for message in messages.push(&buf[0..length_read]){
+                //Either answer means the camera accepted the session and streams video
+                let answered = match message{
+                    TcpMessage::KeepaliveAcknowledgement => {
+                        WifiCamStats::increment(&stats.keepalives_acknowledged);
+                        true
+                    },
+                    TcpMessage::Initialization => true,
+                    _ => false
+                };
+                if answered && sink.state() != ConnectionState::Streaming{
+                    sink.set_state(ConnectionState::Streaming);
+                    *reconnect_delay = MIN_RECONNECT_DELAY;
                 }
-                //eprintln!("Read frame: {:?}", metadata);
-                Some(pixels)
-            },
-            Err(e) => {
-                eprintln!("Decoding error: {:?}", e);
-                None
+                sink.send_event(message);
             }
         }
+        Ok(())
     }
 }
 
 
-pub enum FrameReceptionState{
-    WaitingForFrameStart,
-    AssemblingSubpackets{
-        received: u8,
-        required: u8,
-        frame_number: u8,
-        last_packet_number: u16
-    },
-}
 
+/// Header in front of every UDP packet of the video stream
 pub struct UDPFrameHeader{
-    packet_number: u16,
-    frame_number: u8,
-    sub_packet_number: u8,
-    total_sub_packets: u8,
-    packet_length: u32
+    /// Counts all packets sent
+    pub packet_number: u16,
+    /// Counts frames, wraps around
+    pub frame_number: u8,
+    /// Position of this packet within the frame
+    pub sub_packet_number: u8,
+    /// Number of packets the frame was split into
+    pub total_sub_packets: u8,
+    /// Length of the packet including this header
+    pub packet_length: u32
 }
 
-impl From<&[u8]> for UDPFrameHeader{
-    fn from(bytes: &[u8]) -> Self {
-        UDPFrameHeader{
-            packet_number: u16::from_be_bytes([bytes[0], bytes[1]]),
-            frame_number: bytes[2],
-            sub_packet_number: bytes[3],
-            total_sub_packets: bytes[4],
-            packet_length: u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]])
+impl UDPFrameHeader{
+    /// Bytes in front of the JPEG data
+    pub const LENGTH: usize = 9;
+
+    /// Reads the header at the start of a packet, None if the packet is too short to have one
+    pub fn parse(bytes: &[u8]) -> Option<UDPFrameHeader>{
+        match *bytes.get(0..UDPFrameHeader::LENGTH)?{
+            [packet_high, packet_low, frame_number, sub_packet_number, total_sub_packets, length_0, length_1, length_2, length_3] => Some(UDPFrameHeader{
+                packet_number: u16::from_be_bytes([packet_high, packet_low]),
+                frame_number,
+                sub_packet_number,
+                total_sub_packets,
+                packet_length: u32::from_be_bytes([length_0, length_1, length_2, length_3])
+            }),
+            _ => None
         }
     }
 }
 
-
 #[derive(Debug, Clone)]
 pub enum TcpMessage{
     Initialization,
@@ -323,13 +413,13 @@ pub enum TcpMessage{
     Other(Vec<u8>)
 }
 
+const INITIALIZATION: [u8; 8] = [0x0D, 0x02, 0xD0, 0xD0, 0x0E, 0x01, 0xAF, 0xE0];
+const YELLOW_WIRE_HIGH: [u8; 4] = [0x0B, 0x01, 0xC0, 0xB0];
+const YELLOW_WIRE_LOW: [u8; 4] = [0x0B, 0x00, 0xC0, 0xB0];
+const KEEPALIVE_ACKNOWLEDGEMENT: [u8; 4] = [0x0e, 0x01, 0xaf, 0xe0];
+
 impl From<&[u8]> for TcpMessage{
     fn from(bytes: &[u8]) -> Self {
-        const INITIALIZATION: [u8; 8] = [0x0D, 0x02, 0xD0, 0xD0, 0x0E, 0x01, 0xAF, 0xE0];
-        const YELLOW_WIRE_HIGH: [u8; 4] = [0x0B, 0x01, 0xC0, 0xB0];
-        const YELLOW_WIRE_LOW: [u8; 4] = [0x0B, 0x00, 0xC0, 0xB0];
-        const KEEPALIVE_ACKNOWLEDGEMENT: [u8; 4] = [0x0e, 0x01, 0xaf, 0xe0];
-
         if bytes == INITIALIZATION{
             TcpMessage::Initialization
         }else if bytes == YELLOW_WIRE_HIGH{
@@ -342,4 +432,73 @@ impl From<&[u8]> for TcpMessage{
             TcpMessage::Other(Vec::from(bytes))
         }
     }
-}
\ No newline at end of file
+}
+
+/// Splits the data of the control connection into messages.
+/// A single read may contain several messages or end in the middle of one, the rest is kept for the next read.
+#[derive(Default)]
+pub struct TcpMessageReader{
+    pending: Vec<u8>
+}
+
+impl TcpMessageReader{
+    /// Appends received data and returns the messages completed by it
+    pub fn push(&mut self, data: &[u8]) -> Vec<TcpMessage>{
+        self.pending.extend_from_slice(data);
+        let mut messages = Vec::new();
+        let mut start = 0;
+        while start < self.pending.len(){
+            //The answer to the init sequence is 8 bytes long, all other messages 4 bytes
+            let length = if self.pending[start] == INITIALIZATION[0] {INITIALIZATION.len()} else {KEEPALIVE_ACKNOWLEDGEMENT.len()};
+            if start + length > self.pending.len(){
+                break;
+            }
+            messages.push(TcpMessage::from(&self.pending[start..start + length]));
+            start += length;
+        }
+        self.pending.drain(..start);
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn kinds(messages: &[TcpMessage]) -> Vec<String>{
+        messages.iter().map(|message| format!("{:?}", message)).collect()
+    }
+
+    #[test]
+    fn parses_header_of_long_enough_packets_only(){
+        let header = UDPFrameHeader::parse(&[0x01, 0x02, 7, 3, 5, 0, 0, 0x04, 0x00, 0xFF, 0xD8]).unwrap();
+        assert_eq!((header.packet_number, header.frame_number, header.sub_packet_number, header.total_sub_packets), (0x0102, 7, 3, 5));
+        assert_eq!(header.packet_length, 1024);
+        assert!(UDPFrameHeader::parse(&[0; 9]).is_some());
+        assert!(UDPFrameHeader::parse(&[0; 8]).is_none());
+        assert!(UDPFrameHeader::parse(&[]).is_none());
+    }
+
+    #[test]
+    fn splits_messages_of_one_read(){
+        let mut reader = TcpMessageReader::default();
+        let data = [&KEEPALIVE_ACKNOWLEDGEMENT[..], &YELLOW_WIRE_HIGH[..], &INITIALIZATION[..], &YELLOW_WIRE_LOW[..]].concat();
+        assert_eq!(kinds(&reader.push(&data)), vec!["KeepaliveAcknowledgement", "YellowWireHigh", "Initialization", "YellowWireLow"]);
+    }
+
+    #[test]
+    fn keeps_partial_message_for_next_read(){
+        let mut reader = TcpMessageReader::default();
+        let data = [&KEEPALIVE_ACKNOWLEDGEMENT[..], &INITIALIZATION[..], &YELLOW_WIRE_HIGH[..]].concat();
+        assert_eq!(kinds(&reader.push(&data[..6])), vec!["KeepaliveAcknowledgement"]);
+        assert_eq!(kinds(&reader.push(&data[6..14])), vec!["Initialization"]);
+        assert!(reader.push(&data[14..15]).is_empty());
+        assert_eq!(kinds(&reader.push(&data[15..])), vec!["YellowWireHigh"]);
+    }
+
+    #[test]
+    fn passes_on_unknown_messages(){
+        let mut reader = TcpMessageReader::default();
+        assert_eq!(kinds(&reader.push(&[1, 2, 3, 4, 0x0B, 0x01, 0xC0, 0xB0])), vec!["Other([1, 2, 3, 4])", "YellowWireHigh"]);
+    }
+}